@@ -1,14 +1,30 @@
 use egui::Button;
-use log::{error, info};
+use log::{debug, error, info};
 
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use crate::encrypt::check_public_key;
-use crate::encrypt::encrypt_file_with_inmemory_key;
+use crate::encrypt::{
+    encrypted_block_size, estimate_encrypted_size, CapacityEstimate, DEFAULT_ENCRYPTED_BLOCK_SIZE,
+};
 use crate::folder;
 use crate::folder::*;
+use crate::integrity::{open_protected, IntegrityStatus};
+use crate::key_book::*;
+use crate::key_index::{KeyIndex, KeyQuery};
+use crate::key_provider::*;
+use crate::key_signature::key_fingerprint;
+use crate::key_sync::*;
+use crate::keyserver::KeyServerClient;
+use crate::mirror::{self, MirrorAction, MirrorOptions, MirrorPlan};
+use crate::output::*;
+use crate::revocation::*;
+use crate::watch::{FolderWatcher, POLL_INTERVAL};
 
 use crate::keys_management::*;
 use egui::Color32;
@@ -18,11 +34,260 @@ use egui_extras::{Size, StripBuilder};
 use flowync::error::Cause;
 use flowync::Flower;
 
-use isahc::prelude::*;
-
 use im_native_dialog::ImNativeFileDialog;
 
-type TypedFlower = Flower<String, String>;
+type TypedFlower = Flower<String, FetchedKey>;
+
+/// where the add and synchronization windows look for the keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+enum KeyLookup {
+    /// the key folder, then the configured sources
+    #[default]
+    AllSources,
+    Server,
+    Folder,
+}
+
+impl KeyLookup {
+    /// the folder lookup needs a key folder, the current directory is never searched
+    fn is_ready(self, key_folder: &str) -> bool {
+        self != KeyLookup::Folder || !key_folder.trim().is_empty()
+    }
+}
+
+/// result of a synchronization with the key server, run in the background
+enum SyncOutcome {
+    Fetched(Vec<KeyFetch>),
+    Refreshed(Vec<RefreshEntry>),
+}
+
+// progress of the synchronization (done, total)
+type SyncFlower = Flower<(usize, usize), SyncOutcome>;
+
+/// audit log entries of the history window, with the filter they were read for
+struct HistoryEntries {
+    key_sha1: Option<String>,
+    path: String,
+    entries: std::result::Result<Vec<AuditEntry>, String>,
+}
+
+/// status of a file, with the modification time of the file it was computed for
+#[derive(Debug, Clone, Copy)]
+struct FileStatus {
+    modified: Option<SystemTime>,
+    status: Option<EncryptionStatus>,
+}
+
+impl FileStatus {
+    fn compute(checker: &StatusChecker, path: &Path) -> FileStatus {
+        // read before the status, a change during the hash is seen afterwards
+        let modified = modified_time(path);
+        let status = match checker.status(path) {
+            Ok(status) => Some(status),
+            Err(e) => {
+                debug!("no status for {} : {}", path.display(), e);
+                None
+            }
+        };
+        FileStatus { modified, status }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// statuses computed in the background, with the generation of the checker
+type StatusFlower = Flower<(), (u64, Vec<(PathBuf, FileStatus)>)>;
+
+/// encryption status of the files of the tree for the selected key,
+/// computed in the background when a file is displayed, and again when the watch
+/// of the tree reports its folder changed
+struct TreeStatus {
+    checker: Option<Arc<StatusChecker>>,
+    statuses: HashMap<PathBuf, FileStatus>,
+    /// displayed files without a status
+    requested: HashSet<PathBuf>,
+    /// the statuses computed for a previous checker, or before a change of the files,
+    /// are dropped
+    generation: u64,
+    flower: StatusFlower,
+}
+
+impl Default for TreeStatus {
+    fn default() -> Self {
+        TreeStatus {
+            checker: None,
+            statuses: HashMap::new(),
+            requested: HashSet::new(),
+            generation: 0,
+            flower: StatusFlower::new(3),
+        }
+    }
+}
+
+impl TreeStatus {
+    /// known status of the file, computed in the background when missing
+    fn get(&mut self, path: &Path) -> Option<EncryptionStatus> {
+        self.checker.as_ref()?;
+        match self.statuses.get(path) {
+            Some(s) => s.status,
+            None => {
+                self.requested.insert(path.to_path_buf());
+                None
+            }
+        }
+    }
+
+    /// status of the file, computed now when missing or outdated
+    fn get_now(&mut self, path: &Path) -> Option<EncryptionStatus> {
+        let checker = self.checker.as_ref()?;
+        match self.statuses.get(path) {
+            Some(s) if s.modified == modified_time(path) => s.status,
+            _ => {
+                let computed = FileStatus::compute(checker, path);
+                self.statuses.insert(path.to_path_buf(), computed);
+                self.requested.remove(path);
+                computed.status
+            }
+        }
+    }
+
+    /// statuses being computed
+    fn is_busy(&self) -> bool {
+        self.flower.is_active() || !self.requested.is_empty()
+    }
+
+    /// collect the statuses computed in the background, and compute the requested ones
+    fn poll(&mut self) {
+        if self.flower.is_active() {
+            let mut computed = None;
+            self.flower
+                .extract(|_| {})
+                .finalize(|result| computed = Some(result));
+            match computed {
+                Some(Ok((generation, statuses))) if generation == self.generation => {
+                    for (path, status) in statuses {
+                        self.requested.remove(&path);
+                        self.statuses.insert(path, status);
+                    }
+                }
+                Some(Err(Cause::Suppose(msg))) | Some(Err(Cause::Panicked(msg))) => {
+                    error!("fail to compute the encryption statuses : {}", msg);
+                    self.requested.clear();
+                }
+                _ => {}
+            }
+        }
+        let checker = match &self.checker {
+            Some(checker) if !self.flower.is_active() && !self.requested.is_empty() => {
+                checker.clone()
+            }
+            _ => return,
+        };
+        let paths: Vec<PathBuf> = self.requested.iter().cloned().collect();
+        let generation = self.generation;
+        std::thread::spawn({
+            let handle = self.flower.handle();
+            handle.activate();
+            move || {
+                let statuses = paths
+                    .into_iter()
+                    .map(|path| {
+                        let status = FileStatus::compute(&checker, &path);
+                        (path, status)
+                    })
+                    .collect();
+                handle.set_result(Ok((generation, statuses)));
+            }
+        });
+    }
+
+    /// the files changed on the disk
+    fn clear(&mut self) {
+        self.statuses.clear();
+        self.generation += 1;
+    }
+
+    /// the files of these folders changed on the disk
+    fn forget(&mut self, folders: &[PathBuf]) {
+        self.statuses.retain(|path, _| {
+            !path
+                .parent()
+                .map_or(false, |p| folders.iter().any(|f| f == p))
+        });
+        self.generation += 1;
+    }
+
+    /// the key or the audit log changed
+    fn invalidate(&mut self) {
+        self.checker = None;
+        self.statuses.clear();
+        self.requested.clear();
+        self.generation += 1;
+    }
+}
+
+/// interval between two frames while statuses are computed, or a key is fetched
+const STATUS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// keys listed at most by the key picker
+const KEY_PICKER_RESULTS: usize = 50;
+
+/// default capacity of an OR1 module, in Mo
+const DEFAULT_MODULE_CAPACITY_MB: u64 = 16;
+
+/// human readable size, in Ko / Mo
+fn format_size(size: u64) -> String {
+    if size < 1024 * 1024 {
+        format!("{:.1} Ko", size as f64 / 1024.0)
+    } else {
+        format!("{:.2} Mo", size as f64 / (1024.0 * 1024.0))
+    }
+}
+
+/// encrypted size of the selection, the files are only read again when the selection,
+/// the keys or the files change
+#[derive(Default)]
+struct SizeEstimate {
+    files: Vec<PathBuf>,
+    keys: Vec<String>,
+    size: Option<std::result::Result<u64, String>>,
+}
+
+impl SizeEstimate {
+    fn estimate(
+        &mut self,
+        files: Vec<PathBuf>,
+        keys: &[Key],
+        capacity: u64,
+    ) -> std::result::Result<CapacityEstimate, String> {
+        let sha1: Vec<String> = keys.iter().map(|k| k.sha1.clone()).collect();
+        if self.size.is_none() || self.files != files || self.keys != sha1 {
+            // the largest key of the group gives the largest files
+            let block_size = keys
+                .iter()
+                .filter_map(|k| k.public_key.as_deref())
+                .filter_map(|p| encrypted_block_size(p).ok())
+                .max()
+                .unwrap_or(DEFAULT_ENCRYPTED_BLOCK_SIZE);
+            self.size =
+                Some(estimate_encrypted_size(&files, block_size).map_err(|e| e.to_string()));
+            self.files = files;
+            self.keys = sha1;
+        }
+        let encrypted_size = self.size.clone().unwrap_or(Ok(0))?;
+        Ok(CapacityEstimate {
+            encrypted_size,
+            capacity,
+        })
+    }
+
+    /// the files changed on the disk
+    fn clear(&mut self) {
+        self.size = None;
+    }
+}
 
 #[derive(Debug, Clone)]
 struct AppError {
@@ -45,6 +310,112 @@ impl fmt::Display for AppError {
 }
 impl std::error::Error for AppError {}
 
+/// key being edited in the key management window
+struct KeyEdit {
+    sha1: String,
+    original_name: String,
+    original_public_key: String,
+    name: String,
+    public_key: String,
+    owner: String,
+    instrument_model: String,
+    instrument_serial: String,
+    notes: String,
+    // source and usage, for display
+    info: String,
+    // groups of the key, with the membership
+    groups: Vec<(String, bool)>,
+    // public keys seen for the sha1
+    history: Vec<KeyHistoryEntry>,
+    // expiry date, YYYY-MM-DD or empty
+    original_expires_at: String,
+    expires_at: String,
+    // revocation time, and the reason of a new revocation
+    revoked_at: Option<i64>,
+    revocation_reason: String,
+}
+
+impl KeyEdit {
+    fn from_key(k: &Key, db: &dyn KeyStore) -> KeyEdit {
+        let key_groups = db.get_key_groups(&k.sha1).unwrap_or_default();
+        let groups = db
+            .get_groups()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|g| {
+                let member = key_groups.contains(&g);
+                (g, member)
+            })
+            .collect();
+        let public_key = k
+            .public_key
+            .as_ref()
+            .map(|p| String::from_utf8_lossy(p).to_string())
+            .unwrap_or_default();
+        let expires_at = k
+            .expires_at
+            .map(|t| format_timestamp(t)[..10].to_string())
+            .unwrap_or_default();
+        KeyEdit {
+            sha1: k.sha1.clone(),
+            original_name: k.name.clone(),
+            original_public_key: public_key.clone(),
+            name: k.name.clone(),
+            public_key,
+            owner: k.owner.clone(),
+            instrument_model: k.instrument_model.clone(),
+            instrument_serial: k.instrument_serial.clone(),
+            notes: k.notes.clone(),
+            info: format!(
+                "origine : {}, ajoutée le : {}, dernière utilisation : {}",
+                k.source.as_str(),
+                k.added_at
+                    .map(format_timestamp)
+                    .unwrap_or_else(|| "-".into()),
+                k.last_used
+                    .map(format_timestamp)
+                    .unwrap_or_else(|| "-".into())
+            ),
+            groups,
+            history: db.get_key_history(&k.sha1).unwrap_or_default(),
+            original_expires_at: expires_at.clone(),
+            expires_at,
+            revoked_at: k.revoked_at,
+            revocation_reason: k.revocation_reason.clone(),
+        }
+    }
+}
+
+/// short fingerprint of a public key, to tell the keys of the history apart,
+/// the beginning of the fingerprint checked by the publisher signatures
+fn fingerprint(public_key: &[u8]) -> String {
+    match key_fingerprint(public_key) {
+        Ok(f) => f[..16].to_string(),
+        Err(_) => "clé illisible".into(),
+    }
+}
+
+/// message of the add dialog, once the key is recorded
+fn insertion_message(
+    sha1: &str,
+    result: std::result::Result<KeyInsertion, KeyManagementError>,
+) -> (String, bool) {
+    match result {
+        Ok(KeyInsertion::ChangePending) => (
+            format!(
+                "ATTENTION : la clé publique de {} a changé, le changement doit être accepté dans la gestion des clés",
+                sha1
+            ),
+            true,
+        ),
+        Ok(_) => ("clé ".to_string() + sha1 + " récupérée, et enregistrée", false),
+        Err(_) => (
+            "clé ".to_string() + sha1 + " non sauvegardée, erreur dans l'écriture",
+            true,
+        ),
+    }
+}
+
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
@@ -56,14 +427,71 @@ pub struct EncrypterApp {
     #[serde(skip)]
     value: f32,
 
+    // capacity of the target module, in Mo
+    module_capacity_mb: u64,
+    // the selection does not fit, waiting for the operator confirmation
+    #[serde(skip)]
+    capacity_confirm_pending: bool,
+    // a key is revoked or expired, waiting for the operator to override
+    #[serde(skip)]
+    validity_confirm_pending: bool,
+    #[serde(skip)]
+    validity_override: bool,
+
     #[serde(skip)]
     selected: Option<Key>,
 
+    // key picker, the index is built on the first search
+    #[serde(skip)]
+    key_filter: String,
+    #[serde(skip)]
+    key_index: Option<KeyIndex>,
+    // public key changes waiting for the operator, read again when the keys change
+    #[serde(skip)]
+    pending_key_changes: Option<Vec<KeyHistoryEntry>>,
+    #[serde(skip)]
+    key_picker_open: bool,
+    #[serde(skip)]
+    key_picker_cursor: usize,
+
+    // encrypt for all the keys of this group, instead of the selected key
+    #[serde(skip)]
+    selected_group: Option<String>,
+
     #[serde(skip)]
     files_folder: FolderNode,
 
+    // only show the music files in the tree
+    music_only: bool,
+
+    // folder and naming template of the encrypted files, see `OutputLayout`
+    output_root: String,
+    output_template: String,
+
+    // refresh the expanded folders changed by other programs
+    #[serde(skip)]
+    watcher: FolderWatcher,
+
+    #[serde(skip)]
+    tree_status: TreeStatus,
+
+    #[serde(skip)]
+    size_estimate: SizeEstimate,
+
+    #[serde(skip)]
+    db: Box<dyn KeyStore>,
+
+    // configured key database file, overridden by the ENCRYPTER_KEYS_DB variable
+    database_path: Option<PathBuf>,
+    #[serde(skip)]
+    database_input: String,
+    // the key database can't be opened, the keys are kept in memory
     #[serde(skip)]
-    db: Database,
+    database_error: Option<String>,
+
+    // key folder (usb stick, share) mirroring the key server, and the source used
+    key_folder: String,
+    key_lookup: KeyLookup,
 
     #[serde(skip)]
     last_message: String,
@@ -81,6 +509,8 @@ pub struct EncrypterApp {
     #[serde(skip)]
     key_search_key_internet: bool,
     #[serde(skip)]
+    key_server: KeyServerClient,
+    #[serde(skip)]
     key_public_key: String,
 
     #[serde(skip)]
@@ -88,6 +518,83 @@ pub struct EncrypterApp {
     #[serde(skip)]
     key_is_error: bool,
 
+    // key management window
+    #[serde(skip)]
+    is_manage_opened: bool,
+    #[serde(skip)]
+    manage_filter: String,
+    #[serde(skip)]
+    manage_edit: Option<KeyEdit>,
+    #[serde(skip)]
+    manage_delete_pending: Option<String>,
+    #[serde(skip)]
+    manage_new_group: String,
+    #[serde(skip)]
+    manage_message: String,
+    #[serde(skip)]
+    manage_is_error: bool,
+
+    // key book import / export window
+    #[serde(skip)]
+    is_book_opened: bool,
+    #[serde(skip)]
+    book_path: String,
+    #[serde(skip)]
+    book_format: KeyBookFormat,
+    #[serde(skip)]
+    book_strategy: ImportStrategy,
+    #[serde(skip)]
+    book_records: Vec<KeyRecord>,
+    #[serde(skip)]
+    book_preview: Option<ImportPreview>,
+    #[serde(skip)]
+    book_message: String,
+    #[serde(skip)]
+    book_is_error: bool,
+    #[serde(skip)]
+    revocation_path: String,
+
+    // encryption history window
+    #[serde(skip)]
+    is_history_opened: bool,
+    #[serde(skip)]
+    history_key: Option<String>,
+    #[serde(skip)]
+    history_path: String,
+    /// read again when the filter changes or files are encrypted
+    #[serde(skip)]
+    history_entries: Option<HistoryEntries>,
+
+    // folder synchronization window
+    #[serde(skip)]
+    is_mirror_opened: bool,
+    #[serde(skip)]
+    mirror_remove_orphans: bool,
+    #[serde(skip)]
+    mirror_plans: Vec<(Key, MirrorPlan)>,
+    #[serde(skip)]
+    mirror_message: String,
+    #[serde(skip)]
+    mirror_is_error: bool,
+
+    // key server synchronization window
+    #[serde(skip)]
+    is_sync_opened: bool,
+    #[serde(skip)]
+    sync_text: String,
+    #[serde(skip)]
+    sync_path: String,
+    #[serde(skip)]
+    sync_progress: (usize, usize),
+    #[serde(skip)]
+    sync_refresh: Vec<RefreshEntry>,
+    #[serde(skip)]
+    sync_message: String,
+    #[serde(skip)]
+    sync_is_error: bool,
+    #[serde(skip)]
+    sync_flower: SyncFlower,
+
     // async grab key from internet
     #[serde(skip)]
     flower: TypedFlower,
@@ -104,28 +611,53 @@ pub struct EncrypterApp {
 
 impl Default for EncrypterApp {
     fn default() -> Self {
-        let mut r = FolderNode {
-            expanded: false,
-            is_folder: true,
-            path: ".".into(),
-            subfolders: vec![],
-            selected: false,
-        };
-
-        let db = Database::open_database().expect("cannot open database");
+        let path = resolve_database_path(None);
+        match open_protected(&path) {
+            Ok(db) => EncrypterApp::with_key_store(Box::new(db)),
+            Err(e) => {
+                // started on a transient store, another location can be chosen
+                let mut app = EncrypterApp::with_key_store(Box::new(MemoryKeyStore::new()));
+                app.database_failed(&path, e.as_ref());
+                app
+            }
+        }
+    }
+}
 
+impl EncrypterApp {
+    /// application state using the given key store
+    pub fn with_key_store(db: Box<dyn KeyStore>) -> Self {
         // expand the first level
-        if let Err(e) = expand(&mut r) {
-            error!("error in expanding the tree : {}", e);
-        }
+        let r = EncrypterApp::open_tree(".", false, &OutputLayout::default());
 
-        Self {
+        let mut app = Self {
             // Example stuff:
             label: "Encrypter".to_owned(),
             value: 2.7,
+            module_capacity_mb: DEFAULT_MODULE_CAPACITY_MB,
+            capacity_confirm_pending: false,
+            validity_confirm_pending: false,
+            validity_override: false,
             selected: None,
+            key_filter: "".to_owned(),
+            key_index: None,
+            pending_key_changes: None,
+            key_picker_open: false,
+            key_picker_cursor: 0,
+            selected_group: None,
             files_folder: r,
+            music_only: false,
+            output_root: "".to_owned(),
+            output_template: DEFAULT_TEMPLATE.to_owned(),
+            watcher: FolderWatcher::new(),
+            tree_status: TreeStatus::default(),
+            size_estimate: SizeEstimate::default(),
             db,
+            database_path: None,
+            database_input: "".to_owned(),
+            database_error: None,
+            key_folder: "".to_owned(),
+            key_lookup: KeyLookup::AllSources,
             last_message: "".to_owned(),
             is_error: false,
             is_add_opened: false,
@@ -133,18 +665,110 @@ impl Default for EncrypterApp {
             key_sha1_input: "".to_owned(),
             key_public_key: "".to_owned(),
             key_search_key_internet: true,
+            key_server: KeyServerClient::from_env(),
             key_error_message: "".to_owned(),
             key_is_error: false,
+            is_manage_opened: false,
+            manage_filter: "".to_owned(),
+            manage_edit: None,
+            manage_delete_pending: None,
+            manage_new_group: "".to_owned(),
+            manage_message: "".to_owned(),
+            manage_is_error: false,
+            is_book_opened: false,
+            book_path: "keys.json".to_owned(),
+            book_format: KeyBookFormat::Json,
+            book_strategy: ImportStrategy::Merge,
+            book_records: vec![],
+            book_preview: None,
+            book_message: "".to_owned(),
+            book_is_error: false,
+            revocation_path: "revocations.json".to_owned(),
+            is_history_opened: false,
+            history_key: None,
+            history_path: "".to_owned(),
+            history_entries: None,
+            is_mirror_opened: false,
+            mirror_remove_orphans: false,
+            mirror_plans: vec![],
+            mirror_message: "".to_owned(),
+            mirror_is_error: false,
+            is_sync_opened: false,
+            sync_text: "".to_owned(),
+            sync_path: "sha1.txt".to_owned(),
+            sync_progress: (0, 0),
+            sync_refresh: vec![],
+            sync_message: "".to_owned(),
+            sync_is_error: false,
+            sync_flower: SyncFlower::new(2),
 
             flower: TypedFlower::new(1),
             file_path: PathBuf::from("."),
             file_path_dialog: im_native_dialog::ImNativeFileDialog::default(),
             i18n: crate::i18n::create_french_messages(),
+        };
+        app.check_keys();
+        app
+    }
+
+    /// verify the key records on load, warning about the ones changed outside of the application
+    /// and the public key changes waiting for the operator
+    fn check_keys(&mut self) {
+        let keys = match self.db.get_all() {
+            Ok(keys) => keys,
+            Err(e) => {
+                error!("cannot read the keys : {}", e);
+                return;
+            }
+        };
+        let tampered: Vec<String> = keys
+            .iter()
+            .filter(|k| self.db.verify_integrity(k).is_altered())
+            .map(|k| k.to_string())
+            .collect();
+        if !tampered.is_empty() {
+            error!(
+                "keys failing their integrity check : {}",
+                tampered.join(", ")
+            );
+            self.last_message = format!(
+                "ATTENTION : clés modifiées en dehors de l'application : {}",
+                tampered.join(", ")
+            );
+            self.is_error = true;
+        }
+
+        let pending = self.db.get_pending_key_changes().unwrap_or_default();
+        if !pending.is_empty() {
+            if !self.last_message.is_empty() {
+                self.last_message += "\n";
+            }
+            self.last_message += &format!(
+                "ATTENTION : {} clés publiques modifiées, à accepter ou refuser dans la gestion des clés",
+                pending.len()
+            );
+            self.is_error = true;
+        }
+    }
+
+    /// label of a key in the selectors, with a warning for a record failing its integrity check
+    fn key_label(integrity: IntegrityStatus, k: &Key) -> RichText {
+        if integrity.is_altered() {
+            RichText::new(format!("⚠ CLÉ ALTÉRÉE ⚠ {}", text_representation(k)))
+                .color(Color32::RED)
+                .strong()
+        } else if k.validity(unix_time()) != KeyValidity::Valid {
+            RichText::new(format!(
+                "⛔ {} (refusée, {})",
+                text_representation(k),
+                k.validity_message(unix_time()).unwrap_or_default()
+            ))
+            .color(Color32::RED)
+        } else {
+            RichText::new(text_representation(k))
         }
     }
-}
 
-impl EncrypterApp {
     /// Called once before the first frame.
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
         // This is also where you can customize the look and feel of egui using
@@ -158,12 +782,97 @@ impl EncrypterApp {
         EncrypterApp::install_style(ctx);
 
         if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            let mut app: EncrypterApp =
+                eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            if app.database_path.is_some() {
+                app.reopen_database();
+            }
+            if app.music_only {
+                let layout = app.output_layout().unwrap_or_default();
+                app.files_folder = EncrypterApp::open_tree(".", true, &layout);
+            }
+            return app;
         }
 
         Default::default()
     }
 
+    /// reopen the key database, from the configured location,
+    /// returns false when it can't be opened and the current store is kept
+    fn reopen_database(&mut self) -> bool {
+        let path = resolve_database_path(self.database_path.as_deref());
+        match open_protected(&path) {
+            Ok(db) => {
+                self.db = Box::new(db);
+                self.database_error = None;
+                self.selected = None;
+                self.keys_changed();
+                self.check_keys();
+                true
+            }
+            Err(e) => {
+                self.database_failed(&path, e.as_ref());
+                false
+            }
+        }
+    }
+
+    fn database_failed(&mut self, path: &Path, e: &dyn std::error::Error) {
+        error!("cannot open key database {} : {}", path.display(), e);
+        self.database_error = Some(format!(
+            "Impossible d'ouvrir la base de clés {} : {}",
+            path.display(),
+            e
+        ));
+        self.database_input = path.display().to_string();
+    }
+
+    /// open the key database typed by the user, the configured location
+    /// is kept when it can't be opened
+    fn choose_database(&mut self) {
+        let input = self.database_input.trim();
+        if input.is_empty() {
+            return;
+        }
+        let previous = self.database_path.replace(PathBuf::from(input));
+        if !self.reopen_database() {
+            self.database_path = previous;
+        }
+    }
+
+    /// location of the key database in use, and another one to open
+    /// when it could not be opened
+    fn show_database(&mut self, ui: &mut Ui) {
+        match self.db.path() {
+            Some(db_path) => {
+                ui.label(
+                    RichText::new(format!("Base de clés : {}", db_path.display()))
+                        .small()
+                        .color(Color32::GRAY),
+                );
+            }
+            None => {
+                ui.colored_label(
+                    Color32::RED,
+                    "Base de clés temporaire, les clés ne sont pas enregistrées",
+                );
+            }
+        }
+        if self.database_error.is_none() && self.db.path().is_some() {
+            return;
+        }
+        if let Some(e) = &self.database_error {
+            ui.colored_label(Color32::RED, e);
+        }
+        ui.horizontal(|ui| {
+            ui.label("Emplacement de la base :");
+            ui.text_edit_singleline(&mut self.database_input);
+            if ui.button("Ouvrir").clicked() {
+                self.choose_database();
+            }
+        });
+    }
+
     /**
      * install style
      */
@@ -255,37 +964,162 @@ impl EncrypterApp {
         ctx.set_fonts(fonts);
     }
 
-    /// recursive function to display files
-    fn display_tree(files_folder: &mut FolderNode, ui: &mut Ui) -> crate::Result<()> {
+    /// filters of the tree, without the encrypted files of the layout
+    fn tree_options(music_only: bool, layout: &OutputLayout) -> WalkOptions {
+        match music_only {
+            true => WalkOptions::music(),
+            false => WalkOptions::default(),
+        }
+        .with_outputs(layout)
+    }
+
+    /// filters of the tree for the current layout, the default layout when the
+    /// template is invalid
+    fn current_tree_options(&self) -> WalkOptions {
+        let layout = self.output_layout().unwrap_or_default();
+        EncrypterApp::tree_options(self.music_only, &layout)
+    }
+
+    /// location of the encrypted files
+    fn output_layout(&self) -> std::result::Result<OutputLayout, OutputError> {
+        OutputLayout::new(self.output_root.trim(), self.output_template.trim())
+    }
+
+    /// tree of the folder, with its first level expanded
+    fn open_tree<P: AsRef<Path>>(path: P, music_only: bool, layout: &OutputLayout) -> FolderNode {
+        let mut r = FolderNode::new_root(path);
+        expand_with(&mut r, &EncrypterApp::tree_options(music_only, layout));
+        r
+    }
+
+    /// read again the expanded folders of the tree
+    fn refresh_tree(&mut self) {
+        self.tree_status.clear();
+        self.size_estimate.clear();
+        let options = self.current_tree_options();
+        for folder in expanded_folders(&self.files_folder) {
+            if let Some(node) = find_mut(&mut self.files_folder, &folder) {
+                refresh_with(node, &options);
+            }
+        }
+    }
+
+    /// refresh the expanded folders whose content changed on the disk
+    fn watch_tree(&mut self) {
+        self.watcher.watch(&expanded_folders(&self.files_folder));
+        let options = self.current_tree_options();
+        let changed = self.watcher.changed();
+        if !changed.is_empty() {
+            self.tree_status.forget(&changed);
+            self.size_estimate.clear();
+        }
+        for folder in changed {
+            match find_mut(&mut self.files_folder, &folder) {
+                Some(node) if node.expanded => {
+                    debug!("{} changed, refreshing", folder.display());
+                    refresh_with(node, &options);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// follow the selected key, the status of the files is given for a single key
+    fn update_tree_status(&mut self) {
+        let key = match (&self.selected, &self.selected_group) {
+            (Some(k), None) => k,
+            _ => {
+                self.tree_status.invalidate();
+                return;
+            }
+        };
+        let layout = match self.output_layout() {
+            Ok(layout) => layout,
+            Err(_) => {
+                self.tree_status.invalidate();
+                return;
+            }
+        };
+        let root = &self.files_folder.path;
+        let current = self.tree_status.checker.as_ref();
+        if current.map_or(false, |c| {
+            c.sha1() == key.sha1
+                && c.key_name() == key.name
+                && *c.layout() == layout
+                && c.source_root() == root
+        }) {
+            return;
+        }
+        self.tree_status.invalidate();
+        match StatusChecker::new(self.db.as_ref(), &key.name, &key.sha1) {
+            Ok(checker) => {
+                self.tree_status.checker = Some(Arc::new(checker.with_layout(layout, root)))
+            }
+            Err(e) => error!("fail to read the encryptions of {} : {}", key.sha1, e),
+        }
+    }
+
+    /// badge of the encryption status of a file for the selected key
+    fn status_badge(ui: &mut Ui, status: EncryptionStatus) {
+        let (text, color) = match status {
+            EncryptionStatus::Missing => ("non chiffré", Color32::GRAY),
+            EncryptionStatus::UpToDate => ("chiffré", Color32::DARK_GREEN),
+            EncryptionStatus::Stale => (
+                "modifié depuis le chiffrement",
+                Color32::from_rgb(220, 120, 0),
+            ),
+        };
+        ui.label(RichText::new(text).small().color(color));
+    }
+
+    /// recursive function to display files, the entries which can't be read
+    /// are shown with their error
+    fn display_tree(
+        files_folder: &mut FolderNode,
+        options: &WalkOptions,
+        status: &mut TreeStatus,
+        ui: &mut Ui,
+    ) {
         for ele in &mut files_folder.subfolders {
             let element_name = String::from(ele.name());
             if ele.is_folder {
                 let r = ui.collapsing(element_name, |ui| {
                     ui.spacing_mut().item_spacing.x = 0.0;
+                    if let Some(error) = &ele.error {
+                        ui.label(
+                            RichText::new(format!("illisible : {}", error)).color(Color32::RED),
+                        );
+                        return;
+                    }
                     if ui
                         .button("Selectionner tous les fichiers du répertoire")
                         .clicked()
                     {
                         // handle selection
                         for e in &mut ele.subfolders {
-                            if !e.is_folder {
+                            if !e.is_folder && e.error.is_none() {
                                 e.selected = true;
                             }
                         }
                     }
 
-                    if let Err(e) = EncrypterApp::display_tree(ele, ui) {
-                        error!("error in displaying sub tree {}", e);
-                    }
+                    EncrypterApp::display_tree(ele, options, status, ui);
                 });
                 if r.fully_open() && !ele.expanded {
-                    expand(ele)?;
+                    expand_with(ele, options);
                 }
+            } else if let Some(error) = &ele.error {
+                ui.label(RichText::new(format!("⚠ {}", element_name)).color(Color32::RED))
+                    .on_hover_text(error);
             } else {
-                ui.checkbox(&mut ele.selected, element_name);
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut ele.selected, element_name);
+                    if let Some(s) = status.get(&ele.path) {
+                        EncrypterApp::status_badge(ui, s);
+                    }
+                });
             }
         }
-        Ok(())
     }
 
     fn construct_list(file_folder: &mut FolderNode, ui: &mut Ui) {
@@ -302,42 +1136,88 @@ impl EncrypterApp {
         }
     }
 
+    /// encrypt the selected files for a key, a revoked or expired key is refused
+    /// unless the operator explicitly overrides it
     fn crypt_selected(
         file_folder: &FolderNode,
-        keyname: &String,
-        sha1: &String,
+        layout: &OutputLayout,
+        keyname: &str,
+        sha1: &str,
         key: &[u8],
+        db: &dyn KeyStore,
+        allow_unusable: bool,
     ) -> crate::Result<()> {
-        if file_folder.selected {
-            let filename = file_folder.name().to_string();
-
-            let folder_name = keyname.clone() + "-" + sha1;
-            if !Path::new(&folder_name).try_exists()? {
-                std::fs::create_dir(folder_name.clone())?;
-            }
-
-            let o_conversion = Path::new(&folder_name)
-                .join(filename.clone() + "x")
-                .into_os_string()
-                .into_string();
-
-            if let Ok(s) = o_conversion {
-                let output_filename: String = s;
-                encrypt_file_with_inmemory_key(&file_folder.path.clone(), &output_filename, key)?;
-                info!(" file {}, encrypted", &output_filename);
-            } else {
-                let msg = format!("fail to create file for {}", &filename);
+        if !allow_unusable {
+            if let Some(reason) = db.get_by_sha1(sha1)?.validity_message(unix_time()) {
+                let msg = format!("la clé {} ne peut pas être utilisée, {}", sha1, reason);
                 return Err(AppError::new(msg).into());
             }
         }
+        EncrypterApp::crypt_selected_files(file_folder, layout, keyname, sha1, key, db)
+    }
 
-        for elem in &file_folder.subfolders {
-            EncrypterApp::crypt_selected(elem, keyname, sha1, key)?;
+    /// the sub folders of the selected files are kept under the folder of the tree,
+    /// nothing is encrypted when two files have the same output
+    fn crypt_selected_files(
+        file_folder: &FolderNode,
+        layout: &OutputLayout,
+        keyname: &str,
+        sha1: &str,
+        key: &[u8],
+        db: &dyn KeyStore,
+    ) -> crate::Result<()> {
+        let sources = folder::selected_paths(file_folder);
+        let outputs = layout.paths(&sources, &file_folder.path, keyname, sha1)?;
+        for (source, output_filename) in sources.iter().zip(outputs.iter()) {
+            if let Some(folder_name) = output_filename.parent() {
+                std::fs::create_dir_all(folder_name)?;
+            }
+            encrypt_recorded(db, source, output_filename, key, sha1)?;
         }
         Ok(())
     }
 
-    fn download_key(flower: &TypedFlower, sha1: String) {
+    /// key sources of the lookup choice, the key folder is only used when set
+    fn key_provider(
+        lookup: KeyLookup,
+        key_folder: &str,
+        server: &KeyServerClient,
+    ) -> std::result::Result<Box<dyn KeyProvider>, String> {
+        if !lookup.is_ready(key_folder) {
+            return Err("choisissez le dossier de clés".into());
+        }
+        let key_folder = key_folder.trim();
+        Ok(match lookup {
+            KeyLookup::Server => Box::new(server.clone()),
+            KeyLookup::Folder => Box::new(FolderKeySource::new(key_folder)),
+            KeyLookup::AllSources => {
+                let mut sources = KeySources::from_env();
+                if !key_folder.is_empty() {
+                    sources.push_first(Box::new(FolderKeySource::new(key_folder)));
+                }
+                Box::new(sources)
+            }
+        })
+    }
+
+    /// choice of the key sources, shared by the add and synchronization windows
+    fn show_key_lookup(ui: &mut Ui, lookup: &mut KeyLookup, key_folder: &mut String) {
+        ui.horizontal(|ui| {
+            ui.label("Chercher la clé :");
+            ui.radio_value(lookup, KeyLookup::AllSources, "dans toutes les sources");
+            ui.radio_value(lookup, KeyLookup::Server, "sur le serveur");
+            ui.radio_value(lookup, KeyLookup::Folder, "dans le dossier de clés");
+        });
+        ui.horizontal(|ui| {
+            ui.label("Dossier de clés (clé USB, partage) :");
+            ui.text_edit_singleline(key_folder);
+        });
+        if !lookup.is_ready(key_folder) {
+            ui.label(RichText::new("choisissez le dossier de clés").color(Color32::RED));
+        }
+    }
+
+    fn download_key(flower: &TypedFlower, provider: Box<dyn KeyProvider>, sha1: String) {
         std::thread::spawn({
             let handle = flower.handle();
             // Activate
@@ -345,47 +1225,1341 @@ impl EncrypterApp {
             move || {
                 handle.send("start".into());
 
-                let response_result =
-                    isahc::get("http://or1.frett27.net/k/".to_string() + &sha1 + "/public.key.pem");
-
-                match response_result {
-                    Ok(mut response) => {
-                        if response.status().is_success() {
-                            let text = response.text(); // read response
+                match provider.fetch(&sha1) {
+                    Ok(fetched) => {
+                        // Set result and then extract later.
+                        handle.set_result(Ok(fetched));
+                    }
+                    Err(e) => {
+                        handle.set_result(Err(Box::new(AppError::new(e.to_string()))));
+                    }
+                }
+            }
+        });
+    }
 
-                            match text {
-                                Ok(returned_elements) => {
-                                    // Set result and then extract later.
-                                    handle.set_result(Ok(returned_elements));
+    /// run a synchronization of the keys in the background,
+    /// the progress is sent through the flower
+    fn spawn_sync<F>(flower: &SyncFlower, job: F)
+    where
+        F: FnOnce(&mut dyn FnMut(usize, usize)) -> SyncOutcome + Send + 'static,
+    {
+        std::thread::spawn({
+            let handle = flower.handle();
+            handle.activate();
+            move || {
+                let outcome = job(&mut |done, total| handle.send((done, total)));
+                handle.set_result(Ok(outcome));
+            }
+        });
+    }
+
+    /// key synchronization window, bulk download of a sha1 list
+    /// and check of the stored keys against the key sources
+    fn show_key_sync(&mut self, ctx: &Context) {
+        egui::Window::new("Synchronisation des clés").show(ctx, |ui| {
+            let busy = self.sync_flower.is_active();
+
+            EncrypterApp::show_key_lookup(ui, &mut self.key_lookup, &mut self.key_folder);
+            let ready = self.key_lookup.is_ready(&self.key_folder);
+            ui.separator();
+
+            ui.label("Sha1 des clés à récupérer (un par ligne) :");
+            ui.add(
+                egui::TextEdit::multiline(&mut self.sync_text)
+                    .desired_rows(8)
+                    .desired_width(500.0),
+            );
+            ui.horizontal(|ui| {
+                ui.label("Fichier :");
+                ui.text_edit_singleline(&mut self.sync_path);
+                if ui.button("Lire le fichier").clicked() {
+                    match read_sha1_list(Path::new(self.sync_path.trim())) {
+                        Ok(list) => {
+                            self.sync_text = list.sha1.join("\n");
+                            self.sync_message = format!("{} sha1 lus", list.sha1.len());
+                            self.sync_is_error = false;
+                            if !list.invalid.is_empty() {
+                                self.sync_message +=
+                                    &format!(", ignorés : {}", list.invalid.join(", "));
+                                self.sync_is_error = true;
+                            }
+                        }
+                        Err(e) => {
+                            self.sync_message = format!("{}", e);
+                            self.sync_is_error = true;
+                        }
+                    }
+                }
+            });
+
+            ui.horizontal(|ui| {
+                if ui
+                    .add_enabled(!busy && ready, Button::new("Récupérer les clés"))
+                    .clicked()
+                {
+                    let provider = match EncrypterApp::key_provider(
+                        self.key_lookup,
+                        &self.key_folder,
+                        &self.key_server,
+                    ) {
+                        Ok(provider) => provider,
+                        Err(e) => {
+                            self.sync_message = e;
+                            self.sync_is_error = true;
+                            return;
+                        }
+                    };
+                    let list = parse_sha1_list(&self.sync_text);
+                    if list.invalid.is_empty() {
+                        self.sync_message = "".into();
+                        self.sync_is_error = false;
+                    } else {
+                        self.sync_message = format!("ignorés : {}", list.invalid.join(", "));
+                        self.sync_is_error = true;
+                    }
+                    self.sync_refresh = vec![];
+                    self.sync_progress = (0, list.sha1.len());
+                    EncrypterApp::spawn_sync(&self.sync_flower, move |progress| {
+                        SyncOutcome::Fetched(fetch_keys(
+                            provider.as_ref(),
+                            &list.sha1,
+                            DEFAULT_WORKERS,
+                            progress,
+                        ))
+                    });
+                }
+                if ui
+                    .add_enabled(!busy && ready, Button::new("Vérifier toutes les clés"))
+                    .on_hover_text("compare les clés enregistrées à celles des sources, sans les modifier")
+                    .clicked()
+                {
+                    let provider = EncrypterApp::key_provider(
+                        self.key_lookup,
+                        &self.key_folder,
+                        &self.key_server,
+                    );
+                    let keys = self.db.get_all().map_err(|e| e.to_string());
+                    match keys.and_then(|keys| Ok((keys, provider?))) {
+                        Ok((keys, provider)) => {
+                            self.sync_message = "".into();
+                            self.sync_is_error = false;
+                            self.sync_refresh = vec![];
+                            self.sync_progress = (0, keys.len());
+                            EncrypterApp::spawn_sync(&self.sync_flower, move |progress| {
+                                SyncOutcome::Refreshed(refresh_keys(
+                                    keys,
+                                    provider.as_ref(),
+                                    DEFAULT_WORKERS,
+                                    progress,
+                                ))
+                            });
+                        }
+                        Err(e) => {
+                            self.sync_message = e;
+                            self.sync_is_error = true;
+                        }
+                    }
+                }
+            });
+
+            if busy {
+                let (done, total) = self.sync_progress;
+                ui.add(
+                    egui::ProgressBar::new(done as f32 / total.max(1) as f32)
+                        .text(format!("{} / {}", done, total)),
+                );
+                ctx.request_repaint();
+
+                let mut outcome = None;
+                self.sync_flower
+                    .extract(|progress| self.sync_progress = progress)
+                    .finalize(|result| outcome = Some(result));
+                match outcome {
+                    Some(Ok(SyncOutcome::Fetched(fetched))) => {
+                        match record_fetched(self.db.as_ref(), &fetched) {
+                            Ok(report) => {
+                                self.sync_message = format!(
+                                    "{} clés ajoutées, {} mises à jour, {} inchangées",
+                                    report.added, report.updated, report.unchanged
+                                );
+                                self.sync_is_error = false;
+                                if report.pending > 0 {
+                                    self.sync_message += &format!(
+                                        ", ATTENTION : {} clés publiques modifiées, à accepter dans la gestion des clés",
+                                        report.pending
+                                    );
+                                    self.sync_is_error = true;
                                 }
-                                Err(e) => {
-                                    handle.set_result(Err(Box::new(AppError::new(format!(
-                                        "Erreur dans la récupération du contenu de la clé : {:?}",
-                                        e
-                                    )))));
+                                for (sha1, e) in report.failed.iter() {
+                                    self.sync_message += &format!("\n{} : {}", sha1, e);
+                                    self.sync_is_error = true;
                                 }
                             }
-                        } else {
-                            handle.set_result(Err(Box::new(AppError::new(format!(
-                "Le serveur a retourné un pb, la clé n'existe peut être pas : {:?} -> {:?}", 
-                    response.status(), response.body()
-            )))));
+                            Err(e) => {
+                                self.sync_message = format!("{}", e);
+                                self.sync_is_error = true;
+                            }
                         }
+                        self.keys_changed();
                     }
-                    Err(e) => {
-                        handle.set_result(Err(Box::new(AppError::new(format!(
-                            "Erreur dans le lancement de la requete : {:?}",
-                            e
-                        )))));
+                    Some(Ok(SyncOutcome::Refreshed(entries))) => {
+                        let unchanged = entries.iter().filter(|e| e.is_unchanged()).count();
+                        self.sync_message = format!(
+                            "{} clés identiques dans les sources, {} différences",
+                            unchanged,
+                            entries.len() - unchanged
+                        );
+                        self.sync_is_error = unchanged < entries.len();
+                        self.sync_refresh = entries;
+                    }
+                    Some(Err(Cause::Suppose(msg))) | Some(Err(Cause::Panicked(msg))) => {
+                        self.sync_message = msg;
+                        self.sync_is_error = true;
+                    }
+                    None => {}
+                }
+            }
+
+            if !self.sync_message.is_empty() {
+                let mut rt = RichText::new(&self.sync_message);
+                if self.sync_is_error {
+                    rt = rt.color(Color32::RED);
+                }
+                ui.label(rt);
+            }
+
+            if self.sync_refresh.iter().any(|e| !e.is_unchanged()) {
+                ui.separator();
+                egui::ScrollArea::vertical()
+                    .id_source("sync_refresh")
+                    .max_height(250.0)
+                    .show(ui, |ui| {
+                        egui::Grid::new("sync_refresh_grid")
+                            .striped(true)
+                            .show(ui, |ui| {
+                                for e in self.sync_refresh.iter().filter(|e| !e.is_unchanged()) {
+                                    ui.label(&e.key.name);
+                                    ui.label(&e.key.sha1);
+                                    match &e.status {
+                                        RefreshStatus::Changed(fetched) => ui.label(
+                                            RichText::new(format!(
+                                                "clé publique différente ({})",
+                                                fingerprint(&fetched.public_key)
+                                            ))
+                                            .color(Color32::RED),
+                                        ),
+                                        RefreshStatus::Missing => {
+                                            ui.label("absente des sources")
+                                        }
+                                        RefreshStatus::Failed(err) => ui.label(
+                                            RichText::new(format!("{}", err)).color(Color32::RED),
+                                        ),
+                                        RefreshStatus::Unchanged => ui.label(""),
+                                    };
+                                    ui.end_row();
+                                }
+                            });
+                    });
+                let changed = changed_keys(&self.sync_refresh);
+                if !changed.is_empty()
+                    && ui
+                        .button("Enregistrer les clés modifiées (à accepter dans la gestion des clés)")
+                        .clicked()
+                {
+                    match record_fetched(self.db.as_ref(), &changed) {
+                        Ok(report) => {
+                            self.sync_message = format!(
+                                "{} clés mises à jour, {} clés publiques modifiées à accepter dans la gestion des clés",
+                                report.updated, report.pending
+                            );
+                            self.sync_is_error = false;
+                        }
+                        Err(e) => {
+                            self.sync_message = format!("{}", e);
+                            self.sync_is_error = true;
+                        }
+                    }
+                    self.sync_refresh = vec![];
+                    self.keys_changed();
+                    self.check_keys();
+                }
+            }
+
+            if ui.button("Fermer").clicked() {
+                self.is_sync_opened = false;
+            }
+        });
+    }
+
+    /// key management window, rename, update and removal of the stored keys
+    fn show_key_management(&mut self, ctx: &Context) {
+        self.build_key_index();
+        if self.pending_key_changes.is_none() {
+            self.pending_key_changes = Some(self.db.get_pending_key_changes().unwrap_or_default());
+        }
+        egui::Window::new("Gestion des clés").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Rechercher (nom, sha1, propriétaire, instrument, notes, tag:groupe) :");
+                ui.text_edit_singleline(&mut self.manage_filter);
+            });
+            ui.separator();
+
+            let pending = self.pending_key_changes.clone().unwrap_or_default();
+            if !pending.is_empty() {
+                ui.label(
+                    RichText::new("⚠ Clés publiques modifiées, à accepter ou refuser")
+                        .color(Color32::RED)
+                        .strong(),
+                );
+                egui::Grid::new("pending_key_changes_grid")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for change in pending.iter() {
+                            let current = self
+                                .key_index
+                                .as_ref()
+                                .and_then(|index| index.get(&change.sha1))
+                                .cloned();
+                            ui.label(
+                                current
+                                    .as_ref()
+                                    .map(text_representation)
+                                    .unwrap_or_else(|| change.sha1.clone()),
+                            );
+                            ui.label(format!(
+                                "{} -> {}",
+                                current
+                                    .as_ref()
+                                    .and_then(|k| k.public_key.as_deref())
+                                    .map(fingerprint)
+                                    .unwrap_or_else(|| "-".into()),
+                                fingerprint(&change.public_key)
+                            ));
+                            ui.label(format_timestamp(change.seen_at));
+                            if ui.button("Accepter").clicked() {
+                                self.decide_key_change(change, true);
+                            }
+                            if ui.button("Refuser").clicked() {
+                                self.decide_key_change(change, false);
+                            }
+                            ui.end_row();
+                        }
+                    });
+                ui.separator();
+            }
+
+            let keys: Vec<Key> = match &self.key_index {
+                Some(index) => index
+                    .search(&KeyQuery::parse(&self.manage_filter))
+                    .into_iter()
+                    .cloned()
+                    .collect(),
+                None => vec![],
+            };
+
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    egui::Grid::new("keys_management_grid")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for k in keys.iter() {
+                                ui.label(&k.name);
+                                ui.label(&k.sha1);
+                                ui.label(&k.owner);
+                                ui.label(
+                                    format!("{} {}", k.instrument_model, k.instrument_serial)
+                                        .trim(),
+                                );
+                                if ui.button("Modifier").clicked() {
+                                    self.manage_edit = Some(KeyEdit::from_key(k, self.db.as_ref()));
+                                    self.manage_delete_pending = None;
+                                }
+                                if ui.button("Supprimer").clicked() {
+                                    self.manage_delete_pending = Some(k.sha1.clone());
+                                    self.manage_edit = None;
+                                }
+                                ui.end_row();
+                            }
+                        });
+                });
+
+            ui.separator();
+            ui.collapsing("Groupes de clés", |ui| {
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut self.manage_new_group);
+                    let new_group = self.manage_new_group.trim().to_string();
+                    if ui
+                        .add_enabled(!new_group.is_empty(), Button::new("Créer le groupe"))
+                        .clicked()
+                    {
+                        match self.db.create_group(&new_group) {
+                            Ok(_) => {
+                                self.manage_new_group = "".into();
+                                self.keys_changed();
+                            }
+                            Err(e) => {
+                                self.manage_message = format!("{}", e);
+                                self.manage_is_error = true;
+                            }
+                        }
+                    }
+                });
+                let groups: Vec<(String, usize)> = match &self.key_index {
+                    Some(index) => index
+                        .groups()
+                        .into_iter()
+                        .map(|g| (g.to_string(), index.group_members(g).len()))
+                        .collect(),
+                    None => vec![],
+                };
+                for (group, count) in groups {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{} ({} clés)", group, count));
+                        if ui.button("Supprimer le groupe").clicked() {
+                            if let Err(e) = self.db.delete_group(&group) {
+                                self.manage_message = format!("{}", e);
+                                self.manage_is_error = true;
+                            }
+                            if self.selected_group.as_ref() == Some(&group) {
+                                self.selected_group = None;
+                            }
+                            self.manage_edit = None;
+                            self.keys_changed();
+                        }
+                    });
+                }
+            });
+
+            if let Some(sha1) = self.manage_delete_pending.clone() {
+                ui.separator();
+                ui.label(
+                    RichText::new(format!("Supprimer définitivement la clé {} ?", sha1))
+                        .color(Color32::RED),
+                );
+                ui.horizontal(|ui| {
+                    if ui.button("Confirmer la suppression").clicked() {
+                        self.manage_delete_pending = None;
+                        match self.db.delete(&sha1) {
+                            Ok(_) => {
+                                self.keys_changed();
+                                self.manage_message = "clé ".to_string() + &sha1 + " supprimée";
+                                self.manage_is_error = false;
+                            }
+                            Err(e) => {
+                                self.manage_message = format!("{}", e);
+                                self.manage_is_error = true;
+                            }
+                        }
+                    }
+                    if ui.button("Annuler").clicked() {
+                        self.manage_delete_pending = None;
+                    }
+                });
+            }
+
+            if let Some(mut edit) = self.manage_edit.take() {
+                let mut keep_editing = true;
+                ui.separator();
+                ui.label(format!("Modification de la clé {}", edit.sha1));
+                ui.horizontal(|ui| {
+                    ui.label("Nom de la clé");
+                    ui.text_edit_singleline(&mut edit.name);
+                });
+                ui.label(RichText::new(&edit.info).small().color(Color32::GRAY));
+                ui.horizontal(|ui| {
+                    ui.label("Propriétaire");
+                    ui.text_edit_singleline(&mut edit.owner);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Modèle d'instrument");
+                    ui.text_edit_singleline(&mut edit.instrument_model);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Numéro de série");
+                    ui.text_edit_singleline(&mut edit.instrument_serial);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Notes");
+                    ui.text_edit_multiline(&mut edit.notes);
+                });
+                ui.horizontal(|ui| {
+                    ui.label("Clé Publique :");
+                    ui.text_edit_multiline(&mut edit.public_key);
+                });
+                if !edit.groups.is_empty() {
+                    ui.horizontal_wrapped(|ui| {
+                        ui.label("Groupes :");
+                        for (group, member) in edit.groups.iter_mut() {
+                            ui.checkbox(member, group.as_str());
+                        }
+                    });
+                }
+                ui.horizontal(|ui| {
+                    ui.label("Expire le (AAAA-MM-JJ)");
+                    ui.text_edit_singleline(&mut edit.expires_at);
+                });
+                let mut revocation_changed = false;
+                ui.horizontal(|ui| match edit.revoked_at {
+                    Some(revoked_at) => {
+                        ui.label(
+                            RichText::new(format!(
+                                "Révoquée le {} : {}",
+                                format_timestamp(revoked_at),
+                                edit.revocation_reason
+                            ))
+                            .color(Color32::RED),
+                        );
+                        if ui.button("Annuler la révocation").clicked() {
+                            if let Err(e) = self.db.reinstate(&edit.sha1) {
+                                self.manage_message = format!("{}", e);
+                                self.manage_is_error = true;
+                            }
+                            revocation_changed = true;
+                        }
+                    }
+                    None => {
+                        ui.label("Motif de révocation");
+                        ui.text_edit_singleline(&mut edit.revocation_reason);
+                        if ui.button("Révoquer la clé").clicked() {
+                            let revocation = Revocation {
+                                sha1: edit.sha1.clone(),
+                                revoked_at: unix_time(),
+                                reason: edit.revocation_reason.trim().to_string(),
+                            };
+                            if let Err(e) = self.db.revoke(&revocation) {
+                                self.manage_message = format!("{}", e);
+                                self.manage_is_error = true;
+                            }
+                            revocation_changed = true;
+                        }
+                    }
+                });
+                if revocation_changed {
+                    self.keys_changed();
+                    if let Ok(k) = self.db.get_by_sha1(&edit.sha1) {
+                        edit.revoked_at = k.revoked_at;
+                        edit.revocation_reason = k.revocation_reason.clone();
+                    }
+                }
+                let mut decision = None;
+                ui.collapsing("Historique des clés publiques", |ui| {
+                    egui::Grid::new("key_history_grid")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for entry in edit.history.iter() {
+                                let is_current =
+                                    entry.public_key == edit.original_public_key.as_bytes();
+                                ui.label(fingerprint(&entry.public_key));
+                                ui.label(format_timestamp(entry.seen_at));
+                                ui.label(if is_current {
+                                    "actuelle"
+                                } else {
+                                    match entry.trust {
+                                        KeyTrust::Pending => "à valider",
+                                        KeyTrust::Accepted => "ancienne",
+                                        KeyTrust::Rejected => "refusée",
+                                    }
+                                });
+                                if !is_current && entry.trust != KeyTrust::Rejected {
+                                    let label = if entry.trust == KeyTrust::Pending {
+                                        "Accepter"
+                                    } else {
+                                        "Restaurer"
+                                    };
+                                    if ui.button(label).clicked() {
+                                        decision = Some(entry.clone());
+                                    }
+                                }
+                                ui.end_row();
+                            }
+                        });
+                });
+                if let Some(entry) = decision {
+                    self.decide_key_change(&entry, true);
+                    if let Ok(k) = self.db.get_by_sha1(&edit.sha1) {
+                        edit = KeyEdit::from_key(&k, self.db.as_ref());
+                    }
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Enregistrer").clicked() {
+                        match self.save_key_edit(&edit) {
+                            Ok(_) => {
+                                self.manage_message =
+                                    "clé ".to_string() + &edit.sha1 + " enregistrée";
+                                self.manage_is_error = false;
+                                keep_editing = false;
+                            }
+                            Err(e) => {
+                                self.manage_message = format!("{}", e);
+                                self.manage_is_error = true;
+                            }
+                        }
+                    }
+                    if ui.button("Annuler").clicked() {
+                        keep_editing = false;
+                    }
+                });
+                if keep_editing {
+                    self.manage_edit = Some(edit);
+                }
+            }
+
+            if !self.manage_message.is_empty() {
+                let mut rt = RichText::new(&self.manage_message);
+                if self.manage_is_error {
+                    rt = rt.color(Color32::RED);
+                }
+                ui.label(rt);
+            }
+
+            if ui.button("Fermer").clicked() {
+                self.is_manage_opened = false;
+            }
+        });
+    }
+
+    /// key book window, export and import of all the keys
+    fn show_key_book(&mut self, ctx: &Context) {
+        egui::Window::new("Import / export des clés").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Fichier ou répertoire :");
+                ui.text_edit_singleline(&mut self.book_path);
+            });
+            ui.horizontal(|ui| {
+                ui.label("Format :");
+                ui.radio_value(&mut self.book_format, KeyBookFormat::Json, "JSON");
+                ui.radio_value(&mut self.book_format, KeyBookFormat::Csv, "CSV");
+                ui.radio_value(
+                    &mut self.book_format,
+                    KeyBookFormat::PemBundle,
+                    "Fichiers PEM",
+                );
+            });
+
+            let path = PathBuf::from(self.book_path.trim());
+
+            ui.horizontal(|ui| {
+                if ui.button("Exporter").clicked() {
+                    match export_keys(self.db.as_ref(), self.book_format, &path) {
+                        Ok(count) => {
+                            self.book_message = format!("{} clés exportées", count);
+                            self.book_is_error = false;
+                        }
+                        Err(e) => {
+                            self.book_message = format!("{}", e);
+                            self.book_is_error = true;
+                        }
+                    }
+                }
+                if ui.button("Lire le fichier").clicked() {
+                    self.book_preview = None;
+                    match read_key_book(self.book_format, &path)
+                        .and_then(|records| Ok((preview_import(self.db.as_ref(), &records)?, records)))
+                    {
+                        Ok((preview, records)) => {
+                            self.book_message = format!("{} clés lues", records.len());
+                            self.book_is_error = false;
+                            self.book_records = records;
+                            self.book_preview = Some(preview);
+                        }
+                        Err(e) => {
+                            self.book_message = format!("{}", e);
+                            self.book_is_error = true;
+                        }
+                    }
+                }
+            });
+
+            if let Some(preview) = &self.book_preview {
+                ui.separator();
+                ui.label(format!(
+                    "{} nouvelles clés, {} clés déjà connues, {} conflits",
+                    preview.new_keys.len(),
+                    preview.existing.len(),
+                    preview.conflicts.len()
+                ));
+                for c in preview.conflicts.iter() {
+                    ui.label(
+                        RichText::new(format!(
+                            "{} : la clé publique de \"{}\" est différente de celle importée (\"{}\")",
+                            c.existing.sha1, c.existing.name, c.imported.name
+                        ))
+                        .color(Color32::RED),
+                    );
+                }
+
+                ui.horizontal(|ui| {
+                    ui.label("Clés existantes :");
+                    ui.radio_value(&mut self.book_strategy, ImportStrategy::Merge, "Compléter");
+                    ui.radio_value(
+                        &mut self.book_strategy,
+                        ImportStrategy::Overwrite,
+                        "Remplacer",
+                    );
+                    ui.radio_value(
+                        &mut self.book_strategy,
+                        ImportStrategy::SkipExisting,
+                        "Ignorer",
+                    );
+                });
+
+                if ui.button("Importer").clicked() {
+                    match import_keys(self.db.as_ref(), &self.book_records, self.book_strategy) {
+                        Ok(report) => {
+                            self.book_message = format!(
+                                "{} clés ajoutées, {} mises à jour, {} ignorées",
+                                report.added, report.updated, report.skipped
+                            );
+                            self.book_is_error = false;
+                            if report.pending > 0 {
+                                self.book_message += &format!(
+                                    ", ATTENTION : {} clés publiques modifiées, à accepter dans la gestion des clés",
+                                    report.pending
+                                );
+                                self.book_is_error = true;
+                            }
+                        }
+                        Err(e) => {
+                            self.book_message = format!("{}", e);
+                            self.book_is_error = true;
+                        }
+                    }
+                    self.keys_changed();
+                    self.book_records = vec![];
+                    self.book_preview = None;
+                }
+            }
+
+            ui.separator();
+            ui.label("Liste de révocation");
+            ui.horizontal(|ui| {
+                ui.label("Fichier :");
+                ui.text_edit_singleline(&mut self.revocation_path);
+            });
+            ui.horizontal(|ui| {
+                let mut revocations = None;
+                if ui.button("Importer la liste").clicked() {
+                    revocations = Some(read_revocation_list(Path::new(&self.revocation_path)));
+                }
+                if ui.button("Télécharger la liste du serveur").clicked() {
+                    revocations = Some(fetch_revocation_list(&self.key_server));
+                }
+                if let Some(revocations) = revocations {
+                    match revocations.and_then(|r| apply_revocations(self.db.as_ref(), &r)) {
+                        Ok(report) => {
+                            self.book_message = format!(
+                                "{} clés révoquées, {} déjà révoquées, {} inconnues",
+                                report.revoked, report.already_revoked, report.unknown
+                            );
+                            self.book_is_error = false;
+                            self.keys_changed();
+                        }
+                        Err(e) => {
+                            self.book_message = format!("{}", e);
+                            self.book_is_error = true;
+                        }
+                    }
+                }
+            });
+
+            if !self.book_message.is_empty() {
+                let mut rt = RichText::new(&self.book_message);
+                if self.book_is_error {
+                    rt = rt.color(Color32::RED);
+                }
+                ui.label(rt);
+            }
+
+            if ui.button("Fermer").clicked() {
+                self.is_book_opened = false;
+            }
+        });
+    }
+
+    /// key selector, searching the key index as the filter is typed,
+    /// the arrows move in the found keys and enter selects the highlighted one
+    fn show_key_picker(&mut self, ui: &mut Ui) {
+        self.build_key_index();
+        ui.horizontal(|ui| {
+            ui.label("Clé :");
+            match &self.selected {
+                Some(k) => ui.label(EncrypterApp::key_label(self.key_integrity(k), k)),
+                None => ui.label(RichText::new("aucune").color(Color32::GRAY)),
+            };
+        });
+
+        let response = ui.add(
+            egui::TextEdit::singleline(&mut self.key_filter)
+                .hint_text("rechercher : nom, début du sha1, tag:..., owner:...")
+                .desired_width(500.0),
+        );
+        if response.changed() || response.gained_focus() {
+            self.key_picker_open = true;
+            self.key_picker_cursor = 0;
+        }
+        if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+            self.key_picker_open = false;
+            self.key_filter.clear();
+        }
+        if !self.key_picker_open {
+            return;
+        }
+
+        let found = match &self.key_index {
+            Some(index) => index.search(&KeyQuery::parse(&self.key_filter)),
+            None => vec![],
+        };
+        let total = found.len();
+        let found: Vec<Key> = found
+            .into_iter()
+            .take(KEY_PICKER_RESULTS)
+            .cloned()
+            .collect();
+
+        let mut moved = false;
+        if response.has_focus() {
+            if ui.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown)) {
+                self.key_picker_cursor += 1;
+                moved = true;
+            }
+            if ui.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp)) {
+                self.key_picker_cursor = self.key_picker_cursor.saturating_sub(1);
+                moved = true;
+            }
+        }
+        self.key_picker_cursor = self.key_picker_cursor.min(found.len().saturating_sub(1));
+
+        let mut chosen = None;
+        if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+            chosen = found.get(self.key_picker_cursor).cloned();
+        }
+        egui::ScrollArea::vertical()
+            .id_source("key_picker")
+            .max_height(250.0)
+            .show(ui, |ui| {
+                for (i, k) in found.iter().enumerate() {
+                    let label = EncrypterApp::key_label(self.key_integrity(k), k);
+                    let r = ui.selectable_label(i == self.key_picker_cursor, label);
+                    if moved && i == self.key_picker_cursor {
+                        r.scroll_to_me(None);
+                    }
+                    if r.clicked() {
+                        chosen = Some(k.clone());
                     }
                 }
+            });
+        if found.is_empty() {
+            ui.label(RichText::new("aucune clé trouvée").color(Color32::GRAY));
+        } else if total > found.len() {
+            ui.label(
+                RichText::new(format!(
+                    "{} autres clés, précisez la recherche",
+                    total - found.len()
+                ))
+                .color(Color32::GRAY),
+            );
+        }
+
+        if let Some(k) = chosen {
+            self.selected = Some(k);
+            self.selected_group = None;
+            self.key_picker_open = false;
+            self.key_filter.clear();
+            self.clean_message();
+        }
+    }
+
+    /// index the stored keys, if they changed since the last indexing
+    fn build_key_index(&mut self) {
+        if self.key_index.is_none() {
+            let index = KeyIndex::build(self.db.as_ref()).unwrap_or_else(|e| {
+                error!("fail to index the keys : {}", e);
+                KeyIndex::default()
+            });
+            self.key_index = Some(index);
+        }
+    }
+
+    /// integrity status of a key record, as checked when indexing the keys
+    fn key_integrity(&self, k: &Key) -> IntegrityStatus {
+        self.key_index
+            .as_ref()
+            .map(|index| index.integrity(&k.sha1))
+            .unwrap_or(IntegrityStatus::Unchecked)
+    }
+
+    /// the stored keys changed, the key index is rebuilt on the next search
+    fn keys_changed(&mut self) {
+        self.key_index = None;
+        self.pending_key_changes = None;
+        if let Some(k) = &self.selected {
+            self.selected = self.db.get_by_sha1(&k.sha1).ok();
+        }
+    }
+
+    /// encryption history window, filtered by key or by file
+    fn show_history(&mut self, ctx: &Context) {
+        self.build_key_index();
+        let outdated = !matches!(&self.history_entries, Some(h)
+            if h.key_sha1 == self.history_key && h.path == self.history_path);
+        if outdated {
+            let filter = AuditFilter {
+                key_sha1: self.history_key.clone(),
+                path: Some(self.history_path.clone()).filter(|p| !p.trim().is_empty()),
+                limit: Some(500),
+                ..Default::default()
+            };
+            self.history_entries = Some(HistoryEntries {
+                key_sha1: self.history_key.clone(),
+                path: self.history_path.clone(),
+                entries: self.db.get_audit_log(&filter).map_err(|e| e.to_string()),
+            });
+        }
+
+        egui::Window::new("Historique des chiffrages").show(ctx, |ui| {
+            let keys = self.key_index.as_ref().map(KeyIndex::keys).unwrap_or(&[]);
+            let key_label = |sha1: &str| {
+                keys.iter()
+                    .find(|k| k.sha1 == sha1)
+                    .map(text_representation)
+                    .unwrap_or_else(|| sha1.to_string())
+            };
+
+            ui.horizontal(|ui| {
+                ui.label("Clé :");
+                egui::ComboBox::from_id_source("history_key")
+                    .selected_text(
+                        self.history_key
+                            .as_deref()
+                            .map(key_label)
+                            .unwrap_or_else(|| "Toutes".into()),
+                    )
+                    .width(500.0)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.history_key, None, "Toutes");
+                        for k in keys.iter() {
+                            ui.selectable_value(
+                                &mut self.history_key,
+                                Some(k.sha1.clone()),
+                                text_representation(k),
+                            );
+                        }
+                    });
+            });
+            ui.horizontal(|ui| {
+                ui.label("Fichier :");
+                ui.text_edit_singleline(&mut self.history_path);
+            });
+            ui.separator();
+
+            match self.history_entries.as_ref().map(|h| &h.entries) {
+                Some(Ok(entries)) => {
+                    ui.label(format!("{} chiffrages", entries.len()));
+                    egui::ScrollArea::both().max_height(400.0).show(ui, |ui| {
+                        egui::Grid::new("history_grid")
+                            .striped(true)
+                            .show(ui, |ui| {
+                                ui.strong("Date");
+                                ui.strong("Fichier");
+                                ui.strong("SHA-256");
+                                ui.strong("Fichier chiffré");
+                                ui.strong("Taille");
+                                ui.strong("Clé");
+                                ui.end_row();
+                                for e in entries.iter() {
+                                    ui.label(format_timestamp(e.timestamp));
+                                    ui.label(&e.source_path);
+                                    ui.label(&e.source_sha256[..e.source_sha256.len().min(12)])
+                                        .on_hover_text(&e.source_sha256);
+                                    ui.label(&e.output_path);
+                                    ui.label(format_size(e.output_size));
+                                    ui.label(key_label(&e.key_sha1));
+                                    ui.end_row();
+                                }
+                            });
+                    });
+                }
+                Some(Err(e)) => {
+                    ui.label(RichText::new(e).color(Color32::RED));
+                }
+                None => {}
+            }
+
+            if ui.button("Fermer").clicked() {
+                self.is_history_opened = false;
             }
         });
     }
 
+    /// synchronization of the opened folder with the output folders of the encryption
+    /// keys, the actions are listed before being run
+    fn show_folder_mirror(&mut self, ctx: &Context) {
+        egui::Window::new("Synchronisation du répertoire").show(ctx, |ui| {
+            ui.label(format!(
+                "Répertoire source : {}",
+                self.files_folder.path.display()
+            ));
+            match self.encryption_keys() {
+                Ok(keys) if keys.is_empty() => {
+                    ui.label(
+                        RichText::new("Sélectionnez une clé ou un groupe").color(Color32::RED),
+                    );
+                }
+                Ok(keys) => {
+                    let names: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+                    ui.label(format!("Clés : {}", names.join(", ")));
+                }
+                Err(e) => {
+                    ui.label(RichText::new(format!("{}", e)).color(Color32::RED));
+                }
+            }
+            ui.checkbox(
+                &mut self.mirror_remove_orphans,
+                "Supprimer les fichiers chiffrés dont la source a été supprimée",
+            );
+            ui.horizontal(|ui| {
+                if ui.button("Analyser (sans rien modifier)").clicked() {
+                    self.plan_mirror();
+                }
+                let ready = self.mirror_plans.iter().any(|(_, p)| !p.is_empty());
+                if ui.add_enabled(ready, Button::new("Synchroniser")).clicked() {
+                    self.apply_mirror();
+                }
+            });
+            ui.separator();
+
+            egui::ScrollArea::vertical()
+                .max_height(400.0)
+                .show(ui, |ui| {
+                    for (k, plan) in self.mirror_plans.iter() {
+                        ui.strong(format!(
+                            "{} : {} à chiffrer, {} à supprimer, {} à jour",
+                            k,
+                            plan.to_encrypt(),
+                            plan.to_remove(),
+                            plan.up_to_date
+                        ));
+                        for action in plan.actions.iter() {
+                            let relative = |p: &Path| {
+                                p.strip_prefix(&plan.root)
+                                    .unwrap_or(p)
+                                    .display()
+                                    .to_string()
+                            };
+                            ui.label(match action {
+                                MirrorAction::Encrypt {
+                                    source,
+                                    status: EncryptionStatus::Stale,
+                                    ..
+                                } => format!("modifié : {}", relative(source)),
+                                MirrorAction::Encrypt { source, .. } => {
+                                    format!("nouveau : {}", relative(source))
+                                }
+                                MirrorAction::Remove { output, .. } => {
+                                    format!("supprimé : {}", output.display())
+                                }
+                            });
+                        }
+                        for e in plan.errors.iter() {
+                            ui.label(RichText::new(e.to_string()).color(Color32::RED));
+                        }
+                    }
+                });
+
+            if !self.mirror_message.is_empty() {
+                let mut rt = RichText::new(&self.mirror_message);
+                if self.mirror_is_error {
+                    rt = rt.color(Color32::RED);
+                }
+                ui.label(rt);
+            }
+            if ui.button("Fermer").clicked() {
+                self.is_mirror_opened = false;
+            }
+        });
+    }
+
+    /// dry run of the synchronization of the opened folder, for each encryption key
+    fn plan_mirror(&mut self) {
+        let layout = match self.output_layout() {
+            Ok(layout) => layout,
+            Err(e) => {
+                self.mirror_plans = vec![];
+                self.mirror_message = format!("{}", e);
+                self.mirror_is_error = true;
+                return;
+            }
+        };
+        let options = MirrorOptions {
+            walk: EncrypterApp::tree_options(self.music_only, &layout),
+            remove_orphans: self.mirror_remove_orphans,
+            layout,
+        };
+        let root = self.files_folder.path.clone();
+        let plans = self
+            .encryption_keys()
+            .map_err(|e| e.to_string())
+            .and_then(|keys| {
+                mirror::check_keys(&root, &keys, &options).map_err(|e| e.to_string())?;
+                keys.into_iter()
+                    .map(|k| {
+                        mirror::plan(self.db.as_ref(), &root, &k.name, &k.sha1, &options)
+                            .map(|plan| (k, plan))
+                    })
+                    .collect::<std::result::Result<Vec<_>, _>>()
+                    .map_err(|e| e.to_string())
+            });
+        match plans {
+            Ok(plans) => {
+                self.mirror_message = match plans.iter().all(|(_, p)| p.is_empty()) {
+                    true => "Le répertoire est déjà synchronisé".into(),
+                    false => "".into(),
+                };
+                self.mirror_is_error = false;
+                self.mirror_plans = plans;
+            }
+            Err(e) => {
+                self.mirror_plans = vec![];
+                self.mirror_message = e;
+                self.mirror_is_error = true;
+            }
+        }
+    }
+
+    /// run the listed actions, the keys must be usable
+    fn apply_mirror(&mut self) {
+        let now = unix_time();
+        let refused = self.mirror_plans.iter().find_map(|(k, _)| {
+            if k.public_key.is_none() {
+                Some(format!("no public key for {}", k))
+            } else if self.db.verify_integrity(k).is_altered() {
+                Some(format!("{}", KeyManagementError::Tampered(k.to_string())))
+            } else {
+                k.validity_message(now)
+                    .map(|m| format!("la clé {} ne peut pas être utilisée, {}", k, m))
+            }
+        });
+        if let Some(msg) = refused {
+            self.mirror_message = msg;
+            self.mirror_is_error = true;
+            return;
+        }
+
+        let mut lines = vec![];
+        let mut failed = false;
+        for (k, plan) in std::mem::take(&mut self.mirror_plans) {
+            let kvalue = k.public_key.as_deref().unwrap_or_default();
+            let report = mirror::apply(self.db.as_ref(), &plan, kvalue, |_, _| {});
+            if let Err(e) = self.db.mark_used(&k.sha1) {
+                error!("fail to record the key usage : {}", e);
+            }
+            lines.push(format!(
+                "{} : {} fichiers chiffrés, {} supprimés",
+                k, report.encrypted, report.removed
+            ));
+            for (path, e) in report.failed.iter() {
+                lines.push(format!("Erreur pour {} : {}", path.display(), e));
+            }
+            failed |= !report.failed.is_empty();
+        }
+        self.mirror_message = lines.join("\n");
+        self.mirror_is_error = failed;
+
+        // show the encrypted folders, and their new status
+        self.history_entries = None;
+        self.tree_status.invalidate();
+        self.refresh_tree();
+    }
+
+    /// record the changes made in the key management window
+    fn save_key_edit(&mut self, edit: &KeyEdit) -> std::result::Result<(), KeyManagementError> {
+        let expires_at = match edit.expires_at.trim() {
+            "" => None,
+            date => Some(
+                parse_date(date)
+                    .ok_or_else(|| KeyManagementError::InvalidDate(date.to_string()))?,
+            ),
+        };
+        let db = self.db.as_ref();
+        db.atomically(&mut || {
+            if edit.name != edit.original_name {
+                db.rename(&edit.sha1, &edit.name)?;
+            }
+            if edit.public_key != edit.original_public_key {
+                db.update_public_key(&edit.sha1, edit.public_key.as_bytes())?;
+            }
+            if edit.expires_at != edit.original_expires_at {
+                db.set_expiry(&edit.sha1, expires_at)?;
+            }
+            db.update_metadata(&Key {
+                sha1: edit.sha1.clone(),
+                owner: edit.owner.clone(),
+                instrument_model: edit.instrument_model.clone(),
+                instrument_serial: edit.instrument_serial.clone(),
+                notes: edit.notes.clone(),
+                ..Default::default()
+            })?;
+            for (group, member) in edit.groups.iter() {
+                if *member {
+                    db.add_to_group(group, &edit.sha1)?;
+                } else {
+                    db.remove_from_group(group, &edit.sha1)?;
+                }
+            }
+            Ok(())
+        })?;
+
+        self.keys_changed();
+        Ok(())
+    }
+
+    /// accept (or roll back to) a public key of the history, or refuse a pending change
+    fn decide_key_change(&mut self, entry: &KeyHistoryEntry, accept: bool) {
+        let result = if accept {
+            self.db.accept_public_key(&entry.sha1, entry.id)
+        } else {
+            self.db.reject_public_key(&entry.sha1, entry.id)
+        };
+        match result {
+            Ok(_) => {
+                self.manage_message = format!(
+                    "clé publique {} {} pour {}",
+                    fingerprint(&entry.public_key),
+                    if accept { "acceptée" } else { "refusée" },
+                    entry.sha1
+                );
+                self.manage_is_error = false;
+                self.keys_changed();
+            }
+            Err(e) => {
+                self.manage_message = format!("{}", e);
+                self.manage_is_error = true;
+            }
+        }
+    }
+
     fn clean_message(&mut self) {
         self.last_message = "".into();
         self.is_error = false;
+        self.capacity_confirm_pending = false;
+        self.validity_confirm_pending = false;
+        self.validity_override = false;
+    }
+
+    /// keys the selected files are encrypted for, the members of the
+    /// selected group, read from the key index, or the selected key
+    fn encryption_keys(&mut self) -> std::result::Result<Vec<Key>, KeyManagementError> {
+        if self.selected_group.is_some() {
+            self.build_key_index();
+        }
+        if let (Some(group), Some(index)) = (&self.selected_group, &self.key_index) {
+            if !index.groups().contains(&group.as_str()) {
+                return Err(KeyManagementError::GroupNotFound(group.clone()));
+            }
+            return Ok(index.group_members(group).into_iter().cloned().collect());
+        }
+        Ok(self.selected.iter().cloned().collect())
+    }
+
+    /// encrypt the selected files for each of the given keys, and refresh the tree
+    fn encrypt_selection(&mut self, keys: &[Key]) {
+        // the override of the revoked or expired keys only applies to this encryption
+        let validity_override = self.validity_override;
+        self.clean_message();
+
+        info!("Chiffrage des fichiers");
+        if let Some(k) = keys.iter().find(|k| k.public_key.is_none()) {
+            self.last_message = format!("no public key for {}", k);
+            self.is_error = true;
+            return;
+        }
+        if let Some(k) = keys
+            .iter()
+            .find(|k| self.db.verify_integrity(k).is_altered())
+        {
+            self.last_message = format!("{}", KeyManagementError::Tampered(k.to_string()));
+            self.is_error = true;
+            return;
+        }
+        let now = unix_time();
+        let refused: Vec<String> = keys
+            .iter()
+            .filter_map(|k| k.validity_message(now).map(|m| format!("{} ({})", k, m)))
+            .collect();
+        if !refused.is_empty() && !validity_override {
+            self.last_message = format!(
+                "Clés révoquées ou expirées, chiffrement refusé : {}",
+                refused.join(", ")
+            );
+            self.is_error = true;
+            self.validity_confirm_pending = true;
+            return;
+        }
+
+        let layout = match self.output_layout() {
+            Ok(layout) => layout,
+            Err(e) => {
+                self.last_message = format!("{}", e);
+                self.is_error = true;
+                return;
+            }
+        };
+
+        // the keys of a group can't write the same file
+        let relative: Vec<PathBuf> = folder::selected_paths(&self.files_folder)
+            .iter()
+            .map(|p| relative_to(p, &self.files_folder.path))
+            .collect();
+        if let Err(e) = layout.check_keys(&relative, keys) {
+            self.last_message = format!("{}", e);
+            self.is_error = true;
+            return;
+        }
+
+        let mut result: crate::Result<()> = Ok(());
+        for k in keys {
+            if let Some(kvalue) = &k.public_key {
+                result = EncrypterApp::crypt_selected(
+                    &self.files_folder,
+                    &layout,
+                    &k.name,
+                    &k.sha1,
+                    kvalue,
+                    self.db.as_ref(),
+                    validity_override,
+                );
+                if result.is_err() {
+                    break;
+                }
+                if let Err(e) = self.db.mark_used(&k.sha1) {
+                    error!("fail to record the key usage : {}", e);
+                }
+            }
+        }
+
+        match result {
+            Ok(_) => {
+                println!("Fin du chiffrage des fichiers");
+                self.last_message = if keys.len() > 1 {
+                    format!("Fichiers chiffrés avec succès pour {} clés", keys.len())
+                } else {
+                    "Fichiers chiffrés avec succès".into()
+                };
+                self.is_error = false;
+            }
+            Err(e) => {
+                self.last_message = format!("Erreur dans le chiffrage : {:?}", &e);
+                self.is_error = true;
+                error!("Error in crypt : {:?}", e);
+            }
+        };
+
+        // show the encrypted folders, keeping the expansion and the selection
+        self.history_entries = None;
+        self.tree_status.invalidate();
+        self.refresh_tree();
     }
 }
 
@@ -396,12 +2570,49 @@ impl eframe::App for EncrypterApp {
     }
 
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        // files added or removed by other programs
+        self.watch_tree();
+        self.update_tree_status();
+        self.tree_status.poll();
+        // inotify wakes the interface itself, the other jobs are checked at an interval
+        let watcher_ctx = ctx.clone();
+        self.watcher
+            .wake_on_change(move || watcher_ctx.request_repaint());
+        if self.tree_status.is_busy() || self.flower.is_active() {
+            ctx.request_repaint_after(STATUS_POLL_INTERVAL);
+        } else if self.watcher.needs_polling() && !self.watcher.watched().is_empty() {
+            ctx.request_repaint_after(POLL_INTERVAL);
+        }
+        // the encrypted files are left out of the tree
+        let tree_layout = self.output_layout().unwrap_or_default();
+
         let Self {
             label: _,
             value: _,
+            module_capacity_mb: _,
+            capacity_confirm_pending: _,
+            validity_confirm_pending: _,
+            validity_override: _,
             selected: _,
+            key_filter: _,
+            key_index: _,
+            pending_key_changes: _,
+            key_picker_open: _,
+            key_picker_cursor: _,
+            selected_group: _,
             files_folder: _,
+            music_only: _,
+            output_root: _,
+            output_template: _,
+            watcher: _,
+            tree_status: _,
+            size_estimate: _,
             db: _,
+            database_path: _,
+            database_input: _,
+            database_error: _,
+            key_folder: _,
+            key_lookup: _,
             last_message: _,
             is_error: _,
             is_add_opened: _,
@@ -410,7 +2621,41 @@ impl eframe::App for EncrypterApp {
             key_public_key: _,
             key_error_message: _,
             key_search_key_internet: _,
+            key_server: _,
             key_is_error: _,
+            is_manage_opened: _,
+            manage_filter: _,
+            manage_edit: _,
+            manage_delete_pending: _,
+            manage_new_group: _,
+            manage_message: _,
+            manage_is_error: _,
+            is_book_opened: _,
+            book_path: _,
+            book_format: _,
+            book_strategy: _,
+            book_records: _,
+            book_preview: _,
+            book_message: _,
+            book_is_error: _,
+            revocation_path: _,
+            is_history_opened: _,
+            history_key: _,
+            history_path: _,
+            history_entries: _,
+            is_mirror_opened: _,
+            mirror_remove_orphans: _,
+            mirror_plans: _,
+            mirror_message: _,
+            mirror_is_error: _,
+            is_sync_opened: _,
+            sync_text: _,
+            sync_path: _,
+            sync_progress: _,
+            sync_refresh: _,
+            sync_message: _,
+            sync_is_error: _,
+            sync_flower: _,
             flower: _,
             file_path_dialog: _,
             file_path: _,
@@ -422,20 +2667,9 @@ impl eframe::App for EncrypterApp {
                 Ok(Some(path)) => {
                     ctx.request_repaint();
                     self.file_path = path;
-                    println!(
-                        "selected folder : {}",
-                        self.file_path.as_path().to_str().unwrap()
-                    );
-                    let mut new_folder = FolderNode {
-                        expanded: false,
-                        is_folder: true,
-                        path: self.file_path.as_path().to_str().unwrap().into(),
-                        subfolders: vec![],
-                        selected: false,
-                    };
-                    folder::expand(&mut new_folder).expect("hello");
-                    // self.files_folder.expand();
-                    self.files_folder = new_folder;
+                    debug!("selected folder : {}", self.file_path.display());
+                    self.files_folder =
+                        EncrypterApp::open_tree(&self.file_path, self.music_only, &tree_layout);
                 }
                 Ok(None) => {}
                 Err(error) => {
@@ -478,6 +2712,47 @@ impl eframe::App for EncrypterApp {
                         self.key_search_key_internet = true;
                         self.is_add_opened = true;
                     }
+                    if ui.button("Gérer les clés ..").clicked() {
+                        self.manage_filter = "".into();
+                        self.manage_edit = None;
+                        self.manage_delete_pending = None;
+                        self.manage_message = "".into();
+                        self.manage_is_error = false;
+                        self.is_manage_opened = true;
+                        ui.close_menu();
+                    }
+                    if ui.button("Historique des chiffrages ..").clicked() {
+                        self.history_key = self.selected.as_ref().map(|k| k.sha1.clone());
+                        self.history_path = "".into();
+                        self.history_entries = None;
+                        self.is_history_opened = true;
+                        ui.close_menu();
+                    }
+                    if ui.button("Synchroniser le répertoire ..").clicked() {
+                        self.mirror_plans = vec![];
+                        self.mirror_message = "".into();
+                        self.mirror_is_error = false;
+                        self.is_mirror_opened = true;
+                        ui.close_menu();
+                    }
+                    if ui
+                        .button("Synchroniser avec le serveur de clés ..")
+                        .clicked()
+                    {
+                        self.sync_refresh = vec![];
+                        self.sync_message = "".into();
+                        self.sync_is_error = false;
+                        self.is_sync_opened = true;
+                        ui.close_menu();
+                    }
+                    if ui.button("Importer / exporter les clés ..").clicked() {
+                        self.book_records = vec![];
+                        self.book_preview = None;
+                        self.book_message = "".into();
+                        self.book_is_error = false;
+                        self.is_book_opened = true;
+                        ui.close_menu();
+                    }
                 });
             });
         });
@@ -488,6 +2763,40 @@ impl eframe::App for EncrypterApp {
             .show(ctx, |ui| {
                 ui.vertical(|ui| {
                     ui.label("1 - Selectionnez les fichiers à chiffrer");
+                    if ui
+                        .checkbox(
+                            &mut self.music_only,
+                            "Fichiers musicaux seulement (.mid, .midi)",
+                        )
+                        .changed()
+                    {
+                        let root = self.files_folder.path.clone();
+                        self.files_folder =
+                            EncrypterApp::open_tree(&root, self.music_only, &tree_layout);
+                    }
+                    if self.tree_status.checker.is_some()
+                        && ui
+                            .button("Sélectionner uniquement les fichiers non chiffrés ou modifiés")
+                            .on_hover_text(
+                                "parmi les fichiers des répertoires ouverts, pour la clé sélectionnée",
+                            )
+                            .clicked()
+                    {
+                        let status = &mut self.tree_status;
+                        select_files(&mut self.files_folder, &mut |path| {
+                            status.get_now(path).map_or(false, |s| s.is_outdated())
+                        });
+                    }
+                    if let Some(error) = &self.files_folder.error {
+                        ui.label(
+                            RichText::new(format!(
+                                "Le répertoire {} ne peut pas être lu : {}",
+                                self.files_folder.path.display(),
+                                error
+                            ))
+                            .color(Color32::RED),
+                        );
+                    }
                     ui.separator();
 
                     egui::ScrollArea::both().show(ui, |ui| {
@@ -495,11 +2804,16 @@ impl eframe::App for EncrypterApp {
                             .size(Size::remainder())
                             .horizontal(|mut strip| {
                                 strip.cell(|ui| {
-                                    if let Err(e) =
-                                        EncrypterApp::display_tree(&mut self.files_folder, ui)
-                                    {
-                                        error!("error in display tree: {}", e);
-                                    }
+                                    let options = EncrypterApp::tree_options(
+                                        self.music_only,
+                                        &tree_layout,
+                                    );
+                                    EncrypterApp::display_tree(
+                                        &mut self.files_folder,
+                                        &options,
+                                        &mut self.tree_status,
+                                        ui,
+                                    );
                                 });
                             });
                     });
@@ -514,87 +2828,145 @@ impl eframe::App for EncrypterApp {
                             "Si la clef n'existe pas, vous pouvez l'ajouter avec le menu",
                         );
                 });
+                self.show_database(ui);
                 ui.separator();
-                ui.horizontal(|ui| {
-                    // selected text
-                    let mut selectable_text: String = "".into();
-                    if let Some(v) = &self.selected {
-                        selectable_text = text_representation(v);
-                    }
+                self.show_key_picker(ui);
 
+                let groups: Vec<String> = match &self.key_index {
+                    Some(index) => index.groups().into_iter().map(String::from).collect(),
+                    None => vec![],
+                };
+                if !groups.is_empty() {
                     ui.horizontal(|ui| {
-                        ui.label("Clé :");
-
-                        let choice_key = egui::ComboBox::from_label("")
-                            .selected_text(selectable_text.to_string())
-                            .width(800.0)
+                        ui.label("ou Groupe :");
+                        let choice_group = egui::ComboBox::from_id_source("group_choice")
+                            .selected_text(self.selected_group.clone().unwrap_or_default())
+                            .width(400.0)
                             .show_ui(ui, |ui| {
-                                let keys = self.db.get_all().expect("fail to get keys");
-                                for k in keys.iter() {
+                                for g in groups.iter() {
                                     ui.selectable_value(
-                                        &mut self.selected,
-                                        Some(k.clone()),
-                                        text_representation(k),
+                                        &mut self.selected_group,
+                                        Some(g.clone()),
+                                        g.as_str(),
                                     );
                                 }
                             });
-                        if choice_key.response.changed() {
+                        if choice_group.response.changed() {
+                            self.selected = None;
                             self.clean_message();
                         }
+                        if self.selected_group.is_some() && ui.button("Aucun").clicked() {
+                            self.selected_group = None;
+                        }
                     });
-                });
+                }
+
+                let selected_keys = self.encryption_keys().unwrap_or_default();
+                let estimate = self.size_estimate.estimate(
+                    folder::selected_paths(&self.files_folder),
+                    &selected_keys,
+                    self.module_capacity_mb * 1024 * 1024,
+                );
 
                 ui.group(|ui| {
                     ui.label("Liste des fichiers sélectionnés :");
                     ui.separator();
                     EncrypterApp::construct_list(&mut self.files_folder, ui);
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label("Répertoire des fichiers chiffrés :");
+                        ui.text_edit_singleline(&mut self.output_root)
+                            .on_hover_text("vide pour le répertoire courant");
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Nom des fichiers chiffrés :");
+                        ui.text_edit_singleline(&mut self.output_template)
+                            .on_hover_text(
+                            "{out} répertoire des fichiers chiffrés, {key.name} {key.sha1} clé, \
+                             {relpath} sous-répertoires, {name} nom du fichier, \
+                             {stem} nom sans extension, {ext} extension",
+                        );
+                        if ui.button("Par défaut").clicked() {
+                            self.output_template = DEFAULT_TEMPLATE.into();
+                        }
+                    });
+                    if let Err(e) = self.output_layout() {
+                        ui.label(RichText::new(format!("{}", e)).color(Color32::RED));
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Capacité du module :");
+                        ui.add(
+                            egui::DragValue::new(&mut self.module_capacity_mb)
+                                .clamp_range(1..=4096)
+                                .suffix(" Mo"),
+                        );
+                    });
+                    match &estimate {
+                        Ok(e) => {
+                            let mut rt = RichText::new(format!(
+                                "Taille chiffrée estimée : {} / {}",
+                                format_size(e.encrypted_size),
+                                format_size(e.capacity)
+                            ));
+                            if !e.fits() {
+                                rt = rt.color(Color32::RED);
+                            }
+                            ui.label(rt);
+                        }
+                        Err(e) => {
+                            ui.label(
+                                RichText::new(format!("Taille chiffrée inconnue : {}", e))
+                                    .color(Color32::RED),
+                            );
+                        }
+                    }
                 });
 
                 let button_crypt = egui::Button::new(
                     RichText::new("3 - Chiffrer les fichiers sélectionnés").color(Color32::BLUE),
                 );
-                if let Some(selected_key) = &self.selected {
-                    if ui.add(button_crypt).clicked() {
-                        // reset the last_message
-                        self.last_message = "".into();
-                        self.is_error = false;
-
-                        info!("Chiffrage des fichiers");
-                        if let Some(kvalue) = &selected_key.public_key {
-                            match EncrypterApp::crypt_selected(
-                                &self.files_folder,
-                                &selected_key.name,
-                                &selected_key.sha1,
-                                kvalue,
-                            ) {
-                                Ok(_) => {
-                                    println!("Fin du chiffrage des fichiers");
-                                    self.last_message = "Fichiers chiffrés avec succès".into();
-                                    self.is_error = false;
-                                }
-                                Err(e) => {
-                                    self.last_message =
-                                        format!("Erreur dans le chiffrage : {:?}", &e);
-                                    self.is_error = true;
-                                    error!("Error in crypt : {:?}", e);
-                                }
-                            };
-
-                            let mut r = FolderNode {
-                                expanded: false,
-                                is_folder: true,
-                                path: ".".into(),
-                                subfolders: vec![],
-                                selected: false,
-                            };
-
-                            let _ = expand(&mut r);
-
-                            self.files_folder = r;
-                        } else {
-                            self.last_message = "no public key".into();
-                            self.is_error = true;
+                if self.selected.is_some() || self.selected_group.is_some() {
+                    let keys = self.encryption_keys();
+                    match &keys {
+                        Ok(keys) if self.selected_group.is_some() => {
+                            let names: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+                            ui.label(format!("Clés du groupe : {}", names.join(", ")));
+                        }
+                        Err(e) => {
+                            ui.label(RichText::new(format!("{}", e)).color(Color32::RED));
                         }
+                        _ => {}
+                    }
+                    let keys = keys.unwrap_or_default();
+
+                    if ui.add_enabled(!keys.is_empty(), button_crypt).clicked() {
+                        match &estimate {
+                            Ok(e) if !e.fits() => {
+                                self.last_message = format!(
+                                    "La sélection ({}) dépasse la capacité du module ({})",
+                                    format_size(e.encrypted_size),
+                                    format_size(e.capacity)
+                                );
+                                self.is_error = true;
+                                self.capacity_confirm_pending = true;
+                            }
+                            _ => self.encrypt_selection(&keys),
+                        }
+                    }
+                    if self.capacity_confirm_pending && ui.button("Chiffrer quand même").clicked()
+                    {
+                        self.encrypt_selection(&keys);
+                    }
+                    if self.validity_confirm_pending
+                        && ui
+                            .button(
+                                RichText::new("Chiffrer avec les clés révoquées ou expirées")
+                                    .color(Color32::RED),
+                            )
+                            .clicked()
+                    {
+                        self.validity_override = true;
+                        self.encrypt_selection(&keys);
                     }
                 } else {
                     // ui.set_enabled(false);
@@ -615,6 +2987,26 @@ impl eframe::App for EncrypterApp {
             egui::warn_if_debug_build(ui);
         });
 
+        if self.is_manage_opened {
+            self.show_key_management(ctx);
+        }
+
+        if self.is_book_opened {
+            self.show_key_book(ctx);
+        }
+
+        if self.is_history_opened {
+            self.show_history(ctx);
+        }
+
+        if self.is_sync_opened {
+            self.show_key_sync(ctx);
+        }
+
+        if self.is_mirror_opened {
+            self.show_folder_mirror(ctx);
+        }
+
         if self.is_add_opened {
             let f = &self.flower;
             egui::Window::new("Ajouter une carte").show(ctx, |ui| {
@@ -632,10 +3024,16 @@ impl eframe::App for EncrypterApp {
                     ui.horizontal(|ui| {
                         ui.checkbox(
                             &mut self.key_search_key_internet,
-                            "Récupérer la clé sur internet",
+                            "Récupérer la clé (serveur ou dossier de clés)",
                         );
                     });
-                    if !self.key_search_key_internet {
+                    if self.key_search_key_internet {
+                        EncrypterApp::show_key_lookup(
+                            ui,
+                            &mut self.key_lookup,
+                            &mut self.key_folder,
+                        );
+                    } else {
                         ui.horizontal(|ui| {
                             ui.label("Clé Publique :");
                             ui.text_edit_multiline(&mut self.key_public_key);
@@ -656,8 +3054,10 @@ impl eframe::App for EncrypterApp {
                 let keysrc: String = self.key_sha1_input.trim().into();
 
                 ui.horizontal(|ui| {
+                    let ready =
+                        !self.key_search_key_internet || self.key_lookup.is_ready(&self.key_folder);
                     if ui
-                        .add_enabled(!f.is_active(), Button::new("Ajouter"))
+                        .add_enabled(!f.is_active() && ready, Button::new("Ajouter"))
                         .clicked()
                     {
                         println!("Ajout de la carte dans la liste");
@@ -665,7 +3065,7 @@ impl eframe::App for EncrypterApp {
                         self.key_error_message = "".into();
                         self.key_is_error = false;
 
-                        if keysrc.trim().len() != 40 {
+                        if keysrc.len() != 40 {
                             self.key_error_message =
                                 "le sha1 de la clé doit avoir 40 characteres".into();
                             self.key_is_error = true;
@@ -673,7 +3073,19 @@ impl eframe::App for EncrypterApp {
                         }
 
                         if self.key_search_key_internet {
-                            EncrypterApp::download_key(f, self.key_sha1_input.clone());
+                            match EncrypterApp::key_provider(
+                                self.key_lookup,
+                                &self.key_folder,
+                                &self.key_server,
+                            ) {
+                                Ok(provider) => {
+                                    EncrypterApp::download_key(f, provider, keysrc.clone())
+                                }
+                                Err(e) => {
+                                    self.key_error_message = e;
+                                    self.key_is_error = true;
+                                }
+                            }
                         } else {
                             // check the public key,
                             if self.key_public_key.trim().is_empty() {
@@ -686,24 +3098,17 @@ impl eframe::App for EncrypterApp {
                                     "la clé publique est invalide, vérifiez la".into();
                                 self.key_is_error = true;
                             } else {
-                                // ok, record the key into db
-                                let new_key = Key {
-                                    rowid: 0,
-                                    name: self.key_name.clone(),
-                                    sha1: keysrc.clone(),
-                                    public_key: Some(self.key_public_key.as_bytes().to_vec()),
-                                };
-
-                                if let Ok(_r) = self.db.insert(&new_key) {
-                                    self.key_error_message =
-                                        "clé ".to_string() + &keysrc + " récupérée, et enregistrée";
-                                    self.key_is_error = false;
-                                } else {
-                                    self.key_error_message = "clé ".to_string()
-                                        + &keysrc
-                                        + " non sauvegardée, erreur dans l'écriture";
-                                    self.key_is_error = true;
-                                }
+                                // ok, record the key into db, keeping the metadata of a known key
+                                let mut new_key = self.db.get_by_sha1(&keysrc).unwrap_or_default();
+                                new_key.name = self.key_name.clone();
+                                new_key.sha1 = keysrc.clone();
+                                new_key.public_key = Some(self.key_public_key.as_bytes().to_vec());
+                                new_key.source = KeySource::Manual;
+
+                                (self.key_error_message, self.key_is_error) =
+                                    insertion_message(&keysrc, self.db.insert(&new_key));
+                                // the add window borrows the flower, only the index is reset
+                                self.key_index = None;
                             }
                         }
                     };
@@ -717,29 +3122,24 @@ impl eframe::App for EncrypterApp {
                         f.extract(|r| println!("{}", r)).finalize(|result| {
                             match result {
                                 Ok(value) => {
-                                    println!("success dans la récupération des clés : {:?}", value);
+                                    println!(
+                                        "success dans la récupération des clés : {:?}",
+                                        value.origin
+                                    );
                                     self.key_error_message =
                                         "clé ".to_string() + &keysrc + " récupérée";
                                     self.key_is_error = false;
 
-                                    let new_key = Key {
-                                        rowid: 0,
-                                        name: self.key_name.clone(),
-                                        sha1: self.key_sha1_input.clone(),
-                                        public_key: Some(value.as_bytes().to_vec()),
-                                    };
+                                    let mut new_key =
+                                        self.db.get_by_sha1(&keysrc).unwrap_or_default();
+                                    new_key.name = self.key_name.clone();
+                                    new_key.sha1 = keysrc.clone();
+                                    new_key.public_key = Some(value.public_key);
+                                    new_key.source = value.origin;
 
-                                    if let Ok(_r) = self.db.insert(&new_key) {
-                                        self.key_error_message = "clé ".to_string()
-                                            + &self.key_sha1_input
-                                            + " récupérée, et sauvegardées";
-                                        self.key_is_error = false;
-                                    } else {
-                                        self.key_error_message = "clé ".to_string()
-                                            + &self.key_sha1_input
-                                            + " non sauvegardée, erreur dans l'écriture";
-                                        self.key_is_error = true;
-                                    }
+                                    (self.key_error_message, self.key_is_error) =
+                                        insertion_message(&keysrc, self.db.insert(&new_key));
+                                    self.key_index = None;
                                 }
                                 Err(Cause::Suppose(msg)) => {
                                     println!("{}", msg);
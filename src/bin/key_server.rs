@@ -0,0 +1,87 @@
+#![warn(clippy::all, rust_2018_idioms)]
+
+//! key server of the intranet, serving the keys of a key folder or of a key database
+//!
+//! key_server (--dir <folder> | --db <keys.db>) [--listen <address>]
+//!            [--publisher <publisher.pub.pem>].. [--signing-key <publisher.key.pem>]
+//!
+//! the api tokens accepted for the uploads are given by `ENCRYPTER_SERVER_TOKENS`,
+//! they need `--signing-key`
+
+use std::fs;
+use std::process::exit;
+
+use log::info;
+use openssl::pkey::PKey;
+
+use encrypter::key_signature::PublisherKey;
+use encrypter::reference_server::*;
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+const USAGE: &str = "usage : key_server (--dir <dossier> | --db <keys.db>) [--listen <adresse>] \
+[--publisher <editeur.pub.pem>].. [--signing-key <editeur.key.pem>]";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("{}", USAGE);
+    exit(2)
+}
+
+fn read(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| fail(&format!("lecture de {} impossible : {}", path, e)))
+}
+
+fn main() {
+    env_logger::init();
+
+    let mut dir = None;
+    let mut db = None;
+    let mut listen = DEFAULT_LISTEN.to_string();
+    let mut config = ServerConfig {
+        tokens: ServerConfig::tokens_from_env(),
+        ..Default::default()
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| fail(&format!("valeur manquante pour {}", arg)))
+        };
+        match arg.as_str() {
+            "--dir" => dir = Some(value()),
+            "--db" => db = Some(value()),
+            "--listen" => listen = value(),
+            "--publisher" => {
+                let publisher = PublisherKey::from_pem(&read(&value()))
+                    .unwrap_or_else(|e| fail(&format!("clé d'éditeur invalide : {}", e)));
+                config.publishers.push(publisher);
+            }
+            "--signing-key" => {
+                let key = PKey::private_key_from_pem(&read(&value()))
+                    .unwrap_or_else(|e| fail(&format!("clé de signature invalide : {}", e)));
+                config.signing_key = Some(key);
+            }
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => fail(&format!("argument inconnu : {}", arg)),
+        }
+    }
+
+    let listener = std::net::TcpListener::bind(&listen)
+        .unwrap_or_else(|e| fail(&format!("écoute sur {} impossible : {}", listen, e)));
+    info!("key server listening on http://{}{}", listen, KEY_PREFIX);
+
+    match (dir, db) {
+        (Some(dir), None) => KeyServer::new(DirectoryRepository::new(dir), config)
+            .unwrap_or_else(|e| fail(&e.to_string()))
+            .serve(listener),
+        (None, Some(db)) => KeyServer::new(StoreRepository::new(db), config)
+            .unwrap_or_else(|e| fail(&e.to_string()))
+            .serve(listener),
+        _ => fail("un dossier (--dir) ou une base de clés (--db) est nécessaire"),
+    }
+}
@@ -1,16 +1,21 @@
 use log::{debug, info};
 
 use openssl::rsa::{Padding, Rsa};
+use openssl::sha::sha256;
 
 use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 
+use crate::output::{relative_to, OutputLayout};
 use crate::Result;
 
-// encoding blocks
-const KEY_SIZE: usize = 1024;
-const BLOCK_SIZE: usize = KEY_SIZE / 16;
+// plain bytes of an encoding block, fits the oaep padding of keys of 1024 bits and more
+const BLOCK_SIZE: usize = 64;
+
+/// size of an rsa encrypted block for a key of 1024 bits
+pub const DEFAULT_ENCRYPTED_BLOCK_SIZE: u64 = 128;
 
 pub fn get_file_as_byte_vec(filename: &String) -> Result<Vec<u8>> {
     let mut f = File::open(filename)?;
@@ -22,15 +27,96 @@ pub fn get_file_as_byte_vec(filename: &String) -> Result<Vec<u8>> {
     Ok(buffer)
 }
 
-/// encrypt file using a public key path
+/// sha256 of a content, in hexadecimal
+pub fn sha256_hex(content: &[u8]) -> String {
+    sha256(content)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// size of the rsa encrypted blocks of a public key, the size of its modulus
+pub fn encrypted_block_size(public_key_content: &[u8]) -> Result<u64> {
+    Ok(Rsa::public_key_from_pem_pkcs1(public_key_content)?.size() as u64)
+}
+
+/// size of the encrypted file for a plain content of `plain_len` bytes,
+/// the block count header plus, for each block, its size and the rsa block
+pub fn encrypted_size(plain_len: u64, encrypted_block_size: u64) -> u64 {
+    let block_size = BLOCK_SIZE as u64;
+    let nbblock = (plain_len + block_size - 1) / block_size;
+    4 + nbblock * (4 + encrypted_block_size)
+}
+
+/// estimate the encrypted size of a set of files, without encrypting them
+pub fn estimate_encrypted_size<P: AsRef<Path>>(
+    files: &[P],
+    encrypted_block_size: u64,
+) -> Result<u64> {
+    let mut total = 0;
+    for f in files {
+        let metadata = fs::metadata(f)?;
+        total += encrypted_size(metadata.len(), encrypted_block_size);
+    }
+    Ok(total)
+}
+
+/// projected encrypted size of a selection, against the module capacity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapacityEstimate {
+    pub encrypted_size: u64,
+    pub capacity: u64,
+}
+
+impl CapacityEstimate {
+    pub fn estimate<P: AsRef<Path>>(
+        files: &[P],
+        capacity: u64,
+        encrypted_block_size: u64,
+    ) -> Result<CapacityEstimate> {
+        Ok(CapacityEstimate {
+            encrypted_size: estimate_encrypted_size(files, encrypted_block_size)?,
+            capacity,
+        })
+    }
+
+    pub fn fits(&self) -> bool {
+        self.encrypted_size <= self.capacity
+    }
+
+    /// bytes left on the module, negative when the selection does not fit
+    pub fn remaining(&self) -> i64 {
+        self.capacity as i64 - self.encrypted_size as i64
+    }
+}
+
+/// encrypt file using a public key path, the output is written next to the file
 pub fn encrypt_file(filepath: &String, public_key_path: &String) -> Result<()> {
+    let folder = Path::new(filepath).parent().unwrap_or(Path::new(""));
+    let layout = OutputLayout::new(folder, "{out}/{name}x")?;
+    encrypt_file_as(Path::new(filepath), public_key_path, &layout, "", "")?;
+    Ok(())
+}
+
+/// encrypt file using a public key path, the output is named by the layout for the key,
+/// without sub folders. Returns the output
+pub fn encrypt_file_as(
+    filepath: &Path,
+    public_key_path: &String,
+    layout: &OutputLayout,
+    key_name: &str,
+    sha1: &str,
+) -> Result<PathBuf> {
     info!("reading public key {}", public_key_path);
     let public_file_content = get_file_as_byte_vec(public_key_path)?;
     info!("public file content : {:?}", &public_file_content);
 
-    let o = filepath.clone() + "x";
+    let o = layout.path(&relative_to(filepath, Path::new("")), key_name, sha1);
+    if let Some(folder) = o.parent() {
+        fs::create_dir_all(folder)?;
+    }
     encrypt_file_with_inmemory_key(filepath, &o, &public_file_content)?;
-    Ok(())
+    Ok(o)
 }
 
 pub fn check_public_key(public_key_content: &[u8]) -> Result<()> {
@@ -40,9 +126,19 @@ pub fn check_public_key(public_key_content: &[u8]) -> Result<()> {
 }
 
 /// encrypt file using an inmemory public key
-pub fn encrypt_file_with_inmemory_key(
-    filepath: &String,
-    output_file: &String,
+pub fn encrypt_file_with_inmemory_key<P: AsRef<Path>, Q: AsRef<Path>>(
+    filepath: P,
+    output_file: Q,
+    public_key_content: &[u8],
+) -> Result<()> {
+    let filecontent = fs::read(filepath)?;
+    encrypt_content_with_inmemory_key(&filecontent, output_file, public_key_content)
+}
+
+/// encrypt a content read beforehand using an inmemory public key
+pub fn encrypt_content_with_inmemory_key<Q: AsRef<Path>>(
+    filecontent: &[u8],
+    output_file: Q,
     public_key_content: &[u8],
 ) -> Result<()> {
     // read pem public file
@@ -51,8 +147,6 @@ pub fn encrypt_file_with_inmemory_key(
 
     debug!("{:?}", rsa_key);
 
-    let filecontent = get_file_as_byte_vec(filepath)?;
-
     // to encrypt,
     let mut nbblock = filecontent.len() / BLOCK_SIZE;
     if nbblock * BLOCK_SIZE < filecontent.len() {
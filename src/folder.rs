@@ -1,57 +1,440 @@
-use std::fs::read_dir;
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{self, read_dir};
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use log::{debug, error};
+use log::{debug, warn};
 
-#[derive(thiserror::Error, Debug)]
-pub enum FolderError {
-    #[error("data store disconnected")]
-    OsError(#[from] io::Error),
-    #[error("`{0}`")]
-    Redaction(String),
-    #[error("unknown data store error")]
-    Unknown,
+use crate::output::OutputLayout;
+
+/// entry of a folder which can't be read, the other entries are still read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryError {
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl EntryError {
+    fn new(path: &Path, message: impl fmt::Display) -> EntryError {
+        EntryError {
+            path: path.to_path_buf(),
+            message: message.to_string(),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} : {}", self.path.display(), self.message)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct FolderNode {
-    pub path: String,
+    pub path: PathBuf,
     pub expanded: bool,
     pub is_folder: bool,
     pub subfolders: Vec<Box<FolderNode>>,
     pub selected: bool,
+    /// the entry, or the content of the folder, can't be read
+    pub error: Option<String>,
 }
 
 impl FolderNode {
-    pub fn name(&self) -> &str {
-        let ancestors = Path::new(&self.path).file_name().unwrap().to_str().unwrap();
-        ancestors
-    }
-}
-
-pub fn expand(folder: &mut FolderNode) -> Result<(), FolderError> {
-    debug!("expanding {:?}", &folder);
-    let p: &Path = Path::new(&folder.path);
-    let mut entries: Vec<Box<FolderNode>> = read_dir(p)
-        .map(|res| {
-            res.map(|e| {
-                let de = e.unwrap();
-                let file_type = de.file_type().unwrap();
-                Box::new(FolderNode {
-                    path: String::from(de.path().to_string_lossy()),
-                    expanded: false,
-                    is_folder: file_type.is_dir(),
-                    subfolders: vec![],
-                    selected: false,
-                })
+    /// folder not yet expanded
+    pub fn new_root<P: AsRef<Path>>(path: P) -> FolderNode {
+        FolderNode {
+            path: path.as_ref().to_path_buf(),
+            is_folder: true,
+            ..Default::default()
+        }
+    }
+
+    /// name of the entry, the names which are not utf-8 are displayed with replacement characters
+    pub fn name(&self) -> Cow<'_, str> {
+        match self.path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => self.path.to_string_lossy(),
+        }
+    }
+}
+
+/// music files, for [`WalkOptions::include`]
+pub const MUSIC_FILES: [&str; 2] = ["*.mid", "*.midi"];
+
+/// how the symbolic links are handled by [`walk`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// links are ignored
+    Skip,
+    /// links to files are listed, links to folders are ignored
+    #[default]
+    Files,
+    /// links are followed, a folder already visited is not entered again
+    Follow,
+}
+
+/// filters of a folder walk, the globs are matched on the entry name, or on the path
+/// relative to the root when they contain a `/`. `*` and `?` do not match a `/`,
+/// `**` matches any path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkOptions {
+    /// levels of folders read, `Some(1)` only reads the root, `None` has no limit
+    pub max_depth: Option<usize>,
+    /// globs of the files listed, every file when empty, folders are always listed
+    pub include: Vec<String>,
+    /// globs of the files and folders left out
+    pub exclude: Vec<String>,
+    /// list the entries starting with a dot
+    pub hidden: bool,
+    pub symlinks: SymlinkPolicy,
+}
+
+impl Default for WalkOptions {
+    /// every file, without the hidden entries, the key files and the encrypted folders
+    /// of the default layout
+    fn default() -> Self {
+        let mut exclude = vec!["keys.db".into(), "*.pem".into()];
+        exclude.extend(OutputLayout::default().exclude_globs());
+        WalkOptions {
+            max_depth: None,
+            include: vec![],
+            exclude,
+            hidden: false,
+            symlinks: SymlinkPolicy::default(),
+        }
+    }
+}
+
+impl WalkOptions {
+    /// only the music files
+    pub fn music() -> WalkOptions {
+        WalkOptions {
+            include: MUSIC_FILES.iter().map(|g| g.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_max_depth(mut self, max_depth: Option<usize>) -> WalkOptions {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// leave out the encrypted files of the layout, instead of those of the default layout
+    pub fn with_outputs(mut self, layout: &OutputLayout) -> WalkOptions {
+        let default = OutputLayout::default().exclude_globs();
+        self.exclude.retain(|g| !default.contains(g));
+        for glob in layout.exclude_globs() {
+            if !self.exclude.contains(&glob) {
+                self.exclude.push(glob);
+            }
+        }
+        self
+    }
+
+    fn is_excluded(&self, name: &str, relative: &str) -> bool {
+        (!self.hidden && name.starts_with('.'))
+            || self.exclude.iter().any(|g| glob_matches(g, name, relative))
+    }
+
+    fn is_included(&self, name: &str, relative: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|g| glob_matches(g, name, relative))
+    }
+}
+
+fn glob_matches(glob: &str, name: &str, relative: &str) -> bool {
+    if glob.contains('/') {
+        glob_match(glob, relative)
+    } else {
+        glob_match(glob, name)
+    }
+}
+
+/// match a glob, ignoring the case
+pub fn glob_match(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    match_from(&glob, &text)
+}
+
+fn match_from(glob: &[char], text: &[char]) -> bool {
+    match glob.first() {
+        None => text.is_empty(),
+        Some('*') if glob.get(1) == Some(&'*') => {
+            let rest = glob[2..].strip_prefix(&['/']).unwrap_or(&glob[2..]);
+            // `**/` matches from the start of a path component, `**` from anywhere
+            (0..=text.len()).any(|i| {
+                ((i == 0 || text[i - 1] == '/') && match_from(rest, &text[i..]))
+                    || match_from(&glob[2..], &text[i..])
             })
-        })?
-        .collect();
-    #[allow(clippy::borrowed_box)]
-    entries.sort_by_key(|a: &Box<FolderNode>| String::from(a.clone().name()));
+        }
+        Some('*') => (0..=text.len())
+            .take_while(|i| *i == 0 || text[i - 1] != '/')
+            .any(|i| match_from(&glob[1..], &text[i..])),
+        Some('?') => !text.is_empty() && text[0] != '/' && match_from(&glob[1..], &text[1..]),
+        Some(c) => text.first() == Some(c) && match_from(&glob[1..], &text[1..]),
+    }
+}
+
+/// entry found by [`walk`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkEntry {
+    pub path: PathBuf,
+    /// path relative to the root of the walk
+    pub relative: PathBuf,
+    pub is_folder: bool,
+    /// 1 for the entries of the root
+    pub depth: usize,
+    /// the type of the entry can't be read (broken link, permissions)
+    pub error: Option<String>,
+}
+
+/// entries found by [`walk`], and the entries which can't be read
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Walk {
+    pub entries: Vec<WalkEntry>,
+    pub errors: Vec<EntryError>,
+}
 
-    folder.subfolders = entries.clone();
+impl Walk {
+    /// the readable files
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.entries
+            .iter()
+            .filter(|e| !e.is_folder && e.error.is_none())
+            .map(|e| e.path.clone())
+            .collect()
+    }
+}
+
+/// list the files and folders under the root, each folder followed by its content,
+/// sorted by name. The walk goes on after an error, the unreadable entries are
+/// listed in [`Walk::errors`]
+pub fn walk(root: &Path, options: &WalkOptions) -> Walk {
+    let mut walk = Walk::default();
+    let mut visited = vec![];
+    if options.symlinks == SymlinkPolicy::Follow {
+        match fs::canonicalize(root) {
+            Ok(canonical) => visited.push(canonical),
+            Err(e) => {
+                walk.errors.push(EntryError::new(root, e));
+                return walk;
+            }
+        }
+    }
+    walk_folder(root, Path::new(""), 1, options, &mut visited, &mut walk);
+    walk
+}
+
+/// type of an entry, `None` when the entry is left out by the symlink policy
+fn entry_type(
+    path: &Path,
+    file_type: fs::FileType,
+    options: &WalkOptions,
+) -> Option<io::Result<bool>> {
+    if !file_type.is_symlink() {
+        return Some(Ok(file_type.is_dir()));
+    }
+    if options.symlinks == SymlinkPolicy::Skip {
+        return None;
+    }
+    match fs::metadata(path) {
+        Ok(target) if target.is_dir() && options.symlinks == SymlinkPolicy::Files => None,
+        Ok(target) => Some(Ok(target.is_dir())),
+        Err(e) => Some(Err(io::Error::new(e.kind(), format!("lien cassé, {}", e)))),
+    }
+}
+
+fn walk_folder(
+    folder: &Path,
+    relative: &Path,
+    depth: usize,
+    options: &WalkOptions,
+    visited: &mut Vec<PathBuf>,
+    walk: &mut Walk,
+) {
+    let read = match read_dir(folder) {
+        Ok(read) => read,
+        Err(e) => {
+            walk.errors.push(EntryError::new(folder, e));
+            return;
+        }
+    };
+
+    let mut content = vec![];
+    for entry in read {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                walk.errors.push(EntryError::new(folder, e));
+                continue;
+            }
+        };
+        let name = entry.file_name().to_string_lossy().to_string();
+        let entry_relative = relative.join(entry.file_name());
+        let relative_text = entry_relative.to_string_lossy().replace('\\', "/");
+        if options.is_excluded(&name, &relative_text) {
+            continue;
+        }
+
+        let path = entry.path();
+        let entry_type = match entry.file_type() {
+            Ok(file_type) => entry_type(&path, file_type, options),
+            Err(e) => Some(Err(e)),
+        };
+        let (is_folder, error) = match entry_type {
+            None => continue,
+            Some(Ok(is_folder)) => (is_folder, None),
+            Some(Err(e)) => (false, Some(e.to_string())),
+        };
+        if !is_folder && !options.is_included(&name, &relative_text) {
+            continue;
+        }
+        if let Some(error) = &error {
+            walk.errors.push(EntryError::new(&path, error));
+        }
+        content.push(WalkEntry {
+            path,
+            relative: entry_relative,
+            is_folder,
+            depth,
+            error,
+        });
+    }
+    content.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
+
+    for entry in content {
+        let enter = entry.is_folder && options.max_depth.map_or(true, |max| depth < max);
+        let (path, entry_relative) = (entry.path.clone(), entry.relative.clone());
+        walk.entries.push(entry);
+        if !enter {
+            continue;
+        }
+        if options.symlinks == SymlinkPolicy::Follow {
+            match fs::canonicalize(&path) {
+                Ok(canonical) if visited.contains(&canonical) => {
+                    debug!("{} already visited", path.display());
+                    continue;
+                }
+                Ok(canonical) => visited.push(canonical),
+                Err(e) => {
+                    walk.errors.push(EntryError::new(&path, e));
+                    continue;
+                }
+            }
+        }
+        walk_folder(&path, &entry_relative, depth + 1, options, visited, walk);
+    }
+}
+
+/// read the content of the folder, with the default filters
+pub fn expand(folder: &mut FolderNode) -> Vec<EntryError> {
+    expand_with(folder, &WalkOptions::default())
+}
+
+/// read the content of the folder, keeping the entries given by the options.
+/// The readable entries are kept, the unreadable ones carry their error,
+/// as the folder when it can't be read. Returns the errors
+pub fn expand_with(folder: &mut FolderNode, options: &WalkOptions) -> Vec<EntryError> {
+    debug!("expanding {:?}", &folder.path);
+    let options = options.clone().with_max_depth(Some(1));
+    let walk = walk(&folder.path, &options);
+    folder.subfolders = walk
+        .entries
+        .into_iter()
+        .map(|e| {
+            Box::new(FolderNode {
+                path: e.path,
+                is_folder: e.is_folder,
+                error: e.error,
+                ..Default::default()
+            })
+        })
+        .collect();
+    folder.error = walk
+        .errors
+        .iter()
+        .find(|e| e.path == folder.path)
+        .map(|e| e.message.clone());
     folder.expanded = true;
-    Ok(())
+    for e in walk.errors.iter() {
+        warn!("{}", e);
+    }
+    walk.errors
+}
+
+/// read again the content of an expanded folder, the entries still present keep
+/// their state (expansion, selection, content), returns the errors
+pub fn refresh_with(folder: &mut FolderNode, options: &WalkOptions) -> Vec<EntryError> {
+    let mut previous: Vec<Box<FolderNode>> = std::mem::take(&mut folder.subfolders);
+    let errors = expand_with(folder, options);
+    for node in folder.subfolders.iter_mut() {
+        if let Some(i) = previous
+            .iter()
+            .position(|p| p.path == node.path && p.is_folder == node.is_folder)
+        {
+            let mut kept = previous.swap_remove(i);
+            if !kept.is_folder {
+                kept.error = node.error.take();
+            }
+            *node = kept;
+        }
+    }
+    errors
+}
+
+/// paths of the expanded folders, the root first
+pub fn expanded_folders(folder: &FolderNode) -> Vec<PathBuf> {
+    let mut folders = vec![];
+    collect_expanded(folder, &mut folders);
+    folders
+}
+
+fn collect_expanded(folder: &FolderNode, folders: &mut Vec<PathBuf>) {
+    if folder.is_folder && folder.expanded {
+        folders.push(folder.path.clone());
+        for sub in &folder.subfolders {
+            collect_expanded(sub, folders);
+        }
+    }
+}
+
+/// node of the tree with this path
+pub fn find_mut<'a>(folder: &'a mut FolderNode, path: &Path) -> Option<&'a mut FolderNode> {
+    if folder.path == path {
+        return Some(folder);
+    }
+    folder
+        .subfolders
+        .iter_mut()
+        .filter(|sub| path.starts_with(&sub.path))
+        .find_map(|sub| find_mut(sub, path))
+}
+
+/// select the loaded files for which the predicate is true, and unselect the others
+pub fn select_files(folder: &mut FolderNode, predicate: &mut impl FnMut(&Path) -> bool) {
+    for sub in folder.subfolders.iter_mut() {
+        if sub.is_folder {
+            select_files(sub, predicate);
+        } else {
+            sub.selected = sub.error.is_none() && predicate(&sub.path);
+        }
+    }
+}
+
+/// collect the paths of the selected files, in tree order
+pub fn selected_paths(folder: &FolderNode) -> Vec<PathBuf> {
+    let mut paths = vec![];
+    collect_selected(folder, &mut paths);
+    paths
+}
+
+fn collect_selected(folder: &FolderNode, paths: &mut Vec<PathBuf>) {
+    if folder.selected {
+        paths.push(folder.path.clone());
+    }
+    for sub in &folder.subfolders {
+        collect_selected(sub, paths);
+    }
 }
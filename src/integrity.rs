@@ -0,0 +1,520 @@
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::key_store::KeyStore;
+use crate::keys_management::{
+    insertion_kind, AuditEntry, AuditFilter, Database, Key, KeyHistoryEntry, KeyInsertion,
+    KeyManagementError, Revocation,
+};
+
+/// environment variable holding the passphrase protecting the key records,
+/// the local master key is used when it is not set. The master key is stored
+/// next to the database, it only detects the accidental changes
+pub const PASSPHRASE_ENV: &str = "ENCRYPTER_KEYS_PASSPHRASE";
+
+const MASTER_KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+const PBKDF2_ITERATIONS: usize = 100_000;
+// version of the tagged content, changes invalidate all the tags
+const TAG_CONTEXT: &[u8] = b"encrypter key record v1";
+const CHECK_CONTEXT: &[u8] = b"encrypter integrity check v1";
+// metadata of a protected database, the check value of its key,
+// and the mark set by the schema migration until the protection is set up
+const CHECK_METADATA: &str = "integrity_check";
+const PENDING_METADATA: &str = "integrity_pending";
+
+/// result of the verification of a key record
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IntegrityStatus {
+    /// the tag matches the record
+    Valid,
+    /// the record has no tag, it was added outside of the application,
+    /// the records written before the protection are signed when it is set up
+    Unsigned,
+    /// the tag does not match, the record was changed outside of the application
+    Tampered,
+    /// the store does not protect its records
+    Unchecked,
+}
+
+impl IntegrityStatus {
+    /// the record was changed or added outside of the application, it must not be used
+    pub fn is_altered(self) -> bool {
+        matches!(self, IntegrityStatus::Tampered | IntegrityStatus::Unsigned)
+    }
+}
+
+/// secret used to compute the hmac of the key records
+pub struct IntegrityKey {
+    secret: Vec<u8>,
+}
+
+fn integrity_error<E: std::fmt::Display>(e: E) -> KeyManagementError {
+    KeyManagementError::IntegrityKey(e.to_string())
+}
+
+/// read a random secret from a file, `None` when the file does not exist
+fn read_secret(path: &Path, size: usize) -> Result<Option<Vec<u8>>, KeyManagementError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let secret = fs::read(path).map_err(integrity_error)?;
+    if secret.len() != size {
+        return Err(KeyManagementError::IntegrityKey(format!(
+            "le fichier {} est invalide",
+            path.display()
+        )));
+    }
+    Ok(Some(secret))
+}
+
+/// create a random secret in a file, only readable by its owner
+fn create_secret(path: &Path, size: usize) -> Result<Vec<u8>, KeyManagementError> {
+    let mut secret = vec![0; size];
+    rand_bytes(&mut secret).map_err(integrity_error)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(integrity_error)?;
+    }
+    fs::write(path, &secret).map_err(integrity_error)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(integrity_error)?;
+    }
+    Ok(secret)
+}
+
+/// read a random secret from a file, creating it when `create` is set,
+/// a missing secret can't be replaced once the database is protected by it
+fn load_secret(path: &Path, size: usize, create: bool) -> Result<Vec<u8>, KeyManagementError> {
+    match read_secret(path, size)? {
+        Some(secret) => Ok(secret),
+        None if create => create_secret(path, size),
+        None => Err(KeyManagementError::IntegrityKey(format!(
+            "le fichier {} est introuvable, la base de clés ne peut pas être vérifiée",
+            path.display()
+        ))),
+    }
+}
+
+/// file of the local master key of a database
+pub fn master_key_path(database_path: &Path) -> PathBuf {
+    let mut name = database_path.as_os_str().to_owned();
+    name.push(".key");
+    PathBuf::from(name)
+}
+
+/// file of the salt used with a passphrase for a database
+pub fn salt_path(database_path: &Path) -> PathBuf {
+    let mut name = database_path.as_os_str().to_owned();
+    name.push(".salt");
+    PathBuf::from(name)
+}
+
+impl IntegrityKey {
+    pub fn new(secret: &[u8]) -> IntegrityKey {
+        IntegrityKey {
+            secret: secret.to_vec(),
+        }
+    }
+
+    /// derive the secret from a user passphrase
+    pub fn from_passphrase(
+        passphrase: &str,
+        salt: &[u8],
+    ) -> Result<IntegrityKey, KeyManagementError> {
+        let mut secret = vec![0; MASTER_KEY_SIZE];
+        pbkdf2_hmac(
+            passphrase.as_bytes(),
+            salt,
+            PBKDF2_ITERATIONS,
+            MessageDigest::sha256(),
+            &mut secret,
+        )
+        .map_err(integrity_error)?;
+        Ok(IntegrityKey { secret })
+    }
+
+    /// local master key stored in a file, created on first use
+    pub fn load_or_create(path: &Path) -> Result<IntegrityKey, KeyManagementError> {
+        let secret = load_secret(path, MASTER_KEY_SIZE, true)?;
+        Ok(IntegrityKey { secret })
+    }
+
+    /// key protecting the database at the given path,
+    /// derived from the passphrase of [`PASSPHRASE_ENV`] if set,
+    /// the local master key next to the database otherwise
+    pub fn for_database(database_path: &Path) -> Result<IntegrityKey, KeyManagementError> {
+        database_key(database_path, true)
+    }
+
+    /// value recorded in a protected database, telling whether a key is the one protecting it
+    pub fn check_value(&self) -> Result<Vec<u8>, KeyManagementError> {
+        let pkey = PKey::hmac(&self.secret).map_err(integrity_error)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).map_err(integrity_error)?;
+        signer.update(CHECK_CONTEXT).map_err(integrity_error)?;
+        signer.sign_to_vec().map_err(integrity_error)
+    }
+
+    /// hmac-sha256 of the protected fields of a key: sha1, name, public key,
+    /// expiry and revocation
+    pub fn tag(&self, k: &Key) -> Result<Vec<u8>, KeyManagementError> {
+        let pkey = PKey::hmac(&self.secret).map_err(integrity_error)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).map_err(integrity_error)?;
+        let expires_at = k.expires_at.map(i64::to_be_bytes);
+        let revoked_at = k.revoked_at.map(i64::to_be_bytes);
+        let mut fields: Vec<Option<&[u8]>> = vec![
+            Some(TAG_CONTEXT),
+            Some(k.sha1.as_bytes()),
+            Some(k.name.as_bytes()),
+            k.public_key.as_deref(),
+        ];
+        // only when set, the tags of the keys without expiry or revocation are unchanged
+        if let Some(expires_at) = &expires_at {
+            fields.extend([Some(&b"expires_at"[..]), Some(&expires_at[..])]);
+        }
+        if let Some(revoked_at) = &revoked_at {
+            fields.extend([
+                Some(&b"revoked_at"[..]),
+                Some(&revoked_at[..]),
+                Some(k.revocation_reason.as_bytes()),
+            ]);
+        }
+        for field in fields {
+            // length prefixed, a missing public key differs from an empty one
+            let content = match field {
+                Some(content) => {
+                    signer
+                        .update(&(content.len() as u64).to_be_bytes())
+                        .map_err(integrity_error)?;
+                    content
+                }
+                None => &[0xff; 8][..],
+            };
+            signer.update(content).map_err(integrity_error)?;
+        }
+        signer.sign_to_vec().map_err(integrity_error)
+    }
+
+    pub fn verify(&self, k: &Key) -> IntegrityStatus {
+        let tag = match &k.integrity {
+            Some(tag) => tag,
+            None => return IntegrityStatus::Unsigned,
+        };
+        match self.tag(k) {
+            Ok(expected) if expected.len() == tag.len() && memcmp::eq(&expected, tag) => {
+                IntegrityStatus::Valid
+            }
+            _ => IntegrityStatus::Tampered,
+        }
+    }
+}
+
+/// key protecting a database, its master key or salt is only created when `create` is set
+fn database_key(database_path: &Path, create: bool) -> Result<IntegrityKey, KeyManagementError> {
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) if !passphrase.is_empty() => {
+            let salt = load_secret(&salt_path(database_path), SALT_SIZE, create)?;
+            IntegrityKey::from_passphrase(&passphrase, &salt)
+        }
+        _ => {
+            warn!(
+                "{} is not set, the key records are only protected against accidental changes",
+                PASSPHRASE_ENV
+            );
+            let secret = load_secret(&master_key_path(database_path), MASTER_KEY_SIZE, create)?;
+            Ok(IntegrityKey { secret })
+        }
+    }
+}
+
+/// open the key database at the given path, protected by its integrity key.
+///
+/// The protection is set up once, by the first protected open after the schema migration
+/// marking it pending: the key is created, the records written before are signed
+/// and the check value of the key is recorded in the database. Afterwards the database
+/// is refused when its key or salt is missing, when the key does not match
+/// (other passphrase or master key), or when the protection was removed
+pub fn open_protected(path: &Path) -> Result<IntegrityKeyStore, Box<dyn Error>> {
+    let db = Database::open(path)?;
+    let key = match db.get_metadata(CHECK_METADATA)? {
+        Some(check) => {
+            let key = database_key(path, false)?;
+            let expected = key.check_value()?;
+            if expected.len() != check.len() || !memcmp::eq(&expected, &check) {
+                return Err(KeyManagementError::IntegrityKey(format!(
+                    "la clé d'intégrité ne correspond pas à la base de clés {} \
+                    (phrase de passe, clé maître ou sel différents)",
+                    path.display()
+                ))
+                .into());
+            }
+            key
+        }
+        None if db.get_metadata(PENDING_METADATA)?.is_some() => {
+            let key = database_key(path, true)?;
+            let check = key.check_value()?;
+            let mut signed = 0;
+            db.atomically(&mut || {
+                signed = sign_records(&db, &key)?;
+                db.set_metadata(CHECK_METADATA, Some(&check))?;
+                db.set_metadata(PENDING_METADATA, None)
+            })?;
+            info!(
+                "integrity protection set up, {} existing keys signed",
+                signed
+            );
+            key
+        }
+        None => {
+            return Err(KeyManagementError::IntegrityKey(format!(
+                "la protection de la base de clés {} a été retirée en dehors de l'application",
+                path.display()
+            ))
+            .into());
+        }
+    };
+    Ok(IntegrityKeyStore::new(Box::new(db), key))
+}
+
+/// sign the records without tag, returns the number of signed records
+fn sign_records(store: &dyn KeyStore, key: &IntegrityKey) -> Result<usize, KeyManagementError> {
+    let mut signed = 0;
+    for mut k in store.get_all()? {
+        if k.integrity.is_none() {
+            k.integrity = Some(key.tag(&k)?);
+            store.insert(&k)?;
+            signed += 1;
+        }
+    }
+    Ok(signed)
+}
+
+/// key store signing the key records it writes and verifying them on load
+pub struct IntegrityKeyStore {
+    inner: Box<dyn KeyStore>,
+    key: IntegrityKey,
+}
+
+impl IntegrityKeyStore {
+    pub fn new(inner: Box<dyn KeyStore>, key: IntegrityKey) -> IntegrityKeyStore {
+        IntegrityKeyStore { inner, key }
+    }
+
+    /// sign the records without tag, returns the number of signed records
+    pub fn sign_unsigned(&self) -> Result<usize, KeyManagementError> {
+        let mut signed = 0;
+        self.inner.atomically(&mut || {
+            signed = sign_records(self.inner.as_ref(), &self.key)?;
+            Ok(())
+        })?;
+        Ok(signed)
+    }
+
+    fn insert_signed(&self, k: &Key) -> Result<KeyInsertion, KeyManagementError> {
+        let stored = match self.inner.get_by_sha1(&k.sha1) {
+            Ok(stored) => Some(stored),
+            Err(KeyManagementError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+
+        let mut signed = k.clone();
+        match stored {
+            // the stored public key is kept, the tag covers it
+            Some(s)
+                if k.public_key.is_none()
+                    || insertion_kind(Some(&s), k) == KeyInsertion::ChangePending =>
+            {
+                if self.key.verify(&s).is_altered() {
+                    signed.integrity = s.integrity.clone();
+                } else {
+                    let mut kept = k.clone();
+                    kept.public_key = s.public_key.clone();
+                    signed.integrity = Some(self.key.tag(&kept)?);
+                }
+            }
+            _ => signed.integrity = Some(self.key.tag(k)?),
+        }
+        self.inner.insert(&signed)
+    }
+
+    /// apply a change to the protected fields of a key, the record is signed
+    /// again unless it was tampered with before the change, the change and
+    /// the new tag are written together
+    fn update_signed<F>(&self, sha1: &str, update: F) -> Result<(), KeyManagementError>
+    where
+        F: Fn(&dyn KeyStore) -> Result<(), KeyManagementError>,
+    {
+        self.inner.atomically(&mut || {
+            let intact = !self.key.verify(&self.inner.get_by_sha1(sha1)?).is_altered();
+            update(self.inner.as_ref())?;
+            if intact {
+                self.sign_again(sha1)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// apply a change making the current record of a key trusted, and sign it,
+    /// the change and the new tag are written together
+    fn update_trusted<F>(&self, sha1: &str, update: F) -> Result<(), KeyManagementError>
+    where
+        F: Fn(&dyn KeyStore) -> Result<(), KeyManagementError>,
+    {
+        self.inner.atomically(&mut || {
+            update(self.inner.as_ref())?;
+            self.sign_again(sha1)
+        })
+    }
+
+    fn sign_again(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        let k = self.inner.get_by_sha1(sha1)?;
+        self.insert_signed(&k).map(|_| ())
+    }
+
+    /// current record of a key, refused if it was changed or added outside of the application
+    fn get_verified(&self, sha1: &str) -> Result<Key, KeyManagementError> {
+        let k = self.inner.get_by_sha1(sha1)?;
+        if self.key.verify(&k).is_altered() {
+            return Err(KeyManagementError::Tampered(sha1.into()));
+        }
+        Ok(k)
+    }
+}
+
+impl KeyStore for IntegrityKeyStore {
+    fn path(&self) -> Option<&Path> {
+        self.inner.path()
+    }
+
+    fn atomically(
+        &self,
+        changes: &mut dyn FnMut() -> Result<(), KeyManagementError>,
+    ) -> Result<(), KeyManagementError> {
+        self.inner.atomically(changes)
+    }
+
+    fn verify_integrity(&self, k: &Key) -> IntegrityStatus {
+        self.key.verify(k)
+    }
+
+    fn insert(&self, k: &Key) -> Result<KeyInsertion, KeyManagementError> {
+        self.insert_signed(k)
+    }
+
+    fn get_all(&self) -> Result<Vec<Key>, KeyManagementError> {
+        self.inner.get_all()
+    }
+
+    fn get_by_sha1(&self, sha1: &str) -> Result<Key, KeyManagementError> {
+        self.inner.get_by_sha1(sha1)
+    }
+
+    fn find_by_prefix(&self, prefix: &str) -> Result<Vec<Key>, KeyManagementError> {
+        self.inner.find_by_prefix(prefix)
+    }
+
+    fn search(&self, text: &str) -> Result<Vec<Key>, KeyManagementError> {
+        self.inner.search(text)
+    }
+
+    fn rename(&self, sha1: &str, name: &str) -> Result<(), KeyManagementError> {
+        // signing the renamed record would validate a tampered public key
+        let mut k = self.get_verified(sha1)?;
+        k.name = name.into();
+        self.insert_signed(&k).map(|_| ())
+    }
+
+    fn update_public_key(&self, sha1: &str, public_key: &[u8]) -> Result<(), KeyManagementError> {
+        // replacing the public key is allowed on a tampered record, it repairs it
+        self.update_trusted(sha1, |inner| inner.update_public_key(sha1, public_key))
+    }
+
+    fn update_metadata(&self, k: &Key) -> Result<(), KeyManagementError> {
+        self.inner.update_metadata(k)
+    }
+
+    fn set_expiry(&self, sha1: &str, expires_at: Option<i64>) -> Result<(), KeyManagementError> {
+        self.update_signed(sha1, |inner| inner.set_expiry(sha1, expires_at))
+    }
+
+    fn revoke(&self, revocation: &Revocation) -> Result<(), KeyManagementError> {
+        // a tampered key may still be revoked, it stays flagged
+        self.update_signed(&revocation.sha1, |inner| inner.revoke(revocation))
+    }
+
+    fn reinstate(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        self.update_signed(sha1, |inner| inner.reinstate(sha1))
+    }
+
+    fn mark_used(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        self.inner.mark_used(sha1)
+    }
+
+    fn delete(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        self.inner.delete(sha1)
+    }
+
+    fn create_group(&self, name: &str) -> Result<(), KeyManagementError> {
+        self.inner.create_group(name)
+    }
+
+    fn delete_group(&self, name: &str) -> Result<(), KeyManagementError> {
+        self.inner.delete_group(name)
+    }
+
+    fn get_groups(&self) -> Result<Vec<String>, KeyManagementError> {
+        self.inner.get_groups()
+    }
+
+    fn add_to_group(&self, group: &str, sha1: &str) -> Result<(), KeyManagementError> {
+        self.inner.add_to_group(group, sha1)
+    }
+
+    fn remove_from_group(&self, group: &str, sha1: &str) -> Result<(), KeyManagementError> {
+        self.inner.remove_from_group(group, sha1)
+    }
+
+    fn get_group_members(&self, group: &str) -> Result<Vec<Key>, KeyManagementError> {
+        self.inner.get_group_members(group)
+    }
+
+    fn get_key_groups(&self, sha1: &str) -> Result<Vec<String>, KeyManagementError> {
+        self.inner.get_key_groups(sha1)
+    }
+
+    fn record_encryption(&self, entry: &AuditEntry) -> Result<i64, KeyManagementError> {
+        self.inner.record_encryption(entry)
+    }
+
+    fn get_audit_log(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, KeyManagementError> {
+        self.inner.get_audit_log(filter)
+    }
+
+    fn get_key_history(&self, sha1: &str) -> Result<Vec<KeyHistoryEntry>, KeyManagementError> {
+        self.inner.get_key_history(sha1)
+    }
+
+    fn get_pending_key_changes(&self) -> Result<Vec<KeyHistoryEntry>, KeyManagementError> {
+        self.inner.get_pending_key_changes()
+    }
+
+    fn accept_public_key(&self, sha1: &str, id: i64) -> Result<(), KeyManagementError> {
+        // an explicit decision of the operator, like an update of the public key
+        self.update_trusted(sha1, |inner| inner.accept_public_key(sha1, id))
+    }
+
+    fn reject_public_key(&self, sha1: &str, id: i64) -> Result<(), KeyManagementError> {
+        self.inner.reject_public_key(sha1, id)
+    }
+}
@@ -0,0 +1,407 @@
+//! import and export of the whole key book,
+//! to share the keys between several workstations
+//!
+//! the key book can be exchanged as a json document, a csv file,
+//! or a folder of pem files named by the key sha1
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::info;
+
+use crate::encrypt::check_public_key;
+use crate::keys_management::{Key, KeyInsertion, KeyManagementError, KeySource, KeyStore};
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyBookError {
+    #[error("erreur de lecture / écriture : {0}")]
+    Io(#[from] io::Error),
+    #[error("document json invalide : {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("fichier csv invalide, ligne {0} : {1}")]
+    Csv(usize, String),
+    #[error("la clé publique de {0} est invalide")]
+    InvalidPublicKey(String),
+    #[error("le fichier {0} n'est pas nommé par le sha1 de la clé")]
+    InvalidPemFileName(String),
+    #[error(transparent)]
+    Key(#[from] KeyManagementError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyBookFormat {
+    Json,
+    Csv,
+    /// folder of `<sha1>.pem` files
+    PemBundle,
+}
+
+/// how imported keys are reconciled with the keys already stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportStrategy {
+    /// add the new keys and complete the existing ones (empty fields or missing public key),
+    /// conflicting public keys are left unchanged
+    Merge,
+    /// imported keys replace the stored ones
+    Overwrite,
+    /// only add the new keys
+    SkipExisting,
+}
+
+/// a key, as exchanged in a key book
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct KeyRecord {
+    #[serde(default)]
+    pub name: String,
+    pub sha1: String,
+    /// pem content of the public key
+    #[serde(default)]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    pub owner: String,
+    #[serde(default)]
+    pub instrument_model: String,
+    #[serde(default)]
+    pub instrument_serial: String,
+    #[serde(default)]
+    pub notes: String,
+}
+
+impl KeyRecord {
+    fn from_key(k: &Key) -> KeyRecord {
+        KeyRecord {
+            name: k.name.clone(),
+            sha1: k.sha1.clone(),
+            public_key: k
+                .public_key
+                .as_ref()
+                .map(|p| String::from_utf8_lossy(p).to_string()),
+            owner: k.owner.clone(),
+            instrument_model: k.instrument_model.clone(),
+            instrument_serial: k.instrument_serial.clone(),
+            notes: k.notes.clone(),
+        }
+    }
+
+    fn to_key(&self) -> Key {
+        Key {
+            rowid: 0,
+            name: if self.name.is_empty() {
+                self.sha1.clone()
+            } else {
+                self.name.clone()
+            },
+            sha1: self.sha1.clone(),
+            public_key: self.public_key.as_ref().map(|p| p.as_bytes().to_vec()),
+            owner: self.owner.clone(),
+            instrument_model: self.instrument_model.clone(),
+            instrument_serial: self.instrument_serial.clone(),
+            notes: self.notes.clone(),
+            source: KeySource::Imported,
+            ..Default::default()
+        }
+    }
+}
+
+/// the same sha1, with a different public key
+#[derive(Debug, Clone)]
+pub struct KeyConflict {
+    pub existing: Key,
+    pub imported: KeyRecord,
+}
+
+/// what an import would change, computed before applying it
+#[derive(Debug, Clone, Default)]
+pub struct ImportPreview {
+    pub new_keys: Vec<KeyRecord>,
+    /// same sha1 and public key, possibly another name
+    pub existing: Vec<KeyRecord>,
+    pub conflicts: Vec<KeyConflict>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub added: usize,
+    pub updated: usize,
+    pub skipped: usize,
+    /// keys imported with a different public key, waiting for the operator
+    pub pending: usize,
+}
+
+/// export all the stored keys, returns the number of exported keys
+pub fn export_keys(
+    db: &dyn KeyStore,
+    format: KeyBookFormat,
+    path: &Path,
+) -> Result<usize, KeyBookError> {
+    let records: Vec<KeyRecord> = db.get_all()?.iter().map(KeyRecord::from_key).collect();
+    info!("exporting {} keys to {}", records.len(), path.display());
+
+    match format {
+        KeyBookFormat::Json => fs::write(path, serde_json::to_string_pretty(&records)?)?,
+        KeyBookFormat::Csv => fs::write(path, write_csv(&records))?,
+        KeyBookFormat::PemBundle => {
+            fs::create_dir_all(path)?;
+            for r in &records {
+                if let Some(pem) = &r.public_key {
+                    fs::write(path.join(r.sha1.clone() + ".pem"), pem)?;
+                }
+            }
+        }
+    }
+    Ok(records.len())
+}
+
+/// read a key book, the public keys are checked
+pub fn read_key_book(format: KeyBookFormat, path: &Path) -> Result<Vec<KeyRecord>, KeyBookError> {
+    let records = match format {
+        KeyBookFormat::Json => serde_json::from_str(&fs::read_to_string(path)?)?,
+        KeyBookFormat::Csv => read_csv(&fs::read_to_string(path)?)?,
+        KeyBookFormat::PemBundle => read_pem_bundle(path)?,
+    };
+
+    for r in &records {
+        if let Some(pem) = &r.public_key {
+            if check_public_key(pem.as_bytes()).is_err() {
+                return Err(KeyBookError::InvalidPublicKey(r.sha1.clone()));
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// compare the imported keys with the stored ones
+pub fn preview_import(
+    db: &dyn KeyStore,
+    records: &[KeyRecord],
+) -> Result<ImportPreview, KeyBookError> {
+    let mut preview = ImportPreview::default();
+    for r in records {
+        match db.get_by_sha1(&r.sha1) {
+            Ok(existing) => {
+                let existing_pem = KeyRecord::from_key(&existing).public_key;
+                if r.public_key.is_some() && existing_pem.is_some() && r.public_key != existing_pem
+                {
+                    preview.conflicts.push(KeyConflict {
+                        existing,
+                        imported: r.clone(),
+                    });
+                } else {
+                    preview.existing.push(r.clone());
+                }
+            }
+            Err(KeyManagementError::NotFound(_)) => preview.new_keys.push(r.clone()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(preview)
+}
+
+/// import the keys, following the given strategy
+pub fn import_keys(
+    db: &dyn KeyStore,
+    records: &[KeyRecord],
+    strategy: ImportStrategy,
+) -> Result<ImportReport, KeyBookError> {
+    let mut report = ImportReport::default();
+    for r in records {
+        let existing = match db.get_by_sha1(&r.sha1) {
+            Ok(k) => Some(k),
+            Err(KeyManagementError::NotFound(_)) => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        match (existing, strategy) {
+            (None, _) => {
+                db.insert(&r.to_key())?;
+                report.added += 1;
+            }
+            (Some(_), ImportStrategy::SkipExisting) => report.skipped += 1,
+            (Some(_), ImportStrategy::Overwrite) => match db.insert(&r.to_key())? {
+                KeyInsertion::ChangePending => report.pending += 1,
+                _ => report.updated += 1,
+            },
+            (Some(mut k), ImportStrategy::Merge) => {
+                let mut changed = false;
+                if k.name.is_empty() && !r.name.is_empty() {
+                    k.name = r.name.clone();
+                    changed = true;
+                }
+                if k.public_key.is_none() && r.public_key.is_some() {
+                    k.public_key = r.public_key.as_ref().map(|p| p.as_bytes().to_vec());
+                    changed = true;
+                }
+                for (field, imported) in [
+                    (&mut k.owner, &r.owner),
+                    (&mut k.instrument_model, &r.instrument_model),
+                    (&mut k.instrument_serial, &r.instrument_serial),
+                    (&mut k.notes, &r.notes),
+                ] {
+                    if field.is_empty() && !imported.is_empty() {
+                        *field = imported.clone();
+                        changed = true;
+                    }
+                }
+                if changed {
+                    db.insert(&k)?;
+                    report.updated += 1;
+                } else {
+                    report.skipped += 1;
+                }
+            }
+        }
+    }
+    info!("keys imported : {:?}", report);
+    Ok(report)
+}
+
+fn read_pem_bundle(folder: &Path) -> Result<Vec<KeyRecord>, KeyBookError> {
+    let mut records = vec![];
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("pem") {
+            continue;
+        }
+        let sha1 = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(s) if s.len() == 40 => s.to_string(),
+            _ => return Err(KeyBookError::InvalidPemFileName(path.display().to_string())),
+        };
+        records.push(KeyRecord {
+            name: "".into(),
+            sha1,
+            public_key: Some(fs::read_to_string(&path)?),
+            ..Default::default()
+        });
+    }
+    records.sort_by(|a, b| a.sha1.cmp(&b.sha1));
+    Ok(records)
+}
+
+/// csv columns, files written before the key metadata only have the first three
+const CSV_HEADER: [&str; 7] = [
+    "name",
+    "sha1",
+    "public_key",
+    "owner",
+    "instrument_model",
+    "instrument_serial",
+    "notes",
+];
+const CSV_MIN_COLUMNS: usize = 3;
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_csv(records: &[KeyRecord]) -> String {
+    let mut out = CSV_HEADER.join(",") + "\n";
+    for r in records {
+        let fields = [
+            csv_field(&r.name),
+            csv_field(&r.sha1),
+            csv_field(r.public_key.as_deref().unwrap_or("")),
+            csv_field(&r.owner),
+            csv_field(&r.instrument_model),
+            csv_field(&r.instrument_serial),
+            csv_field(&r.notes),
+        ];
+        out += &fields.join(",");
+        out += "\n";
+    }
+    out
+}
+
+/// split the csv content in rows of fields, quoted fields may span several lines
+fn parse_csv(content: &str) -> Result<Vec<(usize, Vec<String>)>, KeyBookError> {
+    let mut rows = vec![];
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut row_line = 1;
+    let mut chars = content.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            line += 1;
+        }
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            ',' => fields.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                fields.push(std::mem::take(&mut field));
+                rows.push((row_line, std::mem::take(&mut fields)));
+                row_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(KeyBookError::Csv(row_line, "guillemet non fermé".into()));
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        rows.push((row_line, fields));
+    }
+    Ok(rows)
+}
+
+fn read_csv(content: &str) -> Result<Vec<KeyRecord>, KeyBookError> {
+    let mut rows = parse_csv(content)?.into_iter();
+    let columns = match rows.next() {
+        Some((_, header))
+            if header.len() >= CSV_MIN_COLUMNS
+                && header.len() <= CSV_HEADER.len()
+                && header == CSV_HEADER[..header.len()] =>
+        {
+            header.len()
+        }
+        _ => {
+            return Err(KeyBookError::Csv(
+                1,
+                format!("l'entête doit être {}", CSV_HEADER.join(",")),
+            ))
+        }
+    };
+
+    let mut records = vec![];
+    for (line, fields) in rows {
+        if fields.len() == 1 && fields[0].is_empty() {
+            continue;
+        }
+        if fields.len() != columns {
+            return Err(KeyBookError::Csv(
+                line,
+                format!("{} colonnes attendues", columns),
+            ));
+        }
+        let mut fields = fields.into_iter();
+        records.push(KeyRecord {
+            name: fields.next().unwrap_or_default(),
+            sha1: fields.next().unwrap_or_default(),
+            public_key: fields.next().filter(|p| !p.is_empty()),
+            owner: fields.next().unwrap_or_default(),
+            instrument_model: fields.next().unwrap_or_default(),
+            instrument_serial: fields.next().unwrap_or_default(),
+            notes: fields.next().unwrap_or_default(),
+        });
+    }
+    Ok(records)
+}
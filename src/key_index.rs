@@ -0,0 +1,224 @@
+//! in memory index of the keys, to find a key by name, sha1 prefix, group or owner
+//! without querying the key store on each search
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::integrity::IntegrityStatus;
+use crate::keys_management::{Key, KeyManagementError, KeyStore};
+
+/// criteria of a key search, all of them must match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyQuery {
+    /// beginnings of a word of the name, owner, instrument or notes, of a group name,
+    /// or of the sha1
+    pub words: Vec<String>,
+    pub sha1_prefix: Option<String>,
+    /// groups the key must belong to, `tag:` in the search text
+    pub groups: Vec<String>,
+    /// beginnings of a word of the owner
+    pub owner: Vec<String>,
+}
+
+impl KeyQuery {
+    /// parse a search text, `tag:`, `owner:` and `sha1:` select a criteria,
+    /// other words are searched in every field
+    pub fn parse(text: &str) -> KeyQuery {
+        let mut query = KeyQuery::default();
+        for token in text.split_whitespace().map(str::to_lowercase) {
+            if let Some(tag) = token.strip_prefix("tag:") {
+                query.groups.push(tag.into());
+            } else if let Some(owner) = token.strip_prefix("owner:") {
+                query.owner.extend(words(owner));
+            } else if let Some(sha1) = token.strip_prefix("sha1:") {
+                query.sha1_prefix = Some(sha1.into());
+            } else {
+                query.words.extend(words(&token));
+            }
+        }
+        query
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+            && self.sha1_prefix.is_none()
+            && self.groups.is_empty()
+            && self.owner.is_empty()
+    }
+}
+
+/// lowercase words of a text
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+type Postings = BTreeMap<String, BTreeSet<usize>>;
+
+/// keys whose indexed value starts with the prefix
+fn prefix_match(postings: &Postings, prefix: &str) -> BTreeSet<usize> {
+    postings
+        .range(prefix.to_string()..)
+        .take_while(|(value, _)| value.starts_with(prefix))
+        .flat_map(|(_, keys)| keys.iter().copied())
+        .collect()
+}
+
+/// keys indexed by the words of their name, owner, instrument and notes, their groups and sha1,
+/// with the integrity status of their record
+#[derive(Default)]
+pub struct KeyIndex {
+    /// ordered by name
+    keys: Vec<Key>,
+    /// integrity status of each key, checked once when the index is built
+    integrity: Vec<IntegrityStatus>,
+    /// all the groups, with their members
+    members: Vec<(String, BTreeSet<usize>)>,
+    names: Postings,
+    owners: Postings,
+    /// words of the instrument and notes
+    details: Postings,
+    /// lowercase group names
+    groups: Postings,
+    sha1: Postings,
+}
+
+impl KeyIndex {
+    /// index the keys, with the groups and the sha1 of their members
+    pub fn new(mut keys: Vec<Key>, groups: &[(String, Vec<String>)]) -> KeyIndex {
+        keys.sort_by_key(|k| k.name.to_lowercase());
+        let mut index = KeyIndex::default();
+        for (position, k) in keys.iter().enumerate() {
+            let add = |postings: &mut Postings, value: String| {
+                postings.entry(value).or_default().insert(position);
+            };
+            for w in words(&k.name) {
+                add(&mut index.names, w);
+            }
+            for w in words(&k.owner) {
+                add(&mut index.owners, w);
+            }
+            for field in [&k.instrument_model, &k.instrument_serial, &k.notes] {
+                for w in words(field) {
+                    add(&mut index.details, w);
+                }
+            }
+            for (group, members) in groups.iter() {
+                if members.contains(&k.sha1) {
+                    add(&mut index.groups, group.to_lowercase());
+                }
+            }
+            add(&mut index.sha1, k.sha1.to_lowercase());
+        }
+        index.members = groups
+            .iter()
+            .map(|(group, members)| {
+                let positions = keys
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, k)| members.contains(&k.sha1))
+                    .map(|(i, _)| i)
+                    .collect();
+                (group.clone(), positions)
+            })
+            .collect();
+        index.integrity = vec![IntegrityStatus::Unchecked; keys.len()];
+        index.keys = keys;
+        index
+    }
+
+    /// index all the keys of a store, with their groups and integrity status
+    pub fn build(db: &dyn KeyStore) -> Result<KeyIndex, KeyManagementError> {
+        let mut groups = vec![];
+        for group in db.get_groups()? {
+            let members = db
+                .get_group_members(&group)?
+                .into_iter()
+                .map(|k| k.sha1)
+                .collect();
+            groups.push((group, members));
+        }
+        let mut index = KeyIndex::new(db.get_all()?, &groups);
+        index.integrity = index.keys.iter().map(|k| db.verify_integrity(k)).collect();
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// all the keys, ordered by name
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    fn position(&self, sha1: &str) -> Option<usize> {
+        self.sha1
+            .get(&sha1.to_lowercase())
+            .and_then(|positions| positions.iter().next())
+            .copied()
+    }
+
+    /// the indexed key with this sha1
+    pub fn get(&self, sha1: &str) -> Option<&Key> {
+        self.position(sha1).map(|i| &self.keys[i])
+    }
+
+    /// integrity status of the indexed key with this sha1,
+    /// [`IntegrityStatus::Unchecked`] for a key not in the index
+    pub fn integrity(&self, sha1: &str) -> IntegrityStatus {
+        self.position(sha1)
+            .map(|i| self.integrity[i])
+            .unwrap_or(IntegrityStatus::Unchecked)
+    }
+
+    /// names of all the groups, in the order of the store
+    pub fn groups(&self) -> Vec<&str> {
+        self.members.iter().map(|(g, _)| g.as_str()).collect()
+    }
+
+    /// keys of a group, ordered by name
+    pub fn group_members(&self, group: &str) -> Vec<&Key> {
+        self.members
+            .iter()
+            .filter(|(g, _)| g == group)
+            .flat_map(|(_, members)| members.iter().map(|&i| &self.keys[i]))
+            .collect()
+    }
+
+    /// keys matching the query, ordered by name
+    pub fn search(&self, query: &KeyQuery) -> Vec<&Key> {
+        let mut criteria: Vec<BTreeSet<usize>> = vec![];
+        for w in query.words.iter() {
+            let mut found = prefix_match(&self.names, w);
+            found.extend(prefix_match(&self.owners, w));
+            found.extend(prefix_match(&self.details, w));
+            found.extend(prefix_match(&self.groups, w));
+            found.extend(prefix_match(&self.sha1, w));
+            criteria.push(found);
+        }
+        if let Some(prefix) = &query.sha1_prefix {
+            criteria.push(prefix_match(&self.sha1, prefix));
+        }
+        for g in query.groups.iter() {
+            criteria.push(self.groups.get(g).cloned().unwrap_or_default());
+        }
+        for w in query.owner.iter() {
+            criteria.push(prefix_match(&self.owners, w));
+        }
+
+        let mut criteria = criteria.into_iter();
+        match criteria.next() {
+            None => self.keys.iter().collect(),
+            Some(first) => {
+                let matching = criteria.fold(first, |found, c| &found & &c);
+                matching.into_iter().map(|i| &self.keys[i]).collect()
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! sources of the instrument public keys: the key server, a key folder
+//! (usb stick or mounted share) mirroring the server layout `<sha1>/public.key.pem`,
+//! and an ordered list of sources tried in turn
+//!
+//! every source has the same lookup semantics, the key must be signed
+//! by the publisher for the sha1 (`<sha1>/public.key.sig`)
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::key_signature::{PublisherKey, PublisherTrust};
+use crate::keys_management::{check_sha1, KeySource};
+use crate::keyserver::{check_published_key, KeyServerClient, KeyServerError};
+
+/// environment variable giving the ordered key sources, urls of key servers
+/// or key folders separated by `;`
+pub const KEY_SOURCES_ENV: &str = "ENCRYPTER_KEY_SOURCES";
+
+/// public key found by a source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchedKey {
+    pub public_key: Vec<u8>,
+    pub origin: KeySource,
+}
+
+pub trait KeyProvider: Send + Sync {
+    /// description of the source, for the operator
+    fn describe(&self) -> String;
+
+    /// public key of the sha1, checked and signed by the publisher,
+    /// [`KeyServerError::NotFound`] when the source does not have it
+    fn fetch(&self, sha1: &str) -> Result<FetchedKey, KeyServerError>;
+}
+
+impl KeyProvider for KeyServerClient {
+    fn describe(&self) -> String {
+        format!("serveur {}", self.base_url())
+    }
+
+    fn fetch(&self, sha1: &str) -> Result<FetchedKey, KeyServerError> {
+        Ok(FetchedKey {
+            public_key: self.fetch_public_key(sha1)?,
+            origin: KeySource::Downloaded,
+        })
+    }
+}
+
+/// key folder mirroring the layout of the key server
+#[derive(Debug, Clone)]
+pub struct FolderKeySource {
+    root: PathBuf,
+    publisher: PublisherTrust,
+}
+
+impl FolderKeySource {
+    pub fn new<P: AsRef<Path>>(root: P) -> FolderKeySource {
+        FolderKeySource {
+            root: root.as_ref().to_path_buf(),
+            publisher: PublisherTrust::default(),
+        }
+    }
+
+    /// key checking the signatures of the keys, the embedded root key
+    /// or the one of [`crate::key_signature::PUBLISHER_KEY_ENV`] by default
+    pub fn with_publisher_key(mut self, publisher: PublisherKey) -> FolderKeySource {
+        self.publisher = PublisherTrust::Required(publisher);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn key_path(&self, sha1: &str) -> PathBuf {
+        self.root.join(sha1).join("public.key.pem")
+    }
+
+    pub fn signature_path(&self, sha1: &str) -> PathBuf {
+        self.root.join(sha1).join("public.key.sig")
+    }
+}
+
+/// content of a file, `None` when it does not exist
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>, KeyServerError> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(KeyServerError::Read(format!("{} : {}", path.display(), e))),
+    }
+}
+
+impl KeyProvider for FolderKeySource {
+    fn describe(&self) -> String {
+        format!("dossier {}", self.root.display())
+    }
+
+    fn fetch(&self, sha1: &str) -> Result<FetchedKey, KeyServerError> {
+        check_sha1(sha1)?;
+        let public_key = read_optional(&self.key_path(sha1))?
+            .ok_or_else(|| KeyServerError::NotFound(sha1.into()))?;
+        let signature = read_optional(&self.signature_path(sha1))?;
+        check_published_key(&self.publisher, sha1, &public_key, signature.as_deref())?;
+        info!("public key {} read from {}", sha1, self.root.display());
+        Ok(FetchedKey {
+            public_key,
+            origin: KeySource::Folder,
+        })
+    }
+}
+
+/// sources tried in turn, the first one having the key gives it
+#[derive(Default)]
+pub struct KeySources {
+    sources: Vec<Box<dyn KeyProvider>>,
+}
+
+impl KeySources {
+    pub fn new(sources: Vec<Box<dyn KeyProvider>>) -> KeySources {
+        KeySources { sources }
+    }
+
+    /// parse a list of sources separated by `;`, urls (http or https)
+    /// are key servers, other entries are key folders
+    pub fn parse(list: &str) -> KeySources {
+        let sources = list
+            .split(';')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|s| -> Box<dyn KeyProvider> {
+                if s.starts_with("http://") || s.starts_with("https://") {
+                    Box::new(KeyServerClient::new(s))
+                } else {
+                    Box::new(FolderKeySource::new(s))
+                }
+            })
+            .collect();
+        KeySources::new(sources)
+    }
+
+    /// sources given by [`KEY_SOURCES_ENV`], the key server otherwise
+    pub fn from_env() -> KeySources {
+        match std::env::var(KEY_SOURCES_ENV) {
+            Ok(list) if !list.trim().is_empty() => KeySources::parse(&list),
+            _ => KeySources::new(vec![Box::new(KeyServerClient::from_env())]),
+        }
+    }
+
+    pub fn push(&mut self, source: Box<dyn KeyProvider>) {
+        self.sources.push(source);
+    }
+
+    /// add a source tried before the others
+    pub fn push_first(&mut self, source: Box<dyn KeyProvider>) {
+        self.sources.insert(0, source);
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+}
+
+impl KeyProvider for KeySources {
+    fn describe(&self) -> String {
+        self.sources
+            .iter()
+            .map(|s| s.describe())
+            .collect::<Vec<String>>()
+            .join(", puis ")
+    }
+
+    /// the key of the first source having it, when no source gives the key
+    /// the first error other than a missing key is returned
+    fn fetch(&self, sha1: &str) -> Result<FetchedKey, KeyServerError> {
+        let mut first_error = None;
+        for source in self.sources.iter() {
+            match source.fetch(sha1) {
+                Ok(key) => return Ok(key),
+                Err(KeyServerError::NotFound(_)) => {}
+                Err(e) => {
+                    warn!("{} : {}", source.describe(), e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap_or_else(|| KeyServerError::NotFound(sha1.into())))
+    }
+}
@@ -0,0 +1,222 @@
+//! signatures of the published instrument keys
+//!
+//! the publisher signs with its root key a statement binding the sha1 of an instrument
+//! to the fingerprint of its public key. The signature is published next to the key,
+//! base64 encoded in `<sha1>/public.key.sig`, and checked before a downloaded key is recorded,
+//! against the root key embedded in the binary or the one given by [`PUBLISHER_KEY_ENV`].
+//! The revocation list is signed the same way, in `revocations.json.sig`
+
+use openssl::base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::{Signer, Verifier};
+
+use std::path::Path;
+
+use crate::encrypt::sha256_hex;
+
+/// root key of the publisher, embedded in the binary
+const PUBLISHER_ROOT_KEY: &[u8] = include_bytes!("../publisher_root.pub.pem");
+
+/// environment variable with the pem file of a publisher key replacing the embedded root key
+pub const PUBLISHER_KEY_ENV: &str = "ENCRYPTER_PUBLISHER_KEY";
+
+const STATEMENT_CONTEXT: &str = "encrypter-key-v1";
+const REVOCATIONS_CONTEXT: &str = "encrypter-revocations-v1";
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeySignatureError {
+    #[error("la signature de la clé {0} est invalide, la clé a pu être substituée")]
+    Invalid(String),
+    #[error("la signature de la clé {0} est illisible")]
+    Malformed(String),
+    #[error("la signature de la liste de révocation est invalide, la liste a pu être modifiée")]
+    InvalidRevocations,
+    #[error("la clé de l'éditeur {0} est illisible, aucune clé ne peut être vérifiée : {1}")]
+    PublisherKey(String, String),
+    #[error("erreur de cryptographie : {0}")]
+    Crypto(#[from] openssl::error::ErrorStack),
+}
+
+/// fingerprint of a pem public key, the sha256 of its der encoding
+pub fn key_fingerprint(public_key: &[u8]) -> Result<String, KeySignatureError> {
+    let rsa = Rsa::public_key_from_pem_pkcs1(public_key)?;
+    Ok(sha256_hex(&rsa.public_key_to_der_pkcs1()?))
+}
+
+/// whether two pem public keys are the same key, comparing their fingerprints,
+/// or their content when one can't be read
+pub fn same_public_key(a: &[u8], b: &[u8]) -> bool {
+    match (key_fingerprint(a), key_fingerprint(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// signed statement, binding the sha1 of the instrument to the fingerprint of its key
+fn statement(sha1: &str, public_key: &[u8]) -> Result<Vec<u8>, KeySignatureError> {
+    Ok(format!(
+        "{}\n{}\n{}\n",
+        STATEMENT_CONTEXT,
+        sha1.to_lowercase(),
+        key_fingerprint(public_key)?
+    )
+    .into_bytes())
+}
+
+/// signed statement of a revocation list, binding its content
+fn revocations_statement(content: &[u8]) -> Vec<u8> {
+    format!("{}\n{}\n", REVOCATIONS_CONTEXT, sha256_hex(content)).into_bytes()
+}
+
+fn sign_statement(
+    publisher: &PKey<Private>,
+    statement: &[u8],
+) -> Result<String, KeySignatureError> {
+    let mut signer = Signer::new(MessageDigest::sha256(), publisher)?;
+    signer.update(statement)?;
+    Ok(base64::encode_block(&signer.sign_to_vec()?))
+}
+
+/// sign the public key of an instrument, the signature is base64 encoded
+pub fn sign_key(
+    publisher: &PKey<Private>,
+    sha1: &str,
+    public_key: &[u8],
+) -> Result<String, KeySignatureError> {
+    sign_statement(publisher, &statement(sha1, public_key)?)
+}
+
+/// sign a revocation list, the signature is base64 encoded
+pub fn sign_revocation_list(
+    publisher: &PKey<Private>,
+    content: &[u8],
+) -> Result<String, KeySignatureError> {
+    sign_statement(publisher, &revocations_statement(content))
+}
+
+/// base64 signature, `None` when it can't be read
+fn decode_signature(signature: &[u8]) -> Option<Vec<u8>> {
+    std::str::from_utf8(signature)
+        .ok()
+        .and_then(|s| base64::decode_block(s.trim()).ok())
+}
+
+/// public key of a publisher, checking the signatures of the published keys
+#[derive(Debug, Clone)]
+pub struct PublisherKey {
+    key: PKey<Public>,
+}
+
+impl PublisherKey {
+    /// root key of the publisher, embedded in the binary
+    pub fn root() -> PublisherKey {
+        PublisherKey::from_pem(PUBLISHER_ROOT_KEY).expect("invalid embedded publisher key")
+    }
+
+    /// public key in the pem `PUBLIC KEY` format
+    pub fn from_pem(pem: &[u8]) -> Result<PublisherKey, KeySignatureError> {
+        Ok(PublisherKey {
+            key: PKey::public_key_from_pem(pem)?,
+        })
+    }
+
+    /// check the signature of the public key published for the sha1
+    pub fn verify(
+        &self,
+        sha1: &str,
+        public_key: &[u8],
+        signature: &[u8],
+    ) -> Result<(), KeySignatureError> {
+        let signature =
+            decode_signature(signature).ok_or_else(|| KeySignatureError::Malformed(sha1.into()))?;
+        match self.check(&statement(sha1, public_key)?, &signature)? {
+            true => Ok(()),
+            false => Err(KeySignatureError::Invalid(sha1.into())),
+        }
+    }
+
+    /// check the signature of a revocation list
+    pub fn verify_revocation_list(
+        &self,
+        content: &[u8],
+        signature: &[u8],
+    ) -> Result<(), KeySignatureError> {
+        let signature = decode_signature(signature).ok_or(KeySignatureError::InvalidRevocations)?;
+        match self.check(&revocations_statement(content), &signature)? {
+            true => Ok(()),
+            false => Err(KeySignatureError::InvalidRevocations),
+        }
+    }
+
+    /// whether the signature is the one of the statement
+    fn check(&self, statement: &[u8], signature: &[u8]) -> Result<bool, KeySignatureError> {
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &self.key)?;
+        verifier.update(statement)?;
+        Ok(verifier.verify(signature).unwrap_or(false))
+    }
+}
+
+/// publisher key checking the signatures of the published keys
+#[derive(Debug, Clone)]
+pub enum PublisherTrust {
+    /// the published keys must be signed by the publisher
+    Required(PublisherKey),
+    /// the configured publisher key can't be read, every key is refused
+    Invalid(String, String),
+}
+
+impl Default for PublisherTrust {
+    /// the embedded root key, replaced by the pem file given by [`PUBLISHER_KEY_ENV`]
+    fn default() -> Self {
+        match std::env::var_os(PUBLISHER_KEY_ENV).filter(|v| !v.is_empty()) {
+            Some(path) => PublisherTrust::from_file(Path::new(&path)),
+            None => PublisherTrust::Required(PublisherKey::root()),
+        }
+    }
+}
+
+impl PublisherTrust {
+    /// publisher key read from a pem file
+    pub fn from_file(path: &Path) -> PublisherTrust {
+        let key = std::fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|pem| PublisherKey::from_pem(&pem).map_err(|e| e.to_string()));
+        match key {
+            Ok(key) => PublisherTrust::Required(key),
+            Err(e) => PublisherTrust::Invalid(path.display().to_string(), e),
+        }
+    }
+
+    /// check a signature of the publisher, an unreadable publisher key refuses every signature
+    pub fn verify(
+        &self,
+        sha1: &str,
+        public_key: &[u8],
+        signature: &[u8],
+    ) -> Result<(), KeySignatureError> {
+        match self {
+            PublisherTrust::Required(publisher) => publisher.verify(sha1, public_key, signature),
+            PublisherTrust::Invalid(path, e) => {
+                Err(KeySignatureError::PublisherKey(path.clone(), e.clone()))
+            }
+        }
+    }
+
+    /// check the signature of a revocation list
+    pub fn verify_revocation_list(
+        &self,
+        content: &[u8],
+        signature: &[u8],
+    ) -> Result<(), KeySignatureError> {
+        match self {
+            PublisherTrust::Required(publisher) => {
+                publisher.verify_revocation_list(content, signature)
+            }
+            PublisherTrust::Invalid(path, e) => {
+                Err(KeySignatureError::PublisherKey(path.clone(), e.clone()))
+            }
+        }
+    }
+}
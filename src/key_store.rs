@@ -0,0 +1,495 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::encrypt::check_public_key;
+use crate::integrity::IntegrityStatus;
+use crate::keys_management::{
+    check_sha1, insertion_kind, unix_time, AuditEntry, AuditFilter, Key, KeyHistoryEntry,
+    KeyInsertion, KeyManagementError, KeyTrust, Revocation,
+};
+
+/// storage of the keys, their groups and the audit log,
+/// implemented by the sqlite [`crate::keys_management::Database`]
+/// and by the transient [`MemoryKeyStore`]
+pub trait KeyStore {
+    /// file backing the store, none for a transient store
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// apply all the changes or none of them, a store without transactions
+    /// applies them one after the other
+    fn atomically(
+        &self,
+        changes: &mut dyn FnMut() -> Result<(), KeyManagementError>,
+    ) -> Result<(), KeyManagementError> {
+        changes()
+    }
+
+    /// check the record of a key has not been changed outside of the application,
+    /// see [`crate::integrity::IntegrityKeyStore`]
+    fn verify_integrity(&self, _k: &Key) -> IntegrityStatus {
+        IntegrityStatus::Unchecked
+    }
+
+    /// insert a key, replacing the one with the same sha1,
+    /// the first recording time and last usage of a replaced key are kept.
+    /// A different public key for a known sha1 is not stored,
+    /// it is recorded in the key history until the operator accepts it
+    fn insert(&self, k: &Key) -> Result<KeyInsertion, KeyManagementError>;
+
+    fn get_all(&self) -> Result<Vec<Key>, KeyManagementError>;
+
+    /// get the key with the given sha1
+    fn get_by_sha1(&self, sha1: &str) -> Result<Key, KeyManagementError>;
+
+    /// keys whose sha1 starts with the given prefix, ordered by sha1
+    fn find_by_prefix(&self, prefix: &str) -> Result<Vec<Key>, KeyManagementError>;
+
+    /// keys whose name, sha1, owner, instrument or notes contain the text,
+    /// ignoring the case, ordered by name
+    fn search(&self, text: &str) -> Result<Vec<Key>, KeyManagementError>;
+
+    /// change the name of a key
+    fn rename(&self, sha1: &str, name: &str) -> Result<(), KeyManagementError>;
+
+    /// replace the public key of an existing key, the pem content is checked,
+    /// the new public key is recorded as accepted in the key history
+    fn update_public_key(&self, sha1: &str, public_key: &[u8]) -> Result<(), KeyManagementError>;
+
+    /// update the owner, instrument and notes of a key
+    fn update_metadata(&self, k: &Key) -> Result<(), KeyManagementError>;
+
+    /// set or clear the expiry date of a key
+    fn set_expiry(&self, sha1: &str, expires_at: Option<i64>) -> Result<(), KeyManagementError>;
+
+    /// revoke a key, inserting the key again does not clear the revocation
+    fn revoke(&self, revocation: &Revocation) -> Result<(), KeyManagementError>;
+
+    /// clear the revocation of a key, revoked by mistake
+    fn reinstate(&self, sha1: &str) -> Result<(), KeyManagementError>;
+
+    /// record the key has just been used to encrypt files
+    fn mark_used(&self, sha1: &str) -> Result<(), KeyManagementError>;
+
+    /// remove a key, and its group memberships
+    fn delete(&self, sha1: &str) -> Result<(), KeyManagementError>;
+
+    /// create a group of keys, nothing is done if the group already exists
+    fn create_group(&self, name: &str) -> Result<(), KeyManagementError>;
+
+    /// remove a group, its keys are kept
+    fn delete_group(&self, name: &str) -> Result<(), KeyManagementError>;
+
+    /// names of all the groups, in alphabetical order
+    fn get_groups(&self) -> Result<Vec<String>, KeyManagementError>;
+
+    fn add_to_group(&self, group: &str, sha1: &str) -> Result<(), KeyManagementError>;
+
+    fn remove_from_group(&self, group: &str, sha1: &str) -> Result<(), KeyManagementError>;
+
+    /// keys of a group, ordered by name
+    fn get_group_members(&self, group: &str) -> Result<Vec<Key>, KeyManagementError>;
+
+    /// groups the key belongs to, in alphabetical order
+    fn get_key_groups(&self, sha1: &str) -> Result<Vec<String>, KeyManagementError>;
+
+    /// record an encryption in the audit log, returns the id of the entry
+    fn record_encryption(&self, entry: &AuditEntry) -> Result<i64, KeyManagementError>;
+
+    /// audit log entries matching the filter, the most recent first
+    fn get_audit_log(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, KeyManagementError>;
+
+    /// public keys seen for a sha1, the most recent first
+    fn get_key_history(&self, sha1: &str) -> Result<Vec<KeyHistoryEntry>, KeyManagementError>;
+
+    /// public key changes waiting for the operator, the most recent first
+    fn get_pending_key_changes(&self) -> Result<Vec<KeyHistoryEntry>, KeyManagementError>;
+
+    /// make a public key of the history the stored one,
+    /// accepts a pending change or rolls back to a previous key
+    fn accept_public_key(&self, sha1: &str, id: i64) -> Result<(), KeyManagementError>;
+
+    /// refuse a pending public key change, the stored key is kept
+    fn reject_public_key(&self, sha1: &str, id: i64) -> Result<(), KeyManagementError>;
+}
+
+#[derive(Default)]
+struct MemoryContent {
+    keys: BTreeMap<String, Key>,
+    groups: BTreeMap<String, BTreeSet<String>>,
+    audit_log: Vec<AuditEntry>,
+    key_history: Vec<KeyHistoryEntry>,
+    next_rowid: i32,
+}
+
+impl MemoryContent {
+    /// same rules as the sqlite key history
+    fn record_public_key(&mut self, sha1: &str, public_key: &[u8], trust: KeyTrust) {
+        let now = unix_time();
+        let mut same_key = self
+            .key_history
+            .iter_mut()
+            .filter(|e| e.sha1 == sha1 && e.public_key == public_key)
+            .peekable();
+        if trust == KeyTrust::Accepted {
+            if same_key.peek().is_some() {
+                for e in same_key {
+                    e.trust = trust;
+                    e.decided_at = Some(now);
+                }
+                return;
+            }
+        } else if same_key.any(|e| e.trust != KeyTrust::Accepted) {
+            return;
+        }
+
+        let id = self.key_history.len() as i64 + 1;
+        self.key_history.push(KeyHistoryEntry {
+            id,
+            sha1: sha1.into(),
+            public_key: public_key.to_vec(),
+            seen_at: now,
+            trust,
+            decided_at: (trust == KeyTrust::Accepted).then_some(now),
+        });
+    }
+
+    /// history entries, the most recent first
+    fn history<F>(&self, predicate: F) -> Vec<KeyHistoryEntry>
+    where
+        F: Fn(&KeyHistoryEntry) -> bool,
+    {
+        let mut entries: Vec<KeyHistoryEntry> = self
+            .key_history
+            .iter()
+            .filter(|e| predicate(e))
+            .cloned()
+            .collect();
+        entries.sort_by_key(|e| std::cmp::Reverse((e.seen_at, e.id)));
+        entries
+    }
+}
+
+/// key store living only in memory, for tests and transient sessions
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    content: Mutex<MemoryContent>,
+}
+
+impl MemoryKeyStore {
+    pub fn new() -> MemoryKeyStore {
+        MemoryKeyStore::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryContent> {
+        // the content stays consistent even if a panic occurred while locked
+        self.content
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn update_key<F>(&self, sha1: &str, f: F) -> Result<(), KeyManagementError>
+    where
+        F: FnOnce(&mut Key),
+    {
+        let mut content = self.lock();
+        let k = content
+            .keys
+            .get_mut(sha1)
+            .ok_or_else(|| KeyManagementError::NotFound(sha1.into()))?;
+        f(k);
+        Ok(())
+    }
+}
+
+fn contains_ignore_case(value: &str, text: &str) -> bool {
+    value.to_lowercase().contains(text)
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn insert(&self, k: &Key) -> Result<KeyInsertion, KeyManagementError> {
+        check_sha1(&k.sha1)?;
+
+        let mut content = self.lock();
+        let insertion = insertion_kind(content.keys.get(&k.sha1), k);
+        let mut new_key = k.clone();
+        let mut stored_public_key = None;
+        match content.keys.get(&k.sha1) {
+            Some(existing) => {
+                new_key.rowid = existing.rowid;
+                new_key.added_at = existing.added_at.or(k.added_at);
+                new_key.last_used = k.last_used.or(existing.last_used);
+                new_key.expires_at = k.expires_at.or(existing.expires_at);
+                if existing.revoked_at.is_some() {
+                    new_key.revoked_at = existing.revoked_at;
+                    new_key.revocation_reason = existing.revocation_reason.clone();
+                }
+                stored_public_key = existing.public_key.clone();
+                if insertion == KeyInsertion::ChangePending || k.public_key.is_none() {
+                    new_key.public_key = existing.public_key.clone();
+                }
+            }
+            None => {
+                content.next_rowid += 1;
+                new_key.rowid = content.next_rowid;
+                new_key.added_at = Some(k.added_at.unwrap_or_else(unix_time));
+            }
+        }
+        content.keys.insert(k.sha1.clone(), new_key);
+
+        if let Some(new) = &k.public_key {
+            if insertion == KeyInsertion::ChangePending {
+                content.record_public_key(&k.sha1, new, KeyTrust::Pending);
+            } else if stored_public_key.as_ref() != Some(new) {
+                content.record_public_key(&k.sha1, new, KeyTrust::Accepted);
+            }
+        }
+        Ok(insertion)
+    }
+
+    fn get_all(&self) -> Result<Vec<Key>, KeyManagementError> {
+        let mut keys: Vec<Key> = self.lock().keys.values().cloned().collect();
+        keys.sort_by_key(|k| k.rowid);
+        Ok(keys)
+    }
+
+    fn get_by_sha1(&self, sha1: &str) -> Result<Key, KeyManagementError> {
+        self.lock()
+            .keys
+            .get(sha1)
+            .cloned()
+            .ok_or_else(|| KeyManagementError::NotFound(sha1.into()))
+    }
+
+    fn find_by_prefix(&self, prefix: &str) -> Result<Vec<Key>, KeyManagementError> {
+        Ok(self
+            .lock()
+            .keys
+            .values()
+            .filter(|k| k.sha1.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn search(&self, text: &str) -> Result<Vec<Key>, KeyManagementError> {
+        let text = text.trim().to_lowercase();
+        let mut keys: Vec<Key> = self
+            .lock()
+            .keys
+            .values()
+            .filter(|k| {
+                [
+                    &k.name,
+                    &k.sha1,
+                    &k.owner,
+                    &k.instrument_model,
+                    &k.instrument_serial,
+                    &k.notes,
+                ]
+                .iter()
+                .any(|v| contains_ignore_case(v, &text))
+            })
+            .cloned()
+            .collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(keys)
+    }
+
+    fn rename(&self, sha1: &str, name: &str) -> Result<(), KeyManagementError> {
+        self.update_key(sha1, |k| k.name = name.into())
+    }
+
+    fn update_public_key(&self, sha1: &str, public_key: &[u8]) -> Result<(), KeyManagementError> {
+        if check_public_key(public_key).is_err() {
+            return Err(KeyManagementError::InvalidPublicKey);
+        }
+        self.update_key(sha1, |k| k.public_key = Some(public_key.to_vec()))?;
+        self.lock()
+            .record_public_key(sha1, public_key, KeyTrust::Accepted);
+        Ok(())
+    }
+
+    fn update_metadata(&self, k: &Key) -> Result<(), KeyManagementError> {
+        self.update_key(&k.sha1, |stored| {
+            stored.owner = k.owner.clone();
+            stored.instrument_model = k.instrument_model.clone();
+            stored.instrument_serial = k.instrument_serial.clone();
+            stored.notes = k.notes.clone();
+        })
+    }
+
+    fn set_expiry(&self, sha1: &str, expires_at: Option<i64>) -> Result<(), KeyManagementError> {
+        self.update_key(sha1, |k| k.expires_at = expires_at)
+    }
+
+    fn revoke(&self, revocation: &Revocation) -> Result<(), KeyManagementError> {
+        self.update_key(&revocation.sha1, |k| {
+            k.revoked_at = Some(revocation.revoked_at);
+            k.revocation_reason = revocation.reason.clone();
+        })
+    }
+
+    fn reinstate(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        self.update_key(sha1, |k| {
+            k.revoked_at = None;
+            k.revocation_reason = "".into();
+        })
+    }
+
+    fn mark_used(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        self.update_key(sha1, |k| k.last_used = Some(unix_time()))
+    }
+
+    fn delete(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        let mut content = self.lock();
+        if content.keys.remove(sha1).is_none() {
+            return Err(KeyManagementError::NotFound(sha1.into()));
+        }
+        for members in content.groups.values_mut() {
+            members.remove(sha1);
+        }
+        Ok(())
+    }
+
+    fn create_group(&self, name: &str) -> Result<(), KeyManagementError> {
+        self.lock().groups.entry(name.into()).or_default();
+        Ok(())
+    }
+
+    fn delete_group(&self, name: &str) -> Result<(), KeyManagementError> {
+        self.lock()
+            .groups
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| KeyManagementError::GroupNotFound(name.into()))
+    }
+
+    fn get_groups(&self) -> Result<Vec<String>, KeyManagementError> {
+        Ok(self.lock().groups.keys().cloned().collect())
+    }
+
+    fn add_to_group(&self, group: &str, sha1: &str) -> Result<(), KeyManagementError> {
+        let mut content = self.lock();
+        if !content.groups.contains_key(group) {
+            return Err(KeyManagementError::GroupNotFound(group.into()));
+        }
+        if !content.keys.contains_key(sha1) {
+            return Err(KeyManagementError::NotFound(sha1.into()));
+        }
+        if let Some(members) = content.groups.get_mut(group) {
+            members.insert(sha1.into());
+        }
+        Ok(())
+    }
+
+    fn remove_from_group(&self, group: &str, sha1: &str) -> Result<(), KeyManagementError> {
+        let mut content = self.lock();
+        let members = content
+            .groups
+            .get_mut(group)
+            .ok_or_else(|| KeyManagementError::GroupNotFound(group.into()))?;
+        members.remove(sha1);
+        Ok(())
+    }
+
+    fn get_group_members(&self, group: &str) -> Result<Vec<Key>, KeyManagementError> {
+        let content = self.lock();
+        let members = content
+            .groups
+            .get(group)
+            .ok_or_else(|| KeyManagementError::GroupNotFound(group.into()))?;
+        let mut keys: Vec<Key> = members
+            .iter()
+            .filter_map(|sha1| content.keys.get(sha1))
+            .cloned()
+            .collect();
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(keys)
+    }
+
+    fn get_key_groups(&self, sha1: &str) -> Result<Vec<String>, KeyManagementError> {
+        Ok(self
+            .lock()
+            .groups
+            .iter()
+            .filter(|(_, members)| members.contains(sha1))
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    fn record_encryption(&self, entry: &AuditEntry) -> Result<i64, KeyManagementError> {
+        let mut content = self.lock();
+        let mut entry = entry.clone();
+        entry.id = content.audit_log.len() as i64 + 1;
+        let id = entry.id;
+        content.audit_log.push(entry);
+        Ok(id)
+    }
+
+    fn get_audit_log(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, KeyManagementError> {
+        let path = filter.path.as_ref().map(|p| p.trim().to_lowercase());
+        let mut entries: Vec<AuditEntry> = self
+            .lock()
+            .audit_log
+            .iter()
+            .filter(|e| filter.key_sha1.as_ref().map_or(true, |s| &e.key_sha1 == s))
+            .filter(|e| {
+                path.as_ref().map_or(true, |p| {
+                    contains_ignore_case(&e.source_path, p)
+                        || contains_ignore_case(&e.output_path, p)
+                })
+            })
+            .filter(|e| {
+                filter
+                    .source_sha256
+                    .as_ref()
+                    .map_or(true, |s| &e.source_sha256 == s)
+            })
+            .cloned()
+            .collect();
+        entries.sort_by_key(|e| std::cmp::Reverse((e.timestamp, e.id)));
+        if let Some(limit) = filter.limit {
+            entries.truncate(limit);
+        }
+        Ok(entries)
+    }
+
+    fn get_key_history(&self, sha1: &str) -> Result<Vec<KeyHistoryEntry>, KeyManagementError> {
+        Ok(self.lock().history(|e| e.sha1 == sha1))
+    }
+
+    fn get_pending_key_changes(&self) -> Result<Vec<KeyHistoryEntry>, KeyManagementError> {
+        Ok(self.lock().history(|e| e.trust == KeyTrust::Pending))
+    }
+
+    fn accept_public_key(&self, sha1: &str, id: i64) -> Result<(), KeyManagementError> {
+        let mut content = self.lock();
+        let public_key = content
+            .key_history
+            .iter()
+            .find(|e| e.id == id && e.sha1 == sha1)
+            .map(|e| e.public_key.clone())
+            .ok_or_else(|| KeyManagementError::HistoryNotFound(sha1.into(), id))?;
+        let k = content
+            .keys
+            .get_mut(sha1)
+            .ok_or_else(|| KeyManagementError::NotFound(sha1.into()))?;
+        k.public_key = Some(public_key.clone());
+        content.record_public_key(sha1, &public_key, KeyTrust::Accepted);
+        Ok(())
+    }
+
+    fn reject_public_key(&self, sha1: &str, id: i64) -> Result<(), KeyManagementError> {
+        let mut content = self.lock();
+        let entry = content
+            .key_history
+            .iter_mut()
+            .find(|e| e.id == id && e.sha1 == sha1 && e.trust == KeyTrust::Pending)
+            .ok_or_else(|| KeyManagementError::HistoryNotFound(sha1.into(), id))?;
+        entry.trust = KeyTrust::Rejected;
+        entry.decided_at = Some(unix_time());
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+//! bulk synchronization with the key server, or any [`KeyProvider`], downloading
+//! the keys of a list of sha1 with concurrent requests, and checking the stored keys
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use log::info;
+
+use crate::key_provider::{FetchedKey, KeyProvider};
+use crate::key_signature::same_public_key;
+use crate::keys_management::{check_sha1, Key, KeyInsertion, KeyManagementError, KeyStore};
+use crate::keyserver::KeyServerError;
+
+/// concurrent requests to the key sources
+pub const DEFAULT_WORKERS: usize = 8;
+
+/// sha1 read from a list, in the order of the list and without duplicates
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sha1List {
+    pub sha1: Vec<String>,
+    /// words of the list which are not a sha1
+    pub invalid: Vec<String>,
+}
+
+/// parse a list of sha1, separated by spaces, commas or new lines,
+/// `#` starts a comment up to the end of the line
+pub fn parse_sha1_list(text: &str) -> Sha1List {
+    let mut list = Sha1List::default();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        for word in line
+            .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+            .filter(|w| !w.is_empty())
+        {
+            let sha1 = word.to_lowercase();
+            if check_sha1(&sha1).is_err() {
+                list.invalid.push(word.to_string());
+            } else if !list.sha1.contains(&sha1) {
+                list.sha1.push(sha1);
+            }
+        }
+    }
+    list
+}
+
+pub fn read_sha1_list(path: &Path) -> io::Result<Sha1List> {
+    Ok(parse_sha1_list(&fs::read_to_string(path)?))
+}
+
+/// key downloaded for a sha1
+#[derive(Debug)]
+pub struct KeyFetch {
+    pub sha1: String,
+    pub result: Result<FetchedKey, KeyServerError>,
+}
+
+/// download the keys of the sha1 with `workers` concurrent requests,
+/// `progress` is called with the count of finished downloads and the total.
+/// The results are in the order of the sha1
+pub fn fetch_keys(
+    provider: &dyn KeyProvider,
+    sha1: &[String],
+    workers: usize,
+    mut progress: impl FnMut(usize, usize),
+) -> Vec<KeyFetch> {
+    let total = sha1.len();
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<KeyFetch>> = (0..total).map(|_| None).collect();
+    let (sender, receiver) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..workers.clamp(1, total.max(1)) {
+            let sender = sender.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= total {
+                    break;
+                }
+                if sender.send((i, provider.fetch(&sha1[i]))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        for (done, (i, result)) in receiver.iter().enumerate() {
+            results[i] = Some(KeyFetch {
+                sha1: sha1[i].clone(),
+                result,
+            });
+            progress(done + 1, total);
+        }
+    });
+
+    results.into_iter().flatten().collect()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BulkReport {
+    pub added: usize,
+    pub updated: usize,
+    /// stored with the same public key
+    pub unchanged: usize,
+    /// known keys downloaded with a different public key, waiting for the operator
+    pub pending: usize,
+    /// sha1 and error of the failed downloads
+    pub failed: Vec<(String, String)>,
+}
+
+/// record the downloaded keys, a new key is named after its sha1,
+/// a known key keeps its metadata and a changed public key must be accepted
+pub fn record_fetched(
+    db: &dyn KeyStore,
+    fetched: &[KeyFetch],
+) -> Result<BulkReport, KeyManagementError> {
+    let mut report = BulkReport::default();
+    for f in fetched {
+        let fetched = match &f.result {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                report.failed.push((f.sha1.clone(), e.to_string()));
+                continue;
+            }
+        };
+        let mut k = match db.get_by_sha1(&f.sha1) {
+            Ok(k)
+                if k.public_key
+                    .as_deref()
+                    .map_or(false, |p| same_public_key(p, &fetched.public_key)) =>
+            {
+                report.unchanged += 1;
+                continue;
+            }
+            Ok(k) => k,
+            Err(KeyManagementError::NotFound(_)) => Key {
+                name: f.sha1.clone(),
+                sha1: f.sha1.clone(),
+                ..Default::default()
+            },
+            Err(e) => return Err(e),
+        };
+        k.public_key = Some(fetched.public_key.clone());
+        k.source = fetched.origin;
+        match db.insert(&k)? {
+            KeyInsertion::Added => report.added += 1,
+            KeyInsertion::Updated => report.updated += 1,
+            KeyInsertion::ChangePending => report.pending += 1,
+        }
+    }
+    info!(
+        "{} keys added, {} updated, {} pending, {} failed",
+        report.added,
+        report.updated,
+        report.pending,
+        report.failed.len()
+    );
+    Ok(report)
+}
+
+/// how the key given by the source compares to the stored one
+#[derive(Debug)]
+pub enum RefreshStatus {
+    Unchanged,
+    /// the source gives another public key
+    Changed(FetchedKey),
+    Missing,
+    Failed(KeyServerError),
+}
+
+pub struct RefreshEntry {
+    pub key: Key,
+    pub status: RefreshStatus,
+}
+
+impl RefreshEntry {
+    pub fn is_unchanged(&self) -> bool {
+        matches!(self.status, RefreshStatus::Unchanged)
+    }
+}
+
+/// fetch again the given stored keys and compare them to the stored ones,
+/// nothing is recorded, see [`changed_keys`] to record the differences
+pub fn refresh_keys(
+    keys: Vec<Key>,
+    provider: &dyn KeyProvider,
+    workers: usize,
+    progress: impl FnMut(usize, usize),
+) -> Vec<RefreshEntry> {
+    let sha1: Vec<String> = keys.iter().map(|k| k.sha1.clone()).collect();
+    let fetched = fetch_keys(provider, &sha1, workers, progress);
+    keys.into_iter()
+        .zip(fetched)
+        .map(|(key, f)| {
+            let status = match f.result {
+                Ok(fetched)
+                    if key
+                        .public_key
+                        .as_deref()
+                        .map_or(false, |p| same_public_key(p, &fetched.public_key)) =>
+                {
+                    RefreshStatus::Unchanged
+                }
+                Ok(fetched) => RefreshStatus::Changed(fetched),
+                Err(KeyServerError::NotFound(_)) => RefreshStatus::Missing,
+                Err(e) => RefreshStatus::Failed(e),
+            };
+            RefreshEntry { key, status }
+        })
+        .collect()
+}
+
+/// the changed keys of a refresh, to be recorded with [`record_fetched`]
+pub fn changed_keys(entries: &[RefreshEntry]) -> Vec<KeyFetch> {
+    entries
+        .iter()
+        .filter_map(|e| match &e.status {
+            RefreshStatus::Changed(fetched) => Some(KeyFetch {
+                sha1: e.key.sha1.clone(),
+                result: Ok(fetched.clone()),
+            }),
+            _ => None,
+        })
+        .collect()
+}
@@ -1,45 +1,475 @@
-use core::fmt;
 use egui::mutex::RwLock;
 use rusqlite::*;
 use rusqlite::{Connection, Result};
 use std::error::Error;
 use std::fmt::{Debug, Display};
+use std::path::{Path, PathBuf};
 
 /// this module manage keys,
 ///
 ///
 use std::sync::Arc;
 
+use crate::encrypt::check_public_key;
+use crate::key_signature::same_public_key;
+pub use crate::key_store::{KeyStore, MemoryKeyStore};
+
 #[allow(unused_imports)]
 use log::{debug, error, info, log_enabled, Level};
 
+/// environment variable overriding the key database location
+pub const DATABASE_PATH_ENV: &str = "ENCRYPTER_KEYS_DB";
+
+const DATABASE_FILE_NAME: &str = "keys.db";
+const APPLICATION_FOLDER: &str = "encrypter";
+
+/// schema migrations, applied in order on open,
+/// the migration at index `i` brings the schema to version `i + 1`.
+/// Never modify a released migration, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1 - initial schema, databases created before versioning already have it
+    "CREATE TABLE IF NOT EXISTS all_keys (
+        name TEXT NOT NULL,
+        sha1 TEXT NOT NULL PRIMARY KEY,
+        public_key BLOB
+    );",
+    // 2 - key groups, a key may belong to several groups
+    "CREATE TABLE key_groups (
+        name TEXT NOT NULL PRIMARY KEY
+    );
+    CREATE TABLE key_group_members (
+        group_name TEXT NOT NULL,
+        sha1 TEXT NOT NULL,
+        PRIMARY KEY (group_name, sha1)
+    );
+    CREATE INDEX key_group_members_sha1 ON key_group_members (sha1);",
+    // 3 - key metadata, owner and instrument, origin of the key and usage
+    "ALTER TABLE all_keys ADD COLUMN owner TEXT NOT NULL DEFAULT '';
+    ALTER TABLE all_keys ADD COLUMN instrument_model TEXT NOT NULL DEFAULT '';
+    ALTER TABLE all_keys ADD COLUMN instrument_serial TEXT NOT NULL DEFAULT '';
+    ALTER TABLE all_keys ADD COLUMN notes TEXT NOT NULL DEFAULT '';
+    ALTER TABLE all_keys ADD COLUMN source TEXT NOT NULL DEFAULT 'manual';
+    ALTER TABLE all_keys ADD COLUMN added_at INTEGER;
+    ALTER TABLE all_keys ADD COLUMN last_used INTEGER;",
+    // 4 - audit log of the encrypted files
+    "CREATE TABLE audit_log (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        source_path TEXT NOT NULL,
+        source_sha256 TEXT NOT NULL,
+        output_path TEXT NOT NULL,
+        output_size INTEGER NOT NULL,
+        key_sha1 TEXT NOT NULL
+    );
+    CREATE INDEX audit_log_key_sha1 ON audit_log (key_sha1);
+    CREATE INDEX audit_log_source_path ON audit_log (source_path);",
+    // 5 - integrity tag of the key records
+    "ALTER TABLE all_keys ADD COLUMN integrity BLOB;",
+    // 6 - every public key seen for a sha1, the stored ones are trusted
+    "CREATE TABLE key_history (
+        id INTEGER PRIMARY KEY,
+        sha1 TEXT NOT NULL,
+        public_key BLOB NOT NULL,
+        seen_at INTEGER NOT NULL,
+        trust TEXT NOT NULL,
+        decided_at INTEGER
+    );
+    CREATE INDEX key_history_sha1 ON key_history (sha1);
+    INSERT INTO key_history (sha1, public_key, seen_at, trust, decided_at)
+        SELECT sha1, public_key, COALESCE(added_at, 0), 'accepted', added_at
+        FROM all_keys WHERE public_key IS NOT NULL;",
+    // 7 - expiry and revocation of the keys
+    "ALTER TABLE all_keys ADD COLUMN expires_at INTEGER;
+    ALTER TABLE all_keys ADD COLUMN revoked_at INTEGER;
+    ALTER TABLE all_keys ADD COLUMN revocation_reason TEXT NOT NULL DEFAULT '';",
+    // 8 - settings of the database, the integrity protection is set up on the next protected
+    // open, see [`crate::integrity::open_protected`]
+    "CREATE TABLE metadata (
+        name TEXT NOT NULL PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+    INSERT INTO metadata (name, value) VALUES ('integrity_pending', x'01');",
+];
+
+/// schema version of the databases written by this version of the application
+pub const SCHEMA_VERSION: i32 = MIGRATIONS.len() as i32;
+
+/// columns read by [`history_entry_from_row`]
+const HISTORY_COLUMNS: &str = "id, sha1, public_key, seen_at, trust, decided_at";
+
+/// columns read by [`key_from_row`]
+const KEY_COLUMNS: &str = "rowid, name, sha1, public_key, owner, instrument_model, \
+    instrument_serial, notes, source, added_at, last_used, integrity, expires_at, revoked_at, \
+    revocation_reason";
+
 pub struct Database {
     db: Arc<RwLock<Connection>>,
+    path: Option<PathBuf>,
+}
+
+/// per user data directory, following the platform conventions
+fn user_data_dir() -> Option<PathBuf> {
+    let env_path = |name: &str| {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+
+    if cfg!(windows) {
+        env_path("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_path("HOME").map(|h| h.join("Library").join("Application Support"))
+    } else {
+        env_path("XDG_DATA_HOME")
+            .or_else(|| env_path("HOME").map(|h| h.join(".local").join("share")))
+    }
+}
+
+/// default location of the key database, in the user data directory,
+/// or the current directory if there is none. The `keys.db` of the current
+/// directory used by the previous versions is kept while there is no database
+/// in the user data directory
+pub fn default_database_path() -> PathBuf {
+    match user_data_dir() {
+        Some(d) => with_legacy_database(
+            d.join(APPLICATION_FOLDER).join(DATABASE_FILE_NAME),
+            Path::new(DATABASE_FILE_NAME),
+        ),
+        None => PathBuf::from(DATABASE_FILE_NAME),
+    }
+}
+
+/// the legacy database when it exists and the default one does not
+pub fn with_legacy_database(default: PathBuf, legacy: &Path) -> PathBuf {
+    match !default.exists() && legacy.is_file() {
+        true => legacy.to_path_buf(),
+        false => default,
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct KeyManagementError {
-    message: String,
+/// resolve the key database location, the environment variable
+/// takes precedence over the configured path, then the default location
+pub fn resolve_database_path(configured: Option<&Path>) -> PathBuf {
+    if let Some(p) = std::env::var_os(DATABASE_PATH_ENV).filter(|v| !v.is_empty()) {
+        let path = PathBuf::from(p);
+        info!(
+            "key database {}, from {}",
+            path.display(),
+            DATABASE_PATH_ENV
+        );
+        return path;
+    }
+    let path = match configured {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => default_database_path(),
+    };
+    info!("key database {}", path.display());
+    path
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyManagementError {
+    #[error("KeyError: la clé {0} n'existe pas")]
+    NotFound(String),
+    #[error("KeyError: le groupe {0} n'existe pas")]
+    GroupNotFound(String),
+    #[error("KeyError: l'identifiant de la clef doit avoir 40 caractères : {0}")]
+    InvalidSha1(String),
+    #[error("KeyError: la clé publique est invalide")]
+    InvalidPublicKey,
+    #[error("KeyError: date invalide, le format attendu est AAAA-MM-JJ : {0}")]
+    InvalidDate(String),
+    #[error("KeyError: {0}")]
+    Schema(String),
+    #[error("KeyError: la clé publique {1} de {0} n'existe pas dans l'historique")]
+    HistoryNotFound(String, i64),
+    #[error("KeyError: la clé {0} a été modifiée en dehors de l'application")]
+    Tampered(String),
+    #[error("KeyError: erreur de la clé d'intégrité : {0}")]
+    IntegrityKey(String),
+    #[error("KeyError: erreur de la base de clés : {0}")]
+    Database(#[from] rusqlite::Error),
+}
+
+/// where a key comes from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeySource {
+    /// fetched from the key server
+    Downloaded,
+    /// typed or pasted by the operator
+    #[default]
+    Manual,
+    /// read from a key book
+    Imported,
+    /// read from a key folder, usb stick or mounted share
+    Folder,
 }
 
-impl fmt::Display for KeyManagementError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "KeyError: {}", &self.message)
+impl KeySource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeySource::Downloaded => "downloaded",
+            KeySource::Manual => "manual",
+            KeySource::Imported => "imported",
+            KeySource::Folder => "folder",
+        }
+    }
+
+    /// parse the stored value, unknown values are considered as manual
+    pub fn parse(value: &str) -> KeySource {
+        match value {
+            "downloaded" => KeySource::Downloaded,
+            "imported" => KeySource::Imported,
+            "folder" => KeySource::Folder,
+            _ => KeySource::Manual,
+        }
     }
 }
 
-impl Error for KeyManagementError {
-    fn description(&self) -> &str {
-        self.message.as_str()
+impl ToSql for KeySource {
+    fn to_sql(&self) -> Result<types::ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
     }
 }
 
-#[derive(PartialEq, Eq, Clone)]
+impl types::FromSql for KeySource {
+    fn column_result(value: types::ValueRef<'_>) -> types::FromSqlResult<Self> {
+        value.as_str().map(KeySource::parse)
+    }
+}
+
+/// decision of the operator on a public key seen for a sha1
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyTrust {
+    /// a different public key was received for a known sha1, waiting for the operator
+    #[default]
+    Pending,
+    /// the public key is or has been the stored one
+    Accepted,
+    Rejected,
+}
+
+impl KeyTrust {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyTrust::Pending => "pending",
+            KeyTrust::Accepted => "accepted",
+            KeyTrust::Rejected => "rejected",
+        }
+    }
+
+    /// parse the stored value, unknown values are considered as pending
+    pub fn parse(value: &str) -> KeyTrust {
+        match value {
+            "accepted" => KeyTrust::Accepted,
+            "rejected" => KeyTrust::Rejected,
+            _ => KeyTrust::Pending,
+        }
+    }
+}
+
+impl ToSql for KeyTrust {
+    fn to_sql(&self) -> Result<types::ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl types::FromSql for KeyTrust {
+    fn column_result(value: types::ValueRef<'_>) -> types::FromSqlResult<Self> {
+        value.as_str().map(KeyTrust::parse)
+    }
+}
+
+/// a public key seen for a sha1, kept for audit and rollback
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyHistoryEntry {
+    pub id: i64,
+    pub sha1: String,
+    pub public_key: Vec<u8>,
+    /// unix time the public key was first received
+    pub seen_at: i64,
+    pub trust: KeyTrust,
+    /// unix time of the last decision of the operator
+    pub decided_at: Option<i64>,
+}
+
+/// what [`KeyStore::insert`] did with the key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyInsertion {
+    Added,
+    Updated,
+    /// the key is known with another public key, the stored one is kept
+    /// and the new one waits for the operator in the key history
+    ChangePending,
+}
+
+/// how an inserted public key compares to the stored one
+pub(crate) fn insertion_kind(stored: Option<&Key>, k: &Key) -> KeyInsertion {
+    match (stored, &k.public_key) {
+        (None, _) => KeyInsertion::Added,
+        (Some(s), Some(new))
+            if s.public_key
+                .as_deref()
+                .map_or(false, |old| !same_public_key(old, new)) =>
+        {
+            KeyInsertion::ChangePending
+        }
+        _ => KeyInsertion::Updated,
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Default)]
 pub struct Key {
     pub rowid: i32,
     pub name: String,
     pub sha1: String,
     pub public_key: Option<Vec<u8>>,
+    /// contact details of the owner
+    pub owner: String,
+    pub instrument_model: String,
+    pub instrument_serial: String,
+    pub notes: String,
+    pub source: KeySource,
+    /// unix time the key was first recorded
+    pub added_at: Option<i64>,
+    /// unix time the key was last used to encrypt files
+    pub last_used: Option<i64>,
+    /// integrity tag of the record, see [`crate::integrity`]
+    pub integrity: Option<Vec<u8>>,
+    /// unix time after which the key must not be used anymore
+    pub expires_at: Option<i64>,
+    /// unix time the key was revoked, the instrument was sold, stolen or re-keyed
+    pub revoked_at: Option<i64>,
+    pub revocation_reason: String,
+}
+
+/// whether a key may be used to encrypt files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyValidity {
+    Valid,
+    Expired,
+    Revoked,
+}
+
+impl Key {
+    /// validity of the key at the given unix time, a revocation prevails over the expiry
+    pub fn validity(&self, now: i64) -> KeyValidity {
+        if self.revoked_at.map_or(false, |t| t <= now) {
+            KeyValidity::Revoked
+        } else if self.expires_at.map_or(false, |t| t <= now) {
+            KeyValidity::Expired
+        } else {
+            KeyValidity::Valid
+        }
+    }
+
+    /// reason why the key may not be used, none for a valid key
+    pub fn validity_message(&self, now: i64) -> Option<String> {
+        match self.validity(now) {
+            KeyValidity::Valid => None,
+            KeyValidity::Expired => Some(format!(
+                "expirée le {}",
+                format_timestamp(self.expires_at.unwrap_or_default())
+            )),
+            KeyValidity::Revoked if self.revocation_reason.is_empty() => Some(format!(
+                "révoquée le {}",
+                format_timestamp(self.revoked_at.unwrap_or_default())
+            )),
+            KeyValidity::Revoked => Some(format!(
+                "révoquée le {} : {}",
+                format_timestamp(self.revoked_at.unwrap_or_default()),
+                self.revocation_reason
+            )),
+        }
+    }
+}
+
+/// revocation of a key, as listed in a revocation list
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Revocation {
+    pub sha1: String,
+    /// unix time of the revocation
+    pub revoked_at: i64,
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// an encryption, as recorded in the audit log
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// unix time of the encryption
+    pub timestamp: i64,
+    pub source_path: String,
+    /// sha256 of the plain file, in hexadecimal
+    pub source_sha256: String,
+    pub output_path: String,
+    pub output_size: u64,
+    pub key_sha1: String,
+}
+
+/// selection of the audit log entries, empty criteria select everything
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub key_sha1: Option<String>,
+    /// part of the source or output path
+    pub path: Option<String>,
+    pub source_sha256: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// current unix time, in seconds
+pub fn unix_time() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// unix time of the start of a `YYYY-MM-DD` day (UTC)
+pub fn parse_date(text: &str) -> Option<i64> {
+    let mut parts = text.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // number of days since 1970-01-01 from the civil date
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    Some((era * 146097 + doe - 719468) * 86400)
+}
+
+/// display a unix time as `YYYY-MM-DD HH:MM` (UTC)
+pub fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let seconds = timestamp.rem_euclid(86400);
+
+    // civil date from the number of days since 1970-01-01
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        (seconds % 3600) / 60
+    )
 }
 
 pub fn text_representation(k: &Key) -> String {
@@ -64,62 +494,680 @@ impl Debug for Key {
     }
 }
 
+/// bring the database schema up to [`SCHEMA_VERSION`],
+/// each migration is applied in its own transaction
+fn migrate(conn: &mut Connection) -> Result<(), KeyManagementError> {
+    let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(KeyManagementError::Schema(format!(
+            "la base de clés (version {}) a été créée par une version plus récente de l'application (version {})",
+            version, SCHEMA_VERSION
+        )));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let target = index as i32 + 1;
+        info!("migrating key database to version {}", target);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", target)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn key_from_row(row: &Row<'_>) -> Result<Key> {
+    Ok(Key {
+        rowid: row.get(0)?,
+        name: row.get(1)?,
+        sha1: row.get(2)?,
+        public_key: row.get(3)?,
+        owner: row.get(4)?,
+        instrument_model: row.get(5)?,
+        instrument_serial: row.get(6)?,
+        notes: row.get(7)?,
+        source: row.get(8)?,
+        added_at: row.get(9)?,
+        last_used: row.get(10)?,
+        integrity: row.get(11)?,
+        expires_at: row.get(12)?,
+        revoked_at: row.get(13)?,
+        revocation_reason: row.get(14)?,
+    })
+}
+
+fn history_entry_from_row(row: &Row<'_>) -> Result<KeyHistoryEntry> {
+    Ok(KeyHistoryEntry {
+        id: row.get(0)?,
+        sha1: row.get(1)?,
+        public_key: row.get(2)?,
+        seen_at: row.get(3)?,
+        trust: row.get(4)?,
+        decided_at: row.get(5)?,
+    })
+}
+
+fn audit_entry_from_row(row: &Row<'_>) -> Result<AuditEntry> {
+    Ok(AuditEntry {
+        id: row.get(0)?,
+        timestamp: row.get(1)?,
+        source_path: row.get(2)?,
+        source_sha256: row.get(3)?,
+        output_path: row.get(4)?,
+        output_size: row.get::<_, i64>(5)? as u64,
+        key_sha1: row.get(6)?,
+    })
+}
+
+/// escape the LIKE wildcards, for a `LIKE ... ESCAPE '\'` clause
+fn like_pattern(text: &str) -> String {
+    format!(
+        "%{}%",
+        text.trim()
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    )
+}
+
+/// savepoint of the connection, rolled back when dropped without being released.
+/// Unlike transactions savepoints nest, the changes grouped by [`KeyStore::atomically`]
+/// can use them
+struct Savepoint<'c> {
+    conn: &'c Connection,
+    released: bool,
+}
+
+impl<'c> Savepoint<'c> {
+    fn new(conn: &'c Connection) -> Result<Savepoint<'c>> {
+        conn.execute_batch("SAVEPOINT changes")?;
+        Ok(Savepoint {
+            conn,
+            released: false,
+        })
+    }
+
+    fn release(mut self) -> Result<()> {
+        self.conn.execute_batch("RELEASE changes")?;
+        self.released = true;
+        Ok(())
+    }
+}
+
+impl std::ops::Deref for Savepoint<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl Drop for Savepoint<'_> {
+    fn drop(&mut self) {
+        if !self.released {
+            if let Err(e) = self
+                .conn
+                .execute_batch("ROLLBACK TO changes; RELEASE changes")
+            {
+                error!("fail to roll back the changes : {}", e);
+            }
+        }
+    }
+}
+
+/// record a public key seen for a sha1 in the key history,
+/// an accepted key marks all its entries as accepted,
+/// a pending key already waiting or rejected is not recorded twice
+fn record_public_key(
+    c: &Connection,
+    sha1: &str,
+    public_key: &[u8],
+    trust: KeyTrust,
+) -> Result<(), KeyManagementError> {
+    let now = unix_time();
+    if trust == KeyTrust::Accepted {
+        let updated = c.execute(
+            "UPDATE key_history SET trust = ?1, decided_at = ?2 WHERE sha1 = ?3 AND public_key = ?4",
+            (trust, now, sha1, public_key),
+        )?;
+        if updated > 0 {
+            return Ok(());
+        }
+    } else {
+        let known: i64 = c.query_row(
+            "SELECT count(*) FROM key_history WHERE sha1 = ?1 AND public_key = ?2 AND trust != ?3",
+            (sha1, public_key, KeyTrust::Accepted),
+            |row| row.get(0),
+        )?;
+        if known > 0 {
+            return Ok(());
+        }
+    }
+
+    c.execute(
+        "INSERT INTO key_history (sha1, public_key, seen_at, trust, decided_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        (
+            sha1,
+            public_key,
+            now,
+            trust,
+            (trust == KeyTrust::Accepted).then_some(now),
+        ),
+    )?;
+    Ok(())
+}
+
+/// a sha1 is 40 hexadecimal digits
+pub(crate) fn check_sha1(sha1: &str) -> Result<(), KeyManagementError> {
+    if sha1.len() != 40 || !sha1.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(KeyManagementError::InvalidSha1(sha1.into()));
+    }
+    Ok(())
+}
+
 impl Database {
-    pub fn open_database() -> Result<Database> {
+    /// open the database at the location given by [`resolve_database_path`]
+    pub fn open_database() -> Result<Database, Box<dyn Error>> {
+        Database::open(resolve_database_path(None))
+    }
+
+    /// open (or create) the key database stored in the given file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Database, Box<dyn Error>> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+
+        info!("opening key database {}", path.display());
         let conn = Connection::open_with_flags(
-            "keys.db",
+            path,
             OpenFlags::SQLITE_OPEN_READ_WRITE
                 | OpenFlags::SQLITE_OPEN_CREATE
                 | OpenFlags::SQLITE_OPEN_URI
                 | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         )?;
 
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS all_keys (
-                name TEXT NOT NULL,
-                sha1 TEXT NOT NULL PRIMARY KEY,
-                public_key BLOB
-            )",
-            (), // empty list of parameters.
-        )?;
+        Database::init(conn, Some(path.to_path_buf()))
+    }
+
+    /// open a transient database, living only in memory
+    pub fn open_in_memory() -> Result<Database, Box<dyn Error>> {
+        let conn = Connection::open_in_memory()?;
+        Database::init(conn, None)
+    }
+
+    /// schema version of the opened database
+    pub fn schema_version(&self) -> Result<i32> {
+        let c = self.db.read();
+        c.query_row("PRAGMA user_version", [], |row| row.get(0))
+    }
+
+    /// value of a setting of the database
+    pub(crate) fn get_metadata(&self, name: &str) -> Result<Option<Vec<u8>>, KeyManagementError> {
+        let c = self.db.read();
+        Ok(c.query_row(
+            "SELECT value FROM metadata WHERE name = ?1",
+            [name],
+            |row| row.get(0),
+        )
+        .optional()?)
+    }
+
+    /// set a setting of the database, `None` removes it
+    pub(crate) fn set_metadata(
+        &self,
+        name: &str,
+        value: Option<&[u8]>,
+    ) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        match value {
+            Some(value) => c.execute(
+                "INSERT OR REPLACE INTO metadata (name, value) VALUES (?1, ?2)",
+                (name, value),
+            )?,
+            None => c.execute("DELETE FROM metadata WHERE name = ?1", [name])?,
+        };
+        Ok(())
+    }
+
+    fn init(mut conn: Connection, path: Option<PathBuf>) -> Result<Database, Box<dyn Error>> {
+        migrate(&mut conn)?;
 
         Ok(Database {
             db: Arc::new(RwLock::new(conn)),
+            path,
         })
     }
 
-    pub fn insert(&self, k: &Key) -> Result<(), Box<dyn Error>> {
-        if k.sha1.len() != 40 {
-            let s: String = "l'identifiant de la clef doit avoir 40 caractères".into();
-            return Err(Box::new(KeyManagementError { message: s }));
+    fn check_group(&self, group: &str) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        c.query_row(
+            "SELECT name FROM key_groups WHERE name = ?1",
+            [group],
+            |_| Ok(()),
+        )
+        .optional()?
+        .ok_or_else(|| KeyManagementError::GroupNotFound(group.into()))
+    }
+}
+
+impl KeyStore for Database {
+    fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    fn atomically(
+        &self,
+        changes: &mut dyn FnMut() -> Result<(), KeyManagementError>,
+    ) -> Result<(), KeyManagementError> {
+        // the connection is not locked while the changes run, they lock it themselves
+        self.db.read().execute_batch("SAVEPOINT atomically")?;
+        let result = changes().and_then(|()| {
+            let c = self.db.read();
+            Ok(c.execute_batch("RELEASE atomically")?)
+        });
+        if result.is_err() {
+            let c = self.db.read();
+            c.execute_batch("ROLLBACK TO atomically; RELEASE atomically")?;
+        }
+        result
+    }
+
+    fn insert(&self, k: &Key) -> Result<KeyInsertion, KeyManagementError> {
+        check_sha1(&k.sha1)?;
+
+        let c = self.db.read();
+        let tx = Savepoint::new(&c)?;
+        let stored = tx
+            .query_row(
+                &format!("SELECT {} FROM all_keys WHERE sha1 = ?1", KEY_COLUMNS),
+                [&k.sha1],
+                key_from_row,
+            )
+            .optional()?;
+        let insertion = insertion_kind(stored.as_ref(), k);
+        let stored_public_key = stored.and_then(|s| s.public_key);
+        let public_key = match insertion {
+            KeyInsertion::ChangePending => stored_public_key.clone(),
+            _ => k.public_key.clone().or_else(|| stored_public_key.clone()),
+        };
+
+        tx.execute(
+            "INSERT INTO all_keys (name, sha1, public_key, owner, instrument_model,
+                instrument_serial, notes, source, added_at, last_used, integrity, expires_at,
+                revoked_at, revocation_reason)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+            ON CONFLICT (sha1) DO UPDATE SET
+                name = excluded.name,
+                public_key = excluded.public_key,
+                owner = excluded.owner,
+                instrument_model = excluded.instrument_model,
+                instrument_serial = excluded.instrument_serial,
+                notes = excluded.notes,
+                source = excluded.source,
+                added_at = COALESCE(all_keys.added_at, excluded.added_at),
+                last_used = COALESCE(excluded.last_used, all_keys.last_used),
+                integrity = excluded.integrity,
+                expires_at = COALESCE(excluded.expires_at, all_keys.expires_at),
+                revoked_at = COALESCE(all_keys.revoked_at, excluded.revoked_at),
+                revocation_reason = CASE WHEN all_keys.revoked_at IS NULL
+                    THEN excluded.revocation_reason ELSE all_keys.revocation_reason END",
+            (
+                &k.name,
+                &k.sha1,
+                &public_key,
+                &k.owner,
+                &k.instrument_model,
+                &k.instrument_serial,
+                &k.notes,
+                &k.source,
+                k.added_at.unwrap_or_else(unix_time),
+                &k.last_used,
+                &k.integrity,
+                &k.expires_at,
+                &k.revoked_at,
+                &k.revocation_reason,
+            ),
+        )?;
+
+        if let Some(new) = &k.public_key {
+            if insertion == KeyInsertion::ChangePending {
+                record_public_key(&tx, &k.sha1, new, KeyTrust::Pending)?;
+            } else if stored_public_key.as_ref() != Some(new) {
+                record_public_key(&tx, &k.sha1, new, KeyTrust::Accepted)?;
+            }
+        }
+        tx.release()?;
+
+        Ok(insertion)
+    }
+
+    fn get_all(&self) -> Result<Vec<Key>, KeyManagementError> {
+        let c = self.db.read();
+        let mut stmt = c.prepare(&format!("SELECT {} FROM all_keys", KEY_COLUMNS))?;
+        let keys_iter = stmt.query_map([], key_from_row)?;
+        Ok(keys_iter.collect::<Result<Vec<Key>>>()?)
+    }
+
+    fn get_by_sha1(&self, sha1: &str) -> Result<Key, KeyManagementError> {
+        let c = self.db.read();
+        c.query_row(
+            &format!("SELECT {} FROM all_keys WHERE sha1 = ?1", KEY_COLUMNS),
+            [sha1],
+            key_from_row,
+        )
+        .optional()?
+        .ok_or_else(|| KeyManagementError::NotFound(sha1.into()))
+    }
+
+    fn find_by_prefix(&self, prefix: &str) -> Result<Vec<Key>, KeyManagementError> {
+        let c = self.db.read();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {} FROM all_keys WHERE substr(sha1, 1, length(?1)) = ?1 ORDER BY sha1",
+            KEY_COLUMNS
+        ))?;
+        let keys_iter = stmt.query_map([prefix], key_from_row)?;
+        Ok(keys_iter.collect::<Result<Vec<Key>>>()?)
+    }
+
+    fn search(&self, text: &str) -> Result<Vec<Key>, KeyManagementError> {
+        let pattern = like_pattern(text);
+        let c = self.db.read();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {} FROM all_keys WHERE
+                name LIKE ?1 ESCAPE '\\' OR sha1 LIKE ?1 ESCAPE '\\'
+                OR owner LIKE ?1 ESCAPE '\\' OR instrument_model LIKE ?1 ESCAPE '\\'
+                OR instrument_serial LIKE ?1 ESCAPE '\\' OR notes LIKE ?1 ESCAPE '\\'
+            ORDER BY name",
+            KEY_COLUMNS
+        ))?;
+        let keys_iter = stmt.query_map([pattern], key_from_row)?;
+        Ok(keys_iter.collect::<Result<Vec<Key>>>()?)
+    }
+
+    fn rename(&self, sha1: &str, name: &str) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let updated = c.execute(
+            "UPDATE all_keys SET name = ?1 WHERE sha1 = ?2",
+            (name, sha1),
+        )?;
+        if updated == 0 {
+            return Err(KeyManagementError::NotFound(sha1.into()));
+        }
+        Ok(())
+    }
+
+    fn update_public_key(&self, sha1: &str, public_key: &[u8]) -> Result<(), KeyManagementError> {
+        if check_public_key(public_key).is_err() {
+            return Err(KeyManagementError::InvalidPublicKey);
+        }
+
+        let c = self.db.read();
+        let tx = Savepoint::new(&c)?;
+        let updated = tx.execute(
+            "UPDATE all_keys SET public_key = ?1 WHERE sha1 = ?2",
+            (public_key, sha1),
+        )?;
+        if updated == 0 {
+            return Err(KeyManagementError::NotFound(sha1.into()));
+        }
+        record_public_key(&tx, sha1, public_key, KeyTrust::Accepted)?;
+        tx.release()?;
+        Ok(())
+    }
+
+    fn update_metadata(&self, k: &Key) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let updated = c.execute(
+            "UPDATE all_keys SET owner = ?1, instrument_model = ?2, instrument_serial = ?3,
+                notes = ?4 WHERE sha1 = ?5",
+            (
+                &k.owner,
+                &k.instrument_model,
+                &k.instrument_serial,
+                &k.notes,
+                &k.sha1,
+            ),
+        )?;
+        if updated == 0 {
+            return Err(KeyManagementError::NotFound(k.sha1.clone()));
+        }
+        Ok(())
+    }
+
+    fn set_expiry(&self, sha1: &str, expires_at: Option<i64>) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let updated = c.execute(
+            "UPDATE all_keys SET expires_at = ?1 WHERE sha1 = ?2",
+            (expires_at, sha1),
+        )?;
+        if updated == 0 {
+            return Err(KeyManagementError::NotFound(sha1.into()));
+        }
+        Ok(())
+    }
+
+    fn revoke(&self, revocation: &Revocation) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let updated = c.execute(
+            "UPDATE all_keys SET revoked_at = ?1, revocation_reason = ?2 WHERE sha1 = ?3",
+            (revocation.revoked_at, &revocation.reason, &revocation.sha1),
+        )?;
+        if updated == 0 {
+            return Err(KeyManagementError::NotFound(revocation.sha1.clone()));
+        }
+        Ok(())
+    }
+
+    fn reinstate(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let updated = c.execute(
+            "UPDATE all_keys SET revoked_at = NULL, revocation_reason = '' WHERE sha1 = ?1",
+            [sha1],
+        )?;
+        if updated == 0 {
+            return Err(KeyManagementError::NotFound(sha1.into()));
         }
+        Ok(())
+    }
 
+    fn mark_used(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let updated = c.execute(
+            "UPDATE all_keys SET last_used = ?1 WHERE sha1 = ?2",
+            (unix_time(), sha1),
+        )?;
+        if updated == 0 {
+            return Err(KeyManagementError::NotFound(sha1.into()));
+        }
+        Ok(())
+    }
+
+    fn delete(&self, sha1: &str) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let tx = Savepoint::new(&c)?;
+        let deleted = tx.execute("DELETE FROM all_keys WHERE sha1 = ?1", [sha1])?;
+        if deleted == 0 {
+            return Err(KeyManagementError::NotFound(sha1.into()));
+        }
+        tx.execute("DELETE FROM key_group_members WHERE sha1 = ?1", [sha1])?;
+        tx.release()?;
+        Ok(())
+    }
+
+    fn create_group(&self, name: &str) -> Result<(), KeyManagementError> {
         let c = self.db.read();
         c.execute(
-            "INSERT or REPLACE INTO all_keys (name, sha1, public_key) VALUES (?1, ?2, ?3)",
-            (&k.name, &k.sha1, &k.public_key),
+            "INSERT OR IGNORE INTO key_groups (name) VALUES (?1)",
+            [name],
         )?;
+        Ok(())
+    }
 
+    fn delete_group(&self, name: &str) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let tx = Savepoint::new(&c)?;
+        let deleted = tx.execute("DELETE FROM key_groups WHERE name = ?1", [name])?;
+        if deleted == 0 {
+            return Err(KeyManagementError::GroupNotFound(name.into()));
+        }
+        tx.execute(
+            "DELETE FROM key_group_members WHERE group_name = ?1",
+            [name],
+        )?;
+        tx.release()?;
         Ok(())
     }
 
-    pub fn get_all(&self) -> Result<Vec<Key>> {
-        let mut v: Vec<Key> = Vec::new();
+    fn get_groups(&self) -> Result<Vec<String>, KeyManagementError> {
         let c = self.db.read();
-        let mut stmt = c.prepare("SELECT rowid, name, sha1, public_key FROM all_keys")?;
-        let keys_iter = stmt.query_map([], |row| {
-            Ok(Key {
-                rowid: row.get(0)?,
-                name: row.get(1)?,
-                sha1: row.get(2)?,
-                public_key: row.get(3)?,
-            })
-        })?;
+        let mut stmt = c.prepare("SELECT name FROM key_groups ORDER BY name")?;
+        let names = stmt.query_map([], |row| row.get(0))?;
+        Ok(names.collect::<Result<Vec<String>>>()?)
+    }
+
+    fn add_to_group(&self, group: &str, sha1: &str) -> Result<(), KeyManagementError> {
+        self.check_group(group)?;
+        self.get_by_sha1(sha1)?;
 
-        for k in keys_iter {
-            v.push(k?);
+        let c = self.db.read();
+        c.execute(
+            "INSERT OR IGNORE INTO key_group_members (group_name, sha1) VALUES (?1, ?2)",
+            (group, sha1),
+        )?;
+        Ok(())
+    }
+
+    fn remove_from_group(&self, group: &str, sha1: &str) -> Result<(), KeyManagementError> {
+        self.check_group(group)?;
+
+        let c = self.db.read();
+        c.execute(
+            "DELETE FROM key_group_members WHERE group_name = ?1 AND sha1 = ?2",
+            (group, sha1),
+        )?;
+        Ok(())
+    }
+
+    fn get_group_members(&self, group: &str) -> Result<Vec<Key>, KeyManagementError> {
+        self.check_group(group)?;
+
+        let c = self.db.read();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {} FROM all_keys WHERE sha1 IN
+                (SELECT sha1 FROM key_group_members WHERE group_name = ?1)
+            ORDER BY name",
+            KEY_COLUMNS
+        ))?;
+        let keys_iter = stmt.query_map([group], key_from_row)?;
+        Ok(keys_iter.collect::<Result<Vec<Key>>>()?)
+    }
+
+    fn get_key_groups(&self, sha1: &str) -> Result<Vec<String>, KeyManagementError> {
+        let c = self.db.read();
+        let mut stmt = c.prepare(
+            "SELECT group_name FROM key_group_members WHERE sha1 = ?1 ORDER BY group_name",
+        )?;
+        let names = stmt.query_map([sha1], |row| row.get(0))?;
+        Ok(names.collect::<Result<Vec<String>>>()?)
+    }
+
+    fn record_encryption(&self, entry: &AuditEntry) -> Result<i64, KeyManagementError> {
+        let c = self.db.read();
+        c.execute(
+            "INSERT INTO audit_log (timestamp, source_path, source_sha256, output_path,
+                output_size, key_sha1) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (
+                entry.timestamp,
+                &entry.source_path,
+                &entry.source_sha256,
+                &entry.output_path,
+                entry.output_size as i64,
+                &entry.key_sha1,
+            ),
+        )?;
+        Ok(c.last_insert_rowid())
+    }
+
+    fn get_audit_log(&self, filter: &AuditFilter) -> Result<Vec<AuditEntry>, KeyManagementError> {
+        let c = self.db.read();
+        let mut stmt = c.prepare(
+            "SELECT id, timestamp, source_path, source_sha256, output_path, output_size, key_sha1
+            FROM audit_log
+            WHERE (?1 IS NULL OR key_sha1 = ?1)
+                AND (?2 IS NULL OR source_path LIKE ?2 ESCAPE '\\' OR output_path LIKE ?2 ESCAPE '\\')
+                AND (?3 IS NULL OR source_sha256 = ?3)
+            ORDER BY timestamp DESC, id DESC
+            LIMIT ?4",
+        )?;
+        let entries = stmt.query_map(
+            (
+                &filter.key_sha1,
+                filter.path.as_deref().map(like_pattern),
+                &filter.source_sha256,
+                filter.limit.map(|l| l as i64).unwrap_or(-1),
+            ),
+            audit_entry_from_row,
+        )?;
+        Ok(entries.collect::<Result<Vec<AuditEntry>>>()?)
+    }
+
+    fn get_key_history(&self, sha1: &str) -> Result<Vec<KeyHistoryEntry>, KeyManagementError> {
+        let c = self.db.read();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {} FROM key_history WHERE sha1 = ?1 ORDER BY seen_at DESC, id DESC",
+            HISTORY_COLUMNS
+        ))?;
+        let entries = stmt.query_map([sha1], history_entry_from_row)?;
+        Ok(entries.collect::<Result<Vec<KeyHistoryEntry>>>()?)
+    }
+
+    fn get_pending_key_changes(&self) -> Result<Vec<KeyHistoryEntry>, KeyManagementError> {
+        let c = self.db.read();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {} FROM key_history WHERE trust = ?1 ORDER BY seen_at DESC, id DESC",
+            HISTORY_COLUMNS
+        ))?;
+        let entries = stmt.query_map([KeyTrust::Pending], history_entry_from_row)?;
+        Ok(entries.collect::<Result<Vec<KeyHistoryEntry>>>()?)
+    }
+
+    fn accept_public_key(&self, sha1: &str, id: i64) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let tx = Savepoint::new(&c)?;
+        let public_key: Vec<u8> = tx
+            .query_row(
+                "SELECT public_key FROM key_history WHERE id = ?1 AND sha1 = ?2",
+                (id, sha1),
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| KeyManagementError::HistoryNotFound(sha1.into(), id))?;
+        let updated = tx.execute(
+            "UPDATE all_keys SET public_key = ?1 WHERE sha1 = ?2",
+            (&public_key, sha1),
+        )?;
+        if updated == 0 {
+            return Err(KeyManagementError::NotFound(sha1.into()));
         }
+        record_public_key(&tx, sha1, &public_key, KeyTrust::Accepted)?;
+        tx.release()?;
+        Ok(())
+    }
 
-        Ok(v)
+    fn reject_public_key(&self, sha1: &str, id: i64) -> Result<(), KeyManagementError> {
+        let c = self.db.read();
+        let updated = c.execute(
+            "UPDATE key_history SET trust = ?1, decided_at = ?2
+            WHERE id = ?3 AND sha1 = ?4 AND trust = ?5",
+            (KeyTrust::Rejected, unix_time(), id, sha1, KeyTrust::Pending),
+        )?;
+        if updated == 0 {
+            return Err(KeyManagementError::HistoryNotFound(sha1.into(), id));
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,339 @@
+//! client of the key server, which publishes the public keys of the instruments
+//! under `<base url><sha1>/public.key.pem`, and the revocation list, signed by the publisher
+//!
+//! a downloaded key is only returned when its signature, `<sha1>/public.key.sig`,
+//! is valid for the publisher key (see [`crate::key_signature`])
+//!
+//! workshops publish the keys of new instruments with [`KeyServerClient::upload_public_key`],
+//! a `PUT` on the key url authenticated by an api token or by the publisher signature of the key
+//!
+//! failed requests (network failure, server error) are retried following a [`RetryPolicy`]
+
+use std::thread;
+use std::time::Duration;
+
+use isahc::config::Configurable;
+use isahc::prelude::*;
+use log::{info, warn};
+use openssl::pkey::{PKey, Private};
+
+use crate::encrypt::check_public_key;
+use crate::key_signature::{sign_key, KeySignatureError, PublisherKey, PublisherTrust};
+use crate::keys_management::{check_sha1, KeyManagementError};
+
+/// key server of the instruments
+pub const DEFAULT_KEY_SERVER_URL: &str = "http://or1.frett27.net/k/";
+
+/// environment variable overriding the url of the key server
+pub const KEY_SERVER_ENV: &str = "ENCRYPTER_KEY_SERVER";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(20);
+
+const REVOCATION_LIST_FILE: &str = "revocations.json";
+
+/// header carrying the publisher signature of an uploaded key, base64 encoded
+pub const SIGNATURE_HEADER: &str = "X-Key-Signature";
+
+#[derive(thiserror::Error, Debug)]
+pub enum KeyServerError {
+    #[error("la clé {0} n'existe pas sur le serveur")]
+    NotFound(String),
+    #[error("erreur réseau : {0}")]
+    Network(String),
+    #[error("erreur de lecture : {0}")]
+    Read(String),
+    #[error("le serveur a retourné le code {0}")]
+    Status(u16),
+    #[error("adresse du serveur invalide : {0}")]
+    InvalidUrl(String),
+    #[error("la clé publique reçue pour {0} est invalide : {1}")]
+    InvalidKey(String, String),
+    #[error("la clé {0} n'est pas signée par l'éditeur")]
+    Unsigned(String),
+    #[error("envoi de la clé {0} refusé, l'authentification est invalide")]
+    Unauthorized(String),
+    #[error("une autre clé est déjà publiée pour {0}")]
+    Conflict(String),
+    #[error("clé refusée par le serveur : {0}")]
+    Rejected(String),
+    #[error(transparent)]
+    Signature(#[from] KeySignatureError),
+    #[error(transparent)]
+    Key(#[from] KeyManagementError),
+}
+
+impl KeyServerError {
+    /// errors which may not happen again on a new attempt
+    fn is_transient(&self) -> bool {
+        match self {
+            KeyServerError::Network(_) => true,
+            KeyServerError::Status(status) => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+/// attempts of a request, network failures and server errors are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// total number of attempts, at least one is made
+    pub attempts: u32,
+    /// wait between two attempts
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    /// a single attempt
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 1,
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(500),
+        }
+    }
+}
+
+/// check a published key, a pem public key signed by the publisher for the sha1,
+/// a key without signature is refused
+pub(crate) fn check_published_key(
+    trust: &PublisherTrust,
+    sha1: &str,
+    public_key: &[u8],
+    signature: Option<&[u8]>,
+) -> Result<(), KeyServerError> {
+    check_public_key(public_key)
+        .map_err(|e| KeyServerError::InvalidKey(sha1.into(), e.to_string()))?;
+    let signature = signature.ok_or_else(|| KeyServerError::Unsigned(sha1.into()))?;
+    trust.verify(sha1, public_key, signature)?;
+    Ok(())
+}
+
+/// authentication of a key upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadAuth {
+    /// api token given by the administrator of the key server
+    Token(String),
+    /// base64 signature of the key by a publisher trusted by the server,
+    /// published with the key
+    Signature(String),
+}
+
+impl UploadAuth {
+    /// sign the key with the private key of the publisher
+    pub fn signed(
+        publisher: &PKey<Private>,
+        sha1: &str,
+        public_key: &[u8],
+    ) -> Result<UploadAuth, KeySignatureError> {
+        Ok(UploadAuth::Signature(sign_key(
+            publisher, sha1, public_key,
+        )?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadOutcome {
+    Created,
+    /// the same key was already published
+    Unchanged,
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyServerClient {
+    base_url: String,
+    timeout: Duration,
+    retry: RetryPolicy,
+    publisher: PublisherTrust,
+}
+
+impl Default for KeyServerClient {
+    fn default() -> Self {
+        KeyServerClient::new(DEFAULT_KEY_SERVER_URL)
+    }
+}
+
+impl KeyServerClient {
+    /// client of the server at `base_url`, a trailing slash is added if missing
+    pub fn new(base_url: &str) -> KeyServerClient {
+        let mut base_url = base_url.trim().to_string();
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+        KeyServerClient {
+            base_url,
+            timeout: DEFAULT_TIMEOUT,
+            retry: RetryPolicy::default(),
+            publisher: PublisherTrust::default(),
+        }
+    }
+
+    /// client of the server given by [`KEY_SERVER_ENV`], or of the default server
+    pub fn from_env() -> KeyServerClient {
+        match std::env::var(KEY_SERVER_ENV) {
+            Ok(url) if !url.trim().is_empty() => KeyServerClient::new(&url),
+            _ => KeyServerClient::default(),
+        }
+    }
+
+    /// timeout of each attempt
+    pub fn with_timeout(mut self, timeout: Duration) -> KeyServerClient {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> KeyServerClient {
+        self.retry = retry;
+        self
+    }
+
+    /// key checking the signatures of the downloaded keys,
+    /// the embedded root key or the one of [`crate::key_signature::PUBLISHER_KEY_ENV`] by default
+    pub fn with_publisher_key(mut self, publisher: PublisherKey) -> KeyServerClient {
+        self.publisher = PublisherTrust::Required(publisher);
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn key_url(&self, sha1: &str) -> String {
+        format!("{}{}/public.key.pem", self.base_url, sha1)
+    }
+
+    pub fn signature_url(&self, sha1: &str) -> String {
+        format!("{}{}/public.key.sig", self.base_url, sha1)
+    }
+
+    pub fn revocation_list_url(&self) -> String {
+        format!("{}{}", self.base_url, REVOCATION_LIST_FILE)
+    }
+
+    pub fn revocation_signature_url(&self) -> String {
+        format!("{}{}.sig", self.base_url, REVOCATION_LIST_FILE)
+    }
+
+    /// publisher key checking the downloaded keys and revocation list
+    pub fn publisher(&self) -> &PublisherTrust {
+        &self.publisher
+    }
+
+    /// download the public key of an instrument, the key is checked to be a pem public key
+    /// signed by the publisher for this sha1
+    pub fn fetch_public_key(&self, sha1: &str) -> Result<Vec<u8>, KeyServerError> {
+        check_sha1(sha1)?;
+        let content = self.get(&self.key_url(sha1)).map_err(|e| match e {
+            KeyServerError::Status(404) => KeyServerError::NotFound(sha1.into()),
+            e => e,
+        })?;
+        let signature = match self.get(&self.signature_url(sha1)) {
+            Ok(signature) => Some(signature),
+            Err(KeyServerError::Status(404)) => None,
+            Err(e) => return Err(e),
+        };
+        check_published_key(&self.publisher, sha1, &content, signature.as_deref())?;
+        info!("public key {} downloaded", sha1);
+        Ok(content)
+    }
+
+    /// publish the public key of an instrument, a different key already
+    /// published for the sha1 is not replaced
+    pub fn upload_public_key(
+        &self,
+        sha1: &str,
+        public_key: &[u8],
+        auth: &UploadAuth,
+    ) -> Result<UploadOutcome, KeyServerError> {
+        check_sha1(sha1)?;
+        check_public_key(public_key)
+            .map_err(|e| KeyServerError::InvalidKey(sha1.into(), e.to_string()))?;
+        let url = self.key_url(sha1);
+        let (status, content) = self.retried(&url, || {
+            let request =
+                isahc::Request::put(&url).header("Content-Type", "application/x-pem-file");
+            let request = match auth {
+                UploadAuth::Token(token) => {
+                    request.header("Authorization", format!("Bearer {}", token))
+                }
+                UploadAuth::Signature(signature) => {
+                    request.header(SIGNATURE_HEADER, signature.as_str())
+                }
+            };
+            self.send(request, public_key.to_vec())
+        })?;
+        match status {
+            201 => {
+                info!("public key {} published", sha1);
+                Ok(UploadOutcome::Created)
+            }
+            200 | 204 => Ok(UploadOutcome::Unchanged),
+            401 | 403 => Err(KeyServerError::Unauthorized(sha1.into())),
+            409 => Err(KeyServerError::Conflict(sha1.into())),
+            400 => Err(KeyServerError::Rejected(
+                String::from_utf8_lossy(&content).trim().to_string(),
+            )),
+            status => Err(KeyServerError::Status(status)),
+        }
+    }
+
+    /// content of an url, retried on network failures and server errors
+    pub fn get(&self, url: &str) -> Result<Vec<u8>, KeyServerError> {
+        match self.retried(url, || self.send(isahc::Request::get(url), vec![]))? {
+            (status, content) if (200..300).contains(&status) => Ok(content),
+            (status, _) => Err(KeyServerError::Status(status)),
+        }
+    }
+
+    /// run a request, retried on network failures and server errors
+    fn retried<T>(
+        &self,
+        url: &str,
+        request: impl Fn() -> Result<T, KeyServerError>,
+    ) -> Result<T, KeyServerError> {
+        let mut attempt = 1;
+        loop {
+            match request() {
+                Err(e) if e.is_transient() && attempt < self.retry.attempts => {
+                    warn!("attempt {} on {} failed : {}, retrying", attempt, url, e);
+                    attempt += 1;
+                    thread::sleep(self.retry.delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// send a request, returns the status and the content of the response,
+    /// server errors are returned as [`KeyServerError::Status`]
+    fn send(
+        &self,
+        request: isahc::http::request::Builder,
+        body: Vec<u8>,
+    ) -> Result<(u16, Vec<u8>), KeyServerError> {
+        let url = request.uri_ref().map(|u| u.to_string()).unwrap_or_default();
+        let request = request
+            .timeout(self.timeout)
+            .body(body)
+            .map_err(|e| KeyServerError::InvalidUrl(format!("{} : {}", url, e)))?;
+        let mut response = request
+            .send()
+            .map_err(|e| KeyServerError::Network(e.to_string()))?;
+        let status = response.status().as_u16();
+        if status >= 500 {
+            return Err(KeyServerError::Status(status));
+        }
+        let mut content = vec![];
+        response
+            .copy_to(&mut content)
+            .map_err(|e| KeyServerError::Network(e.to_string()))?;
+        Ok((status, content))
+    }
+}
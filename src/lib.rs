@@ -8,8 +8,34 @@ pub mod encrypt;
 
 pub mod folder;
 
+pub mod output;
+
+pub mod mirror;
+
+pub mod watch;
+
 pub mod keys_management;
 
+pub mod key_store;
+
+pub mod key_index;
+
+pub mod integrity;
+
+pub mod key_book;
+
+pub mod key_signature;
+
+pub mod keyserver;
+
+pub mod reference_server;
+
+pub mod key_provider;
+
+pub mod key_sync;
+
+pub mod revocation;
+
 pub mod i18n;
 
 use std::error;
@@ -0,0 +1,250 @@
+//! incremental synchronization of a source folder with the output folder of a key,
+//! keeping the sub folders with the default layout: only the new and the changed files
+//! are encrypted, and the outputs of the removed files can be deleted.
+//!
+//! [`plan`] lists the actions without changing anything, as a dry run, [`apply`] runs them.
+//! Only the outputs recorded in the audit log are deleted, the other files of the output
+//! folder are left as they are
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+use crate::folder::{walk, EntryError, Walk, WalkOptions};
+use crate::key_store::KeyStore;
+use crate::keys_management::{AuditFilter, Key, KeyManagementError};
+use crate::output::{
+    absolute, encrypt_recorded, relative_to, EncryptionStatus, OutputError, OutputLayout,
+    StatusChecker,
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MirrorOptions {
+    /// files of the source folder which are encrypted
+    pub walk: WalkOptions,
+    /// delete the outputs whose source was removed
+    pub remove_orphans: bool,
+    pub layout: OutputLayout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MirrorAction {
+    /// new file (missing) or file changed since its encryption (stale)
+    Encrypt {
+        source: PathBuf,
+        output: PathBuf,
+        status: EncryptionStatus,
+    },
+    /// the source of the output was removed
+    Remove { source: PathBuf, output: PathBuf },
+}
+
+/// actions synchronizing a folder with the output folder of a key
+#[derive(Debug, Clone, Default)]
+pub struct MirrorPlan {
+    pub root: PathBuf,
+    pub key_name: String,
+    pub sha1: String,
+    /// folder of the outputs of the key, only the outputs in this folder are deleted
+    pub key_folder: PathBuf,
+    pub actions: Vec<MirrorAction>,
+    /// files already encrypted
+    pub up_to_date: usize,
+    /// entries of the source folder which can't be read, or encrypted to the same
+    /// file as another entry
+    pub errors: Vec<EntryError>,
+}
+
+impl MirrorPlan {
+    pub fn to_encrypt(&self) -> usize {
+        self.actions
+            .iter()
+            .filter(|a| matches!(a, MirrorAction::Encrypt { .. }))
+            .count()
+    }
+
+    pub fn to_remove(&self) -> usize {
+        self.actions.len() - self.to_encrypt()
+    }
+
+    /// the folder is already synchronized
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MirrorReport {
+    pub encrypted: usize,
+    pub removed: usize,
+    /// path and error of the failed actions
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+/// files of the root folder, without the encrypted files of the layout
+fn source_walk(root: &Path, options: &MirrorOptions) -> Walk {
+    walk(root, &options.walk.clone().with_outputs(&options.layout))
+}
+
+/// two keys can't write the same file, checked before the plans of the keys of a group
+pub fn check_keys(root: &Path, keys: &[Key], options: &MirrorOptions) -> Result<(), OutputError> {
+    if keys.len() < 2 {
+        return Ok(());
+    }
+    let relative: Vec<PathBuf> = source_walk(root, options)
+        .files()
+        .iter()
+        .map(|f| relative_to(f, root))
+        .collect();
+    options.layout.check_keys(&relative, keys)
+}
+
+/// actions synchronizing the root folder with the output folder of the key, nothing is changed
+pub fn plan(
+    db: &dyn KeyStore,
+    root: &Path,
+    key_name: &str,
+    sha1: &str,
+    options: &MirrorOptions,
+) -> Result<MirrorPlan, KeyManagementError> {
+    let checker = StatusChecker::new(db, key_name, sha1)?;
+    let walk = source_walk(root, options);
+    let key_folder = options.layout.key_folder(key_name, sha1);
+    let mut plan = MirrorPlan {
+        root: root.to_path_buf(),
+        key_name: key_name.into(),
+        sha1: sha1.into(),
+        // the current folder when the template starts with the source sub folders
+        key_folder: absolute(match key_folder.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => &key_folder,
+        }),
+        errors: walk.errors,
+        ..Default::default()
+    };
+
+    let files: Vec<(&Path, PathBuf)> = walk
+        .entries
+        .iter()
+        .filter(|e| !e.is_folder && e.error.is_none())
+        .map(|e| {
+            let output = options.layout.path(&e.relative, key_name, sha1);
+            (e.path.as_path(), output)
+        })
+        .collect();
+    let mut count: HashMap<&Path, usize> = HashMap::new();
+    for (_, output) in files.iter() {
+        *count.entry(output).or_default() += 1;
+    }
+
+    for (source, output) in files.iter() {
+        if count[output.as_path()] > 1 {
+            plan.errors.push(EntryError {
+                path: source.to_path_buf(),
+                message: format!("other files are encrypted to {}", output.display()),
+            });
+            continue;
+        }
+        let (source, output) = (source.to_path_buf(), output.clone());
+        match checker.status_of(&source, &output) {
+            Ok(EncryptionStatus::UpToDate) => plan.up_to_date += 1,
+            Ok(status) => plan.actions.push(MirrorAction::Encrypt {
+                source,
+                output,
+                status,
+            }),
+            Err(e) => plan.errors.push(EntryError {
+                path: source,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    if options.remove_orphans {
+        let root = absolute(root);
+        let mut seen = HashSet::new();
+        let entries = db.get_audit_log(&AuditFilter {
+            key_sha1: Some(sha1.into()),
+            ..Default::default()
+        })?;
+        // the most recent encryption of each output first
+        for entry in entries {
+            if !seen.insert(entry.output_path.clone()) {
+                continue;
+            }
+            let source = PathBuf::from(entry.source_path);
+            let output = PathBuf::from(entry.output_path);
+            if output.starts_with(&plan.key_folder)
+                && source.starts_with(&root)
+                && !source.exists()
+                && output.exists()
+            {
+                plan.actions.push(MirrorAction::Remove { source, output });
+            }
+        }
+    }
+    Ok(plan)
+}
+
+/// delete the output, and its folders left empty up to the output folder of the key
+fn remove_output(output: &Path, target: &Path) -> std::io::Result<()> {
+    fs::remove_file(output)?;
+    let mut folder = output.parent();
+    while let Some(f) = folder.filter(|f| f.starts_with(target) && *f != target) {
+        if fs::remove_dir(f).is_err() {
+            break;
+        }
+        folder = f.parent();
+    }
+    Ok(())
+}
+
+/// run the actions of the plan with the public key, `progress` is called with the count
+/// of finished actions and the total. A failed action does not stop the synchronization
+pub fn apply(
+    db: &dyn KeyStore,
+    plan: &MirrorPlan,
+    key: &[u8],
+    mut progress: impl FnMut(usize, usize),
+) -> MirrorReport {
+    let mut report = MirrorReport::default();
+    let total = plan.actions.len();
+    for (done, action) in plan.actions.iter().enumerate() {
+        let (path, result) = match action {
+            MirrorAction::Encrypt { source, output, .. } => {
+                let result = match output.parent().map(fs::create_dir_all) {
+                    Some(Err(e)) => Err(e.into()),
+                    _ => encrypt_recorded(db, source, output, key, &plan.sha1),
+                };
+                if result.is_ok() {
+                    report.encrypted += 1;
+                }
+                (source, result)
+            }
+            MirrorAction::Remove { output, .. } => {
+                let result = remove_output(output, &plan.key_folder);
+                if result.is_ok() {
+                    info!(" file {}, removed", output.display());
+                    report.removed += 1;
+                }
+                (output, result.map_err(|e| e.into()))
+            }
+        };
+        if let Err(e) = result {
+            warn!("synchronization of {} failed : {}", path.display(), e);
+            report.failed.push((path.clone(), e.to_string()));
+        }
+        progress(done + 1, total);
+    }
+    info!(
+        "{} synchronized for {} : {} files encrypted, {} removed, {} failed",
+        plan.root.display(),
+        plan.sha1,
+        report.encrypted,
+        report.removed,
+        report.failed.len()
+    );
+    report
+}
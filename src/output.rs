@@ -0,0 +1,493 @@
+//! encrypted files of a key, written under an output root and named by a template,
+//! by default `<key name>-<sha1>/<sub folders>/<file name>x` in the current folder,
+//! and their status against the source files
+//!
+//! an output is stale when the source changed since the encryption: the sha256 recorded
+//! in the audit log differs from the source, or without record, the source is more recent
+//! than the output
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::encrypt::{encrypt_content_with_inmemory_key, sha256_hex};
+use crate::key_store::KeyStore;
+use crate::keys_management::{unix_time, AuditEntry, AuditFilter, Key, KeyManagementError};
+
+/// sub folders of the source kept under the folder of the key
+pub const DEFAULT_TEMPLATE: &str = "{out}/{key.name}-{key.sha1}/{relpath}/{name}x";
+
+#[derive(thiserror::Error, Debug)]
+pub enum OutputError {
+    #[error("unknown placeholder {{{0}}} in the output template")]
+    UnknownPlaceholder(String),
+    #[error("invalid output template {0}, {1}")]
+    Invalid(String, &'static str),
+    #[error("{0} and {1} would be encrypted to the same file {2}")]
+    Collision(PathBuf, PathBuf, PathBuf),
+    #[error("the keys {0} and {1} would write the same file {2}")]
+    KeyCollision(String, String, PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    KeyName,
+    KeySha1,
+    Name,
+    Stem,
+    Ext,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Out,
+    RelPath,
+    Parts(Vec<Part>),
+}
+
+impl Segment {
+    /// the segment changes with the source file
+    fn is_per_file(&self) -> bool {
+        match self {
+            Segment::Out => false,
+            Segment::RelPath => true,
+            Segment::Parts(parts) => parts
+                .iter()
+                .any(|p| matches!(p, Part::Name | Part::Stem | Part::Ext)),
+        }
+    }
+}
+
+fn parse_segment(template: &str, segment: &str) -> Result<Segment, OutputError> {
+    let mut parts = vec![];
+    let mut rest = segment;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(Part::Text(rest[..start].into()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| OutputError::Invalid(template.into(), "unclosed {"))?;
+        let placeholder = &rest[start + 1..start + end];
+        parts.push(match placeholder {
+            "out" | "relpath" if segment.len() != placeholder.len() + 2 => {
+                return Err(OutputError::Invalid(
+                    template.into(),
+                    "{out} and {relpath} must be whole folders",
+                ))
+            }
+            "out" => return Ok(Segment::Out),
+            "relpath" => return Ok(Segment::RelPath),
+            "key.name" => Part::KeyName,
+            "key.sha1" => Part::KeySha1,
+            "name" => Part::Name,
+            "stem" => Part::Stem,
+            "ext" => Part::Ext,
+            p => return Err(OutputError::UnknownPlaceholder(p.into())),
+        });
+        rest = &rest[start + end + 1..];
+    }
+    if rest.contains('}') {
+        return Err(OutputError::Invalid(template.into(), "unopened }"));
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest.into()));
+    }
+    Ok(Segment::Parts(parts))
+}
+
+/// names reserved by windows, with or without extension
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+
+/// a key name is a single folder or file name on every system: the separators,
+/// the characters refused by windows and the trailing dots and spaces (so `.` and `..`)
+/// are replaced by `_`, the empty and reserved names are prefixed by `_`
+pub fn path_safe(text: &str) -> String {
+    let mut safe: String = text
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let kept = safe.trim_end_matches(['.', ' ']).len();
+    safe.replace_range(kept.., &"_".repeat(safe.len() - kept));
+    let stem = safe.split('.').next().unwrap_or_default().to_lowercase();
+    if safe.is_empty() || RESERVED_NAMES.contains(&stem.as_str()) {
+        safe.insert(0, '_');
+    }
+    safe
+}
+
+/// location of the encrypted files: an output root and a naming template, whose
+/// placeholders are
+///
+/// - `{out}` the output root, the current folder when empty
+/// - `{key.name}`, `{key.sha1}` the key
+/// - `{relpath}` the sub folders of the source, from the folder the files are taken from
+/// - `{name}` the file name, `{stem}` without its extension, `{ext}` its extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputLayout {
+    root: PathBuf,
+    template: String,
+    segments: Vec<Segment>,
+}
+
+impl Default for OutputLayout {
+    fn default() -> Self {
+        OutputLayout::new("", DEFAULT_TEMPLATE).expect("valid default template")
+    }
+}
+
+impl OutputLayout {
+    pub fn new<P: Into<PathBuf>>(root: P, template: &str) -> Result<OutputLayout, OutputError> {
+        let segments = template
+            .split(['/', '\\'])
+            .filter(|s| !s.is_empty())
+            .map(|s| parse_segment(template, s))
+            .collect::<Result<Vec<Segment>, OutputError>>()?;
+        if segments.iter().skip(1).any(|s| *s == Segment::Out) {
+            return Err(OutputError::Invalid(
+                template.into(),
+                "{out} must be the first folder",
+            ));
+        }
+        match segments.last() {
+            Some(Segment::Parts(parts))
+                if parts.iter().any(|p| matches!(p, Part::Name | Part::Stem)) => {}
+            _ => {
+                return Err(OutputError::Invalid(
+                    template.into(),
+                    "the file name must contain {name} or {stem}",
+                ))
+            }
+        }
+        Ok(OutputLayout {
+            root: root.into(),
+            template: template.into(),
+            segments,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    fn start(&self) -> PathBuf {
+        match self.template.starts_with('/') {
+            true => PathBuf::from("/"),
+            false => PathBuf::new(),
+        }
+    }
+
+    fn push_parts(
+        &self,
+        path: &mut PathBuf,
+        parts: &[Part],
+        key_name: &str,
+        sha1: &str,
+        file: &Path,
+    ) {
+        let mut text = OsString::new();
+        for part in parts {
+            match part {
+                Part::Text(t) => text.push(t),
+                Part::KeyName => text.push(path_safe(key_name)),
+                Part::KeySha1 => text.push(sha1),
+                Part::Name => text.push(file.file_name().unwrap_or_default()),
+                Part::Stem => text.push(file.file_stem().unwrap_or_default()),
+                Part::Ext => text.push(file.extension().unwrap_or_default()),
+            }
+        }
+        if !text.is_empty() {
+            path.push(text);
+        }
+    }
+
+    /// encrypted file of a source for the key, `relative` is the path of the source
+    /// from the folder the files are taken from
+    pub fn path(&self, relative: &Path, key_name: &str, sha1: &str) -> PathBuf {
+        let mut path = self.start();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Out => path.push(&self.root),
+                Segment::RelPath => {
+                    if let Some(parent) = relative.parent() {
+                        path.push(parent);
+                    }
+                }
+                Segment::Parts(parts) => {
+                    self.push_parts(&mut path, parts, key_name, sha1, relative)
+                }
+            }
+        }
+        path
+    }
+
+    /// folder of all the encrypted files of the key, the start of the template
+    /// which does not depend on the source
+    pub fn key_folder(&self, key_name: &str, sha1: &str) -> PathBuf {
+        let mut path = self.start();
+        for segment in self.segments.iter().take_while(|s| !s.is_per_file()) {
+            match segment {
+                Segment::Out => path.push(&self.root),
+                Segment::Parts(parts) => {
+                    self.push_parts(&mut path, parts, key_name, sha1, Path::new(""))
+                }
+                Segment::RelPath => {}
+            }
+        }
+        path
+    }
+
+    /// globs of the key folders, or of the encrypted files when the key folders can't be
+    /// told from the source folders, to leave them out of the source files
+    pub fn exclude_globs(&self) -> Vec<String> {
+        let glob = |parts: &[Part]| -> String {
+            parts
+                .iter()
+                .map(|p| match p {
+                    Part::Text(t) => t.clone(),
+                    Part::KeySha1 => "?".repeat(40),
+                    _ => "*".into(),
+                })
+                .collect()
+        };
+        // `*` matches every name
+        let distinct = |glob: &String| !glob.chars().all(|c| c == '*');
+        let folders: Vec<String> = self.segments[..self.segments.len() - 1]
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Parts(parts)
+                    if parts
+                        .iter()
+                        .any(|p| matches!(p, Part::KeyName | Part::KeySha1)) =>
+                {
+                    Some(glob(parts))
+                }
+                _ => None,
+            })
+            .filter(distinct)
+            .collect();
+        if !folders.is_empty() {
+            return folders;
+        }
+        match self.segments.last() {
+            Some(Segment::Parts(parts)) => Some(glob(parts)).into_iter().filter(distinct).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// two keys can't write the same file, `relative` are the paths of the sources
+    /// from the folder the files are taken from
+    pub fn check_keys(&self, relative: &[PathBuf], keys: &[Key]) -> Result<(), OutputError> {
+        if keys.len() < 2 {
+            return Ok(());
+        }
+        let mut outputs: HashMap<PathBuf, &Key> = HashMap::new();
+        for k in keys {
+            for source in relative {
+                let output = self.path(source, &k.name, &k.sha1);
+                match outputs.insert(output.clone(), k) {
+                    Some(other) if other.sha1 != k.sha1 => {
+                        return Err(OutputError::KeyCollision(
+                            other.to_string(),
+                            k.to_string(),
+                            output,
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// encrypted files of the sources taken from the folder `source_root`,
+    /// two sources can't be encrypted to the same file
+    pub fn paths(
+        &self,
+        sources: &[PathBuf],
+        source_root: &Path,
+        key_name: &str,
+        sha1: &str,
+    ) -> Result<Vec<PathBuf>, OutputError> {
+        let mut outputs: HashMap<PathBuf, &PathBuf> = HashMap::new();
+        let mut paths = vec![];
+        for source in sources {
+            let output = self.path(&relative_to(source, source_root), key_name, sha1);
+            if let Some(other) = outputs.insert(output.clone(), source) {
+                return Err(OutputError::Collision(
+                    other.clone(),
+                    source.clone(),
+                    output,
+                ));
+            }
+            paths.push(output);
+        }
+        Ok(paths)
+    }
+}
+
+/// path of the source from the folder the files are taken from, the file name when
+/// the source is not in this folder
+pub fn relative_to(source: &Path, source_root: &Path) -> PathBuf {
+    match source.strip_prefix(source_root) {
+        Ok(relative) if !source_root.as_os_str().is_empty() => relative.to_path_buf(),
+        _ => PathBuf::from(source.file_name().unwrap_or(source.as_os_str())),
+    }
+}
+
+/// encrypt the source file for the key, and keep track of it in the audit log,
+/// with the hash of the content that was encrypted
+pub fn encrypt_recorded(
+    db: &dyn KeyStore,
+    source: &Path,
+    output: &Path,
+    key: &[u8],
+    sha1: &str,
+) -> crate::Result<()> {
+    let content = fs::read(source)?;
+    encrypt_content_with_inmemory_key(&content, output, key)?;
+    info!(" file {}, encrypted", output.display());
+    let text = |p: &Path| absolute(p).to_string_lossy().to_string();
+    db.record_encryption(&AuditEntry {
+        id: 0,
+        timestamp: unix_time(),
+        source_path: text(source),
+        source_sha256: sha256_hex(&content),
+        output_path: text(output),
+        output_size: fs::metadata(output)?.len(),
+        key_sha1: sha1.into(),
+    })?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionStatus {
+    /// not encrypted for the key
+    Missing,
+    UpToDate,
+    /// the source changed since its encryption
+    Stale,
+}
+
+impl EncryptionStatus {
+    /// to be encrypted again
+    pub fn is_outdated(&self) -> bool {
+        *self != EncryptionStatus::UpToDate
+    }
+}
+
+/// canonical path, the path itself when it does not exist
+pub(crate) fn absolute(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// status of the source files for a key
+pub struct StatusChecker {
+    key_name: String,
+    sha1: String,
+    layout: OutputLayout,
+    source_root: PathBuf,
+    /// last encryption recorded for each output
+    recorded: HashMap<PathBuf, AuditEntry>,
+}
+
+impl StatusChecker {
+    /// checker of the key, with the encryptions recorded in the audit log,
+    /// for the default layout
+    pub fn new(
+        db: &dyn KeyStore,
+        key_name: &str,
+        sha1: &str,
+    ) -> Result<StatusChecker, KeyManagementError> {
+        let mut recorded = HashMap::new();
+        let entries = db.get_audit_log(&AuditFilter {
+            key_sha1: Some(sha1.into()),
+            ..Default::default()
+        })?;
+        // the most recent first
+        for entry in entries {
+            recorded
+                .entry(PathBuf::from(&entry.output_path))
+                .or_insert(entry);
+        }
+        Ok(StatusChecker {
+            key_name: key_name.into(),
+            sha1: sha1.into(),
+            layout: OutputLayout::default(),
+            source_root: PathBuf::new(),
+            recorded,
+        })
+    }
+
+    /// outputs of the layout, for the files taken from `source_root`
+    pub fn with_layout(mut self, layout: OutputLayout, source_root: &Path) -> StatusChecker {
+        self.layout = layout;
+        self.source_root = source_root.to_path_buf();
+        self
+    }
+
+    pub fn key_name(&self) -> &str {
+        &self.key_name
+    }
+
+    pub fn sha1(&self) -> &str {
+        &self.sha1
+    }
+
+    pub fn layout(&self) -> &OutputLayout {
+        &self.layout
+    }
+
+    pub fn source_root(&self) -> &Path {
+        &self.source_root
+    }
+
+    pub fn output_path(&self, source: &Path) -> PathBuf {
+        self.layout.path(
+            &relative_to(source, &self.source_root),
+            &self.key_name,
+            &self.sha1,
+        )
+    }
+
+    pub fn status(&self, source: &Path) -> io::Result<EncryptionStatus> {
+        self.status_of(source, &self.output_path(source))
+    }
+
+    /// status of the source encrypted in the given output
+    pub fn status_of(&self, source: &Path, output: &Path) -> io::Result<EncryptionStatus> {
+        let output_metadata = match fs::metadata(output) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(EncryptionStatus::Missing),
+            Err(e) => return Err(e),
+        };
+        let recorded = self
+            .recorded
+            .get(&absolute(output))
+            .filter(|e| Path::new(&e.source_path) == absolute(source));
+        let stale = match recorded {
+            Some(entry) => sha256_hex(&fs::read(source)?) != entry.source_sha256,
+            None => fs::metadata(source)?.modified()? > output_metadata.modified()?,
+        };
+        Ok(match stale {
+            true => EncryptionStatus::Stale,
+            false => EncryptionStatus::UpToDate,
+        })
+    }
+}
@@ -0,0 +1,654 @@
+//! reference implementation of the key server, for the tests and the intranets
+//!
+//! serves the keys of a [`KeyRepository`] under `/k/<sha1>/public.key.pem`, with their
+//! signature `/k/<sha1>/public.key.sig`, and accepts the keys uploaded by the workshops
+//! (`PUT` on the key url) authenticated by an api token or by a signature of a trusted publisher.
+//!
+//! `/k/` lists the published sha1 (json), the responses carry an `ETag` and
+//! `If-None-Match` is answered with `304 Not Modified`. With a signing key, the keys
+//! published without signature are signed by the server, and the key responses carry
+//! their signature in the [`SIGNATURE_HEADER`] header. The api tokens need a signing key, the
+//! keys uploaded with a token are signed by the server.
+//!
+//! the keys are read from a key folder ([`DirectoryRepository`]) or from the key database
+//! ([`StoreRepository`]), the `key_server` binary runs the server on the intranet.
+//!
+//! each connection is answered in its own thread, at most [`MAX_CONNECTIONS`] at the same
+//! time, the server is meant for a small traffic
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use openssl::memcmp;
+use openssl::pkey::{PKey, Private};
+
+use crate::encrypt::{check_public_key, sha256_hex};
+use crate::integrity::{open_protected, IntegrityKeyStore};
+use crate::key_signature::{sign_key, PublisherKey};
+use crate::key_store::KeyStore;
+use crate::keys_management::{check_sha1, Key, KeyInsertion, KeyManagementError, KeySource};
+use crate::keyserver::SIGNATURE_HEADER;
+
+/// prefix of the urls served
+pub const KEY_PREFIX: &str = "/k/";
+
+/// environment variable giving the api tokens accepted for the uploads, separated by `;`
+pub const SERVER_TOKENS_ENV: &str = "ENCRYPTER_SERVER_TOKENS";
+
+const MAX_HEADER_LINES: usize = 64;
+const MAX_LINE: u64 = 8 * 1024;
+const MAX_BODY_SIZE: usize = 64 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// pause after a failed accept (too many open files ...)
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// connections answered at the same time, the next ones wait in the listen queue
+pub const MAX_CONNECTIONS: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ServerError {
+    #[error("erreur d'entrée / sortie : {0}")]
+    Io(#[from] io::Error),
+    #[error("requête invalide : {0}")]
+    BadRequest(String),
+    #[error("erreur de la base de clés : {0}")]
+    Store(String),
+    #[error("erreur de signature : {0}")]
+    Signing(String),
+    #[error("ligne de requête trop longue")]
+    LineTooLong,
+    #[error("configuration invalide : {0}")]
+    Config(String),
+}
+
+impl From<KeyManagementError> for ServerError {
+    fn from(e: KeyManagementError) -> Self {
+        ServerError::Store(e.to_string())
+    }
+}
+
+/// public key published for a sha1, with the publisher signature if any
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishedKey {
+    pub public_key: Vec<u8>,
+    pub signature: Option<Vec<u8>>,
+}
+
+/// storage of the published keys
+pub trait KeyRepository: Send + Sync {
+    /// sha1 of the published keys, sorted
+    fn list(&self) -> Result<Vec<String>, ServerError>;
+
+    fn get(&self, sha1: &str) -> Result<Option<PublishedKey>, ServerError>;
+
+    fn put(&self, sha1: &str, key: &PublishedKey) -> Result<(), ServerError>;
+
+    /// add the signature of a key published without signature
+    fn put_signature(&self, sha1: &str, signature: &[u8]) -> Result<(), ServerError>;
+}
+
+/// keys stored in a folder, with the layout of the key server and of the key folders
+pub struct DirectoryRepository {
+    root: PathBuf,
+}
+
+impl DirectoryRepository {
+    pub fn new<P: AsRef<Path>>(root: P) -> DirectoryRepository {
+        DirectoryRepository {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+}
+
+/// content of a file, `None` when it does not exist
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>, ServerError> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl KeyRepository for DirectoryRepository {
+    fn list(&self) -> Result<Vec<String>, ServerError> {
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        let mut sha1 = vec![];
+        for entry in entries {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str() {
+                if check_sha1(name).is_ok() && entry.path().join("public.key.pem").is_file() {
+                    sha1.push(name.to_lowercase());
+                }
+            }
+        }
+        sha1.sort();
+        Ok(sha1)
+    }
+
+    fn get(&self, sha1: &str) -> Result<Option<PublishedKey>, ServerError> {
+        let folder = self.root.join(sha1);
+        Ok(match read_optional(&folder.join("public.key.pem"))? {
+            Some(public_key) => Some(PublishedKey {
+                public_key,
+                signature: read_optional(&folder.join("public.key.sig"))?,
+            }),
+            None => None,
+        })
+    }
+
+    fn put(&self, sha1: &str, key: &PublishedKey) -> Result<(), ServerError> {
+        let folder = self.root.join(sha1);
+        fs::create_dir_all(&folder)?;
+        if let Some(signature) = &key.signature {
+            fs::write(folder.join("public.key.sig"), signature)?;
+        }
+        fs::write(folder.join("public.key.pem"), &key.public_key)?;
+        Ok(())
+    }
+
+    fn put_signature(&self, sha1: &str, signature: &[u8]) -> Result<(), ServerError> {
+        fs::write(self.root.join(sha1).join("public.key.sig"), signature)?;
+        Ok(())
+    }
+}
+
+/// keys of the key database, the database is opened for each request
+/// as the connection can't be shared between threads.
+/// The signatures are not stored, the served keys are signed by the signing key of the server.
+/// The database is protected by its integrity key, the records changed or added
+/// outside of the application are not served
+pub struct StoreRepository {
+    path: PathBuf,
+}
+
+impl StoreRepository {
+    pub fn new<P: AsRef<Path>>(path: P) -> StoreRepository {
+        StoreRepository {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn open(&self) -> Result<IntegrityKeyStore, ServerError> {
+        open_protected(&self.path).map_err(|e| ServerError::Store(e.to_string()))
+    }
+}
+
+impl KeyRepository for StoreRepository {
+    fn list(&self) -> Result<Vec<String>, ServerError> {
+        let store = self.open()?;
+        let mut sha1: Vec<String> = store
+            .get_all()?
+            .into_iter()
+            .filter(|k| k.public_key.is_some() && !store.verify_integrity(k).is_altered())
+            .map(|k| k.sha1.to_lowercase())
+            .collect();
+        sha1.sort();
+        Ok(sha1)
+    }
+
+    fn get(&self, sha1: &str) -> Result<Option<PublishedKey>, ServerError> {
+        let store = self.open()?;
+        match store.get_by_sha1(sha1) {
+            Ok(k) if store.verify_integrity(&k).is_altered() => {
+                Err(KeyManagementError::Tampered(sha1.into()).into())
+            }
+            Ok(k) => Ok(k.public_key.map(|public_key| PublishedKey {
+                public_key,
+                signature: None,
+            })),
+            Err(KeyManagementError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn put(&self, sha1: &str, key: &PublishedKey) -> Result<(), ServerError> {
+        let inserted = self.open()?.insert(&Key {
+            name: sha1.into(),
+            sha1: sha1.into(),
+            public_key: Some(key.public_key.clone()),
+            source: KeySource::Downloaded,
+            ..Default::default()
+        })?;
+        match inserted {
+            KeyInsertion::ChangePending => Err(ServerError::Store(format!(
+                "another key is stored for {}",
+                sha1
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// the signatures are not stored, the keys are signed when served
+    fn put_signature(&self, _sha1: &str, _signature: &[u8]) -> Result<(), ServerError> {
+        Ok(())
+    }
+}
+
+/// who may upload keys, and how the served keys are signed
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    /// api tokens accepted in the `Authorization: Bearer` header
+    pub tokens: Vec<String>,
+    /// publishers whose signature of the key authenticates an upload
+    pub publishers: Vec<PublisherKey>,
+    /// private key signing the keys published without signature
+    pub signing_key: Option<PKey<Private>>,
+}
+
+impl ServerConfig {
+    /// tokens given by [`SERVER_TOKENS_ENV`]
+    pub fn tokens_from_env() -> Vec<String> {
+        std::env::var(SERVER_TOKENS_ENV)
+            .unwrap_or_default()
+            .split(';')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// the keys uploaded with a token are published with the signature of the server
+    pub fn check(&self) -> Result<(), ServerError> {
+        match self.tokens.is_empty() || self.signing_key.is_some() {
+            true => Ok(()),
+            false => Err(ServerError::Config(
+                "the api tokens need a signing key".into(),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16, body: &[u8]) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: body.to_vec(),
+        }
+    }
+
+    fn text(status: u16, text: &str) -> Response {
+        Response::new(status, format!("{}\n", text).as_bytes())
+    }
+
+    fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// entity tag of a content
+fn etag(content: &[u8]) -> String {
+    format!("\"{}\"", sha256_hex(content))
+}
+
+/// the `If-None-Match` header lists the tag, or is `*`
+fn matches_etag(if_none_match: &str, tag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|t| t.trim().trim_start_matches("W/"))
+        .any(|t| t == "*" || t == tag)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+pub struct KeyServer<R: KeyRepository> {
+    repository: R,
+    config: ServerConfig,
+}
+
+impl<R: KeyRepository> KeyServer<R> {
+    /// refuses a configuration with api tokens and no signing key
+    pub fn new(repository: R, config: ServerConfig) -> Result<KeyServer<R>, ServerError> {
+        config.check()?;
+        Ok(KeyServer { repository, config })
+    }
+
+    /// answer a request, the successful `GET` carry an entity tag
+    pub fn handle(&self, request: &Request) -> Response {
+        let response = self.route(request);
+        if request.method != "GET" || response.status != 200 {
+            return response;
+        }
+        let tag = etag(&response.body);
+        match request.header("if-none-match") {
+            Some(if_none_match) if matches_etag(if_none_match, &tag) => {
+                Response::new(304, b"").with_header("ETag", &tag)
+            }
+            _ => response.with_header("ETag", &tag),
+        }
+    }
+
+    fn route(&self, request: &Request) -> Response {
+        let path = request.path.split('?').next().unwrap_or("");
+        if path == KEY_PREFIX || path == KEY_PREFIX.trim_end_matches('/') {
+            return match request.method.as_str() {
+                "GET" => self.list(),
+                _ => Ok(Response::text(405, "method not allowed")),
+            }
+            .unwrap_or_else(|e| self.internal_error(request, e));
+        }
+        let (sha1, file) = match path
+            .strip_prefix(KEY_PREFIX)
+            .and_then(|p| p.split_once('/'))
+        {
+            // checked to keep the paths in the repository
+            Some((sha1, file)) if check_sha1(sha1).is_ok() => (sha1.to_lowercase(), file),
+            _ => return Response::text(404, "not found"),
+        };
+        let result = match (request.method.as_str(), file) {
+            ("GET", "public.key.pem") => self.get(&sha1, false),
+            ("GET", "public.key.sig") => self.get(&sha1, true),
+            ("PUT", "public.key.pem") => self.upload(&sha1, request),
+            (_, "public.key.pem") | (_, "public.key.sig") => {
+                Ok(Response::text(405, "method not allowed"))
+            }
+            _ => Ok(Response::text(404, "not found")),
+        };
+        result.unwrap_or_else(|e| self.internal_error(request, e))
+    }
+
+    fn internal_error(&self, request: &Request, e: ServerError) -> Response {
+        error!("{} {} : {}", request.method, request.path, e);
+        Response::text(500, "internal error")
+    }
+
+    fn list(&self) -> Result<Response, ServerError> {
+        let list = serde_json::to_vec(&self.repository.list()?)
+            .map_err(|e| ServerError::Store(e.to_string()))?;
+        Ok(Response::new(200, &list).with_header("Content-Type", "application/json"))
+    }
+
+    /// signature of the key, the stored one or made with the signing key
+    fn signature(&self, sha1: &str, key: &PublishedKey) -> Result<Option<Vec<u8>>, ServerError> {
+        Ok(match (&key.signature, &self.config.signing_key) {
+            (Some(signature), _) => Some(signature.clone()),
+            (None, Some(signing_key)) => Some(
+                sign_key(signing_key, sha1, &key.public_key)
+                    .map_err(|e| ServerError::Signing(e.to_string()))?
+                    .into_bytes(),
+            ),
+            (None, None) => None,
+        })
+    }
+
+    fn get(&self, sha1: &str, signature_file: bool) -> Result<Response, ServerError> {
+        let key = match self.repository.get(sha1)? {
+            Some(key) => key,
+            None => return Ok(Response::text(404, "not found")),
+        };
+        let signature = self.signature(sha1, &key)?;
+        Ok(match (signature_file, signature) {
+            (true, Some(signature)) => Response::new(200, &signature),
+            (true, None) => Response::text(404, "not found"),
+            (false, Some(signature)) => Response::new(200, &key.public_key)
+                .with_header(SIGNATURE_HEADER, &String::from_utf8_lossy(&signature)),
+            (false, None) => Response::new(200, &key.public_key),
+        })
+    }
+
+    /// signature published with the key, `None` for a token,
+    /// `Err` with the response when the upload is refused
+    fn authenticate(&self, sha1: &str, request: &Request) -> Result<Option<Vec<u8>>, Response> {
+        if let Some(token) = request
+            .header("authorization")
+            .and_then(|v| v.strip_prefix("Bearer "))
+        {
+            let token = token.trim().as_bytes();
+            return match self
+                .config
+                .tokens
+                .iter()
+                .any(|t| t.len() == token.len() && memcmp::eq(t.as_bytes(), token))
+            {
+                true => Ok(None),
+                false => Err(Response::text(403, "invalid token")),
+            };
+        }
+        if let Some(signature) = request.header(SIGNATURE_HEADER) {
+            let signature = signature.trim().as_bytes();
+            return match self
+                .config
+                .publishers
+                .iter()
+                .any(|p| p.verify(sha1, &request.body, signature).is_ok())
+            {
+                true => Ok(Some(signature.to_vec())),
+                false => Err(Response::text(403, "invalid signature")),
+            };
+        }
+        Err(Response::text(401, "authentication required"))
+    }
+
+    fn upload(&self, sha1: &str, request: &Request) -> Result<Response, ServerError> {
+        if let Err(e) = check_public_key(&request.body) {
+            return Ok(Response::text(400, &format!("invalid public key : {}", e)));
+        }
+        let signature = match self.authenticate(sha1, request) {
+            Ok(signature) => signature,
+            Err(response) => {
+                warn!("upload of {} refused : {}", sha1, response.status);
+                return Ok(response);
+            }
+        };
+        match self.repository.get(sha1)? {
+            Some(stored) if stored.public_key != request.body => {
+                Ok(Response::text(409, "another key is published"))
+            }
+            Some(stored) => {
+                // the stored signature is kept, a missing one is added
+                if stored.signature.is_none() {
+                    let key = PublishedKey {
+                        signature,
+                        ..stored
+                    };
+                    if let Some(signature) = self.signature(sha1, &key)? {
+                        self.repository.put_signature(sha1, &signature)?;
+                        info!("signature of {} stored", sha1);
+                    }
+                }
+                Ok(Response::text(200, "unchanged"))
+            }
+            None => {
+                let mut key = PublishedKey {
+                    public_key: request.body.clone(),
+                    signature,
+                };
+                // uploaded with a token, signed by the server
+                key.signature = self.signature(sha1, &key)?;
+                self.repository.put(sha1, &key)?;
+                info!("public key {} published", sha1);
+                Ok(Response::text(201, "created"))
+            }
+        }
+    }
+
+    /// answer the connections of the listener, each in its own thread,
+    /// the accept errors are logged and the server goes on
+    pub fn serve(&self, listener: TcpListener) {
+        // free connection slots
+        let (release, slots) = mpsc::sync_channel(MAX_CONNECTIONS);
+        for _ in 0..MAX_CONNECTIONS {
+            let _ = release.send(());
+        }
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("accept error : {}", e);
+                        thread::sleep(ACCEPT_RETRY);
+                        continue;
+                    }
+                };
+                let _ = slots.recv();
+                let release = release.clone();
+                scope.spawn(move || {
+                    if let Err(e) = self.answer(stream) {
+                        warn!("connection error : {}", e);
+                    }
+                    let _ = release.send(());
+                });
+            }
+        });
+    }
+
+    fn answer(&self, mut stream: TcpStream) -> Result<(), ServerError> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let response = match read_request(&mut stream) {
+            Ok(request) => self.handle(&request),
+            Err(ServerError::BadRequest(e)) if e == "body too large" => {
+                Response::text(413, "payload too large")
+            }
+            Err(ServerError::LineTooLong) => Response::text(431, "line too long"),
+            Err(ServerError::BadRequest(e)) => Response::text(400, &e),
+            Err(e) => return Err(e),
+        };
+        write_response(&mut stream, &response)?;
+        Ok(())
+    }
+}
+
+impl<R: KeyRepository + 'static> KeyServer<R> {
+    /// serve in a background thread on the address, `127.0.0.1:0` picks a free port,
+    /// returns the address listened
+    pub fn spawn(self, address: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        thread::spawn(move || self.serve(listener));
+        Ok(address)
+    }
+}
+
+/// read a line of at most [`MAX_LINE`] bytes
+fn read_line<B: BufRead>(reader: &mut B, line: &mut String) -> Result<(), ServerError> {
+    line.clear();
+    let read = reader.by_ref().take(MAX_LINE).read_line(line)?;
+    if read as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(ServerError::LineTooLong);
+    }
+    Ok(())
+}
+
+/// read a http request, the body is read up to its content length
+pub fn read_request(stream: &mut TcpStream) -> Result<Request, ServerError> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(ServerError::BadRequest("invalid request line".into())),
+    };
+
+    let mut headers = vec![];
+    loop {
+        read_line(&mut reader, &mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADER_LINES {
+            return Err(ServerError::BadRequest("too many headers".into()));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+
+    let length = headers
+        .iter()
+        .find(|(n, _)| n == "content-length")
+        .map(|(_, v)| v.parse::<usize>())
+        .transpose()
+        .map_err(|_| ServerError::BadRequest("invalid content length".into()))?
+        .unwrap_or(0);
+    if length > MAX_BODY_SIZE {
+        return Err(ServerError::BadRequest("body too large".into()));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+pub fn write_response(stream: &mut TcpStream, response: &Response) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (name, value) in response.headers.iter() {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    head += &format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
@@ -0,0 +1,94 @@
+//! revocation lists, the keys of instruments sold, stolen or re-keyed
+//!
+//! a revocation list is a json array of `{"sha1", "revoked_at", "reason"}`,
+//! read from a file or downloaded from the key server. A downloaded list is only
+//! applied when its signature, `revocations.json.sig`, is valid for the publisher key
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use log::info;
+
+use crate::key_signature::KeySignatureError;
+use crate::keys_management::{check_sha1, KeyManagementError, KeyStore, Revocation};
+use crate::keyserver::{KeyServerClient, KeyServerError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum RevocationError {
+    #[error("erreur de lecture : {0}")]
+    Io(#[from] io::Error),
+    #[error("liste de révocation invalide : {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("erreur dans le téléchargement de la liste de révocation : {0}")]
+    Download(#[from] KeyServerError),
+    #[error("la liste de révocation n'est pas signée par l'éditeur")]
+    Unsigned,
+    #[error(transparent)]
+    Signature(#[from] KeySignatureError),
+    #[error(transparent)]
+    Key(#[from] KeyManagementError),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RevocationReport {
+    /// stored keys revoked by the list
+    pub revoked: usize,
+    pub already_revoked: usize,
+    /// listed keys that are not stored
+    pub unknown: usize,
+}
+
+/// parse a revocation list, the sha1 of the entries are checked
+pub fn parse_revocation_list(content: &str) -> Result<Vec<Revocation>, RevocationError> {
+    let revocations: Vec<Revocation> = serde_json::from_str(content)?;
+    for r in revocations.iter() {
+        check_sha1(&r.sha1)?;
+    }
+    Ok(revocations)
+}
+
+pub fn read_revocation_list(path: &Path) -> Result<Vec<Revocation>, RevocationError> {
+    parse_revocation_list(&fs::read_to_string(path)?)
+}
+
+/// download the revocation list published by the key server,
+/// the list is checked to be signed by the publisher
+pub fn fetch_revocation_list(server: &KeyServerClient) -> Result<Vec<Revocation>, RevocationError> {
+    let content = server.get(&server.revocation_list_url())?;
+    let signature = match server.get(&server.revocation_signature_url()) {
+        Ok(signature) => signature,
+        Err(KeyServerError::Status(404)) => return Err(RevocationError::Unsigned),
+        Err(e) => return Err(e.into()),
+    };
+    server
+        .publisher()
+        .verify_revocation_list(&content, &signature)?;
+    parse_revocation_list(&String::from_utf8_lossy(&content))
+}
+
+/// revoke the stored keys of the list, a key already revoked keeps its first revocation
+pub fn apply_revocations(
+    db: &dyn KeyStore,
+    revocations: &[Revocation],
+) -> Result<RevocationReport, RevocationError> {
+    let mut report = RevocationReport::default();
+    for r in revocations {
+        let k = match db.get_by_sha1(&r.sha1) {
+            Ok(k) => k,
+            Err(KeyManagementError::NotFound(_)) => {
+                report.unknown += 1;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if k.revoked_at.is_some() {
+            report.already_revoked += 1;
+        } else {
+            info!("revoking key {}", k);
+            db.revoke(r)?;
+            report.revoked += 1;
+        }
+    }
+    Ok(report)
+}
@@ -0,0 +1,445 @@
+//! watch of the expanded folders of the tree, to refresh them when files are
+//! added, removed or written by other programs
+//!
+//! inotify is used on linux, other systems (or when inotify can't be used)
+//! compare the content of the folders at a regular interval.
+//! With inotify a background thread wakes the interface when events arrive,
+//! see [`FolderWatcher::wake_on_change`]
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, warn};
+
+/// interval between two reads of the folders, when polling
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// content of a folder, names and types of the entries, and modification times of the files
+type Snapshot = Vec<(std::ffi::OsString, bool, Option<SystemTime>)>;
+
+fn snapshot(folder: &Path) -> Option<Snapshot> {
+    let mut content: Snapshot = fs::read_dir(folder)
+        .ok()?
+        .flatten()
+        .map(|e| {
+            let is_dir = e.path().is_dir();
+            // the content of the sub folders is watched on its own
+            let modified = match is_dir {
+                true => None,
+                false => e.metadata().and_then(|m| m.modified()).ok(),
+            };
+            (e.file_name(), is_dir, modified)
+        })
+        .collect();
+    content.sort();
+    Some(content)
+}
+
+/// folders compared at a regular interval
+struct Polling {
+    interval: Duration,
+    last_poll: Option<Instant>,
+    snapshots: HashMap<PathBuf, Option<Snapshot>>,
+}
+
+impl Polling {
+    fn watch(&mut self, folder: &Path) {
+        self.snapshots
+            .insert(folder.to_path_buf(), snapshot(folder));
+    }
+
+    fn changed(&mut self) -> Vec<PathBuf> {
+        if self
+            .last_poll
+            .map_or(false, |t| t.elapsed() < self.interval)
+        {
+            return vec![];
+        }
+        self.last_poll = Some(Instant::now());
+        let mut changed = vec![];
+        for (folder, previous) in self.snapshots.iter_mut() {
+            let current = snapshot(folder);
+            if current != *previous {
+                *previous = current;
+                changed.push(folder.clone());
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::io;
+    use std::mem::size_of;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+    use log::warn;
+
+    // a file written in place is reported once it is closed
+    const MASK: u32 = libc::IN_CREATE
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_DELETE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF
+        | libc::IN_ONLYDIR;
+
+    /// the same folder given by several paths (links) has a single watch,
+    /// it is removed with the last path
+    pub struct Inotify {
+        fd: i32,
+        folders: HashMap<i32, Vec<PathBuf>>,
+        waker: Option<Waker>,
+    }
+
+    /// thread waiting for the events, it stops when the pipe is closed
+    struct Waker {
+        stop: i32,
+        /// the events were read, the thread waits for the next ones
+        read: SyncSender<()>,
+    }
+
+    impl Drop for Waker {
+        fn drop(&mut self) {
+            // SAFETY: the write end of the pipe is owned by the waker
+            unsafe { libc::close(self.stop) };
+        }
+    }
+
+    /// wait for the events on `fd`, calling `wake` once until they are read
+    fn wait_events(fd: i32, stop: i32, read: Receiver<()>, wake: Box<dyn Fn() + Send>) {
+        let mut fds = [
+            libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: stop,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            // SAFETY: the descriptors are valid for the length given
+            let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+            if ready < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                warn!(
+                    "fail to wait for the folder events : {}",
+                    io::Error::last_os_error()
+                );
+                break;
+            }
+            if fds[1].revents != 0 {
+                break;
+            }
+            if fds[0].revents & libc::POLLIN != 0 {
+                wake();
+                if read.recv().is_err() {
+                    break;
+                }
+            }
+        }
+        // SAFETY: the duplicated descriptor and the read end of the pipe are owned by the thread
+        unsafe {
+            libc::close(fd);
+            libc::close(stop);
+        }
+    }
+
+    impl Inotify {
+        pub fn new() -> io::Result<Inotify> {
+            // SAFETY: no pointer is given, the descriptor is owned by the watcher
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Inotify {
+                fd,
+                folders: HashMap::new(),
+                waker: None,
+            })
+        }
+
+        /// call `wake` from a background thread when events are waiting to be read
+        pub fn wake_on_change(&mut self, wake: Box<dyn Fn() + Send>) -> io::Result<()> {
+            let mut pipe = [0; 2];
+            // SAFETY: the array holds the two descriptors of the pipe
+            if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // the thread has its own descriptor, closed when it stops
+            // SAFETY: the descriptor is owned by the watcher
+            let fd = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+            if fd < 0 {
+                let e = io::Error::last_os_error();
+                // SAFETY: the pipe was just created
+                unsafe {
+                    libc::close(pipe[0]);
+                    libc::close(pipe[1]);
+                }
+                return Err(e);
+            }
+            let (read, wait_read) = sync_channel(1);
+            std::thread::spawn(move || wait_events(fd, pipe[0], wait_read, wake));
+            self.waker = Some(Waker {
+                stop: pipe[1],
+                read,
+            });
+            Ok(())
+        }
+
+        pub fn wakes(&self) -> bool {
+            self.waker.is_some()
+        }
+
+        pub fn watch(&mut self, folder: &Path) -> io::Result<i32> {
+            let path = CString::new(folder.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            // SAFETY: the path is a valid nul terminated string
+            let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), MASK) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.folders
+                .entry(wd)
+                .or_default()
+                .push(folder.to_path_buf());
+            Ok(wd)
+        }
+
+        pub fn unwatch(&mut self, wd: i32, folder: &Path) {
+            let paths = match self.folders.get_mut(&wd) {
+                Some(paths) => paths,
+                None => return,
+            };
+            if let Some(i) = paths.iter().position(|p| p == folder) {
+                paths.remove(i);
+            }
+            if paths.is_empty() {
+                self.folders.remove(&wd);
+                // SAFETY: the watch was added on this descriptor
+                unsafe { libc::inotify_rm_watch(self.fd, wd) };
+            }
+        }
+
+        /// folders having events since the last read, all the folders when
+        /// the event queue has overflowed, and the folders no longer watched
+        /// as they were removed or moved
+        pub fn changed(&mut self) -> (Vec<PathBuf>, Vec<PathBuf>) {
+            let mut changed = vec![];
+            let mut ignored = vec![];
+            let mut buffer = [0_u8; 4096];
+            loop {
+                // SAFETY: the buffer is valid for its length
+                let read = unsafe {
+                    libc::read(
+                        self.fd,
+                        buffer.as_mut_ptr() as *mut libc::c_void,
+                        buffer.len(),
+                    )
+                };
+                if read <= 0 {
+                    break;
+                }
+                if let Some(waker) = &self.waker {
+                    // the thread may already be waiting, full
+                    let _ = waker.read.try_send(());
+                }
+                let mut offset = 0;
+                while offset + size_of::<libc::inotify_event>() <= read as usize {
+                    // SAFETY: the kernel writes whole events, the header is in the buffer
+                    let event: libc::inotify_event = unsafe {
+                        std::ptr::read_unaligned(
+                            buffer[offset..].as_ptr() as *const libc::inotify_event
+                        )
+                    };
+                    offset += size_of::<libc::inotify_event>() + event.len as usize;
+                    if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                        changed.extend(self.folders.values().flatten().cloned());
+                        continue;
+                    }
+                    if let Some(paths) = self.folders.get(&event.wd) {
+                        changed.extend(paths.iter().cloned());
+                    }
+                    if event.mask & libc::IN_IGNORED != 0 {
+                        // the folder is removed, or moved
+                        ignored.extend(self.folders.remove(&event.wd).unwrap_or_default());
+                    }
+                }
+            }
+            (changed, ignored)
+        }
+    }
+
+    impl Drop for Inotify {
+        fn drop(&mut self) {
+            // stops the thread
+            self.waker = None;
+            // SAFETY: the descriptor is owned by the watcher
+            unsafe { libc::close(self.fd) };
+        }
+    }
+}
+
+enum Backend {
+    #[cfg(target_os = "linux")]
+    Inotify(inotify::Inotify, HashMap<PathBuf, i32>),
+    Polling(Polling),
+}
+
+/// watch of a set of folders, not recursive
+pub struct FolderWatcher {
+    backend: Backend,
+    watched: Vec<PathBuf>,
+    /// the waking thread is only started once
+    wake_requested: bool,
+}
+
+impl Default for FolderWatcher {
+    fn default() -> Self {
+        FolderWatcher::new()
+    }
+}
+
+impl FolderWatcher {
+    /// inotify on linux, polling otherwise
+    pub fn new() -> FolderWatcher {
+        #[cfg(target_os = "linux")]
+        match inotify::Inotify::new() {
+            Ok(inotify) => {
+                return FolderWatcher {
+                    backend: Backend::Inotify(inotify, HashMap::new()),
+                    watched: vec![],
+                    wake_requested: false,
+                }
+            }
+            Err(e) => warn!("inotify can't be used, the folders are polled : {}", e),
+        }
+        FolderWatcher::polling(POLL_INTERVAL)
+    }
+
+    /// compare the content of the folders at most every `interval`
+    pub fn polling(interval: Duration) -> FolderWatcher {
+        FolderWatcher {
+            backend: Backend::Polling(Polling {
+                interval,
+                last_poll: None,
+                snapshots: HashMap::new(),
+            }),
+            watched: vec![],
+            wake_requested: false,
+        }
+    }
+
+    /// call `wake` from a background thread when a watched folder changes,
+    /// only the first call starts the thread. The polling backend can't,
+    /// see [`FolderWatcher::needs_polling`]
+    pub fn wake_on_change<F: Fn() + Send + 'static>(&mut self, wake: F) {
+        if self.wake_requested {
+            return;
+        }
+        self.wake_requested = true;
+        match &mut self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Inotify(inotify, _) => {
+                if let Err(e) = inotify.wake_on_change(Box::new(wake)) {
+                    warn!("the folder events can't wake the interface : {}", e);
+                }
+            }
+            // read at a regular interval instead
+            Backend::Polling(_) => drop(wake),
+        }
+    }
+
+    /// whether [`FolderWatcher::changed`] must be called at a regular interval
+    /// to see the changes, the watcher does not wake the interface
+    pub fn needs_polling(&self) -> bool {
+        match &self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Inotify(inotify, _) => !inotify.wakes(),
+            Backend::Polling(_) => true,
+        }
+    }
+
+    pub fn watched(&self) -> &[PathBuf] {
+        &self.watched
+    }
+
+    /// watch these folders, and only these ones
+    pub fn watch(&mut self, folders: &[PathBuf]) {
+        if self.watched == folders {
+            return;
+        }
+        for folder in self.watched.iter().filter(|f| !folders.contains(f)) {
+            debug!("unwatch {}", folder.display());
+            match &mut self.backend {
+                #[cfg(target_os = "linux")]
+                Backend::Inotify(inotify, watches) => {
+                    if let Some(wd) = watches.remove(folder) {
+                        inotify.unwatch(wd, folder);
+                    }
+                }
+                Backend::Polling(polling) => {
+                    polling.snapshots.remove(folder);
+                }
+            }
+        }
+        let added: Vec<&PathBuf> = folders
+            .iter()
+            .filter(|f| !self.watched.contains(f))
+            .collect();
+        for folder in added {
+            debug!("watch {}", folder.display());
+            if let Err(e) = self.add(folder) {
+                warn!("{} can't be watched : {}", folder.display(), e);
+            }
+        }
+        self.watched = folders.to_vec();
+    }
+
+    fn add(&mut self, folder: &Path) -> io::Result<()> {
+        match &mut self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Inotify(inotify, watches) => {
+                watches.insert(folder.to_path_buf(), inotify.watch(folder)?);
+            }
+            Backend::Polling(polling) => polling.watch(folder),
+        }
+        Ok(())
+    }
+
+    /// watched folders whose content changed since the last call, does not block.
+    /// A removed or moved folder is no longer watched, it is watched again
+    /// by the next [`FolderWatcher::watch`] listing it
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        let mut changed = match &mut self.backend {
+            #[cfg(target_os = "linux")]
+            Backend::Inotify(inotify, watches) => {
+                let (changed, ignored) = inotify.changed();
+                for folder in ignored.iter() {
+                    debug!("{} is no longer watched", folder.display());
+                    watches.remove(folder);
+                }
+                self.watched.retain(|f| !ignored.contains(f));
+                changed
+            }
+            Backend::Polling(polling) => polling.changed(),
+        };
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+}
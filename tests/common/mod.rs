@@ -0,0 +1,104 @@
+//! helpers shared by the integration tests, their temporary folders, a stand-in
+//! of the key server and the signatures of the test publisher
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use encrypter::key_signature::*;
+use encrypter::keyserver::*;
+use openssl::pkey::{PKey, Private};
+
+pub const PUBLIC_KEY: &[u8] = include_bytes!("../../test_public.key.pem");
+
+/// empty folder of a test, unique to the test run so that two runs do not share it
+pub fn temp_folder(name: &str) -> PathBuf {
+    let folder = std::env::temp_dir()
+        .join(format!("encrypter_tests_{}", std::process::id()))
+        .join(name);
+    let _ = fs::remove_dir_all(&folder);
+    fs::create_dir_all(&folder).unwrap();
+    folder
+}
+
+/// private key of the test publisher
+pub fn publisher_private_key() -> PKey<Private> {
+    PKey::private_key_from_pem(include_bytes!("../fixtures/publisher_test.key.pem")).unwrap()
+}
+
+/// signature of the test publisher
+pub fn sign(sha1: &str, public_key: &[u8]) -> Vec<u8> {
+    sign_key(&publisher_private_key(), sha1, public_key)
+        .unwrap()
+        .into_bytes()
+}
+
+/// published key and signature, for the path
+pub fn published(path: &str, sha1: &str, public_key: &[u8], signature: &[u8]) -> (u16, Vec<u8>) {
+    if path == format!("/k/{}/public.key.pem", sha1) {
+        (200, public_key.to_vec())
+    } else if path == format!("/k/{}/public.key.sig", sha1) {
+        (200, signature.to_vec())
+    } else {
+        (404, vec![])
+    }
+}
+
+/// stand-in of the key server, `answer` gives the status and content
+/// for the path and the number of the request (starting at 0),
+/// returns the base url and the count of the received requests
+pub fn serve<F>(answer: F) -> (String, Arc<AtomicUsize>)
+where
+    F: Fn(&str, usize) -> (u16, Vec<u8>) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}/k", listener.local_addr().unwrap());
+    let count = Arc::new(AtomicUsize::new(0));
+    let received = count.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            // skip the headers
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let path = request_line.split_whitespace().nth(1).unwrap_or("");
+            let (status, content) = answer(path, received.fetch_add(1, Ordering::SeqCst));
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                content.len()
+            );
+            let _ = stream.write_all(&content);
+        }
+    });
+    (base_url, count)
+}
+
+/// key of the test publisher, checking the signatures made by [`sign`]
+pub fn publisher() -> PublisherKey {
+    PublisherKey::from_pem(include_bytes!("../fixtures/publisher_test.pub.pem")).unwrap()
+}
+
+/// client of a stand-in server, trusting the test publisher
+pub fn client(base_url: &str) -> KeyServerClient {
+    KeyServerClient::new(base_url)
+        .with_timeout(Duration::from_secs(5))
+        .with_retry(RetryPolicy {
+            attempts: 3,
+            delay: Duration::from_millis(10),
+        })
+        .with_publisher_key(publisher())
+}
@@ -0,0 +1,9 @@
+-- key database as created before schema versioning (user_version = 0)
+CREATE TABLE all_keys (
+    name TEXT NOT NULL,
+    sha1 TEXT NOT NULL PRIMARY KEY,
+    public_key BLOB
+);
+INSERT INTO all_keys (name, sha1, public_key) VALUES
+    ('martin', '30d9690cc085429a1d0a3ae787932bf1518a1798', X'68656c6c6f'),
+    ('orgue de barbarie', '0123456789abcdef0123456789abcdef01234567', NULL);
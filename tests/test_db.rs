@@ -3,23 +3,520 @@
 mod test_db {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
 
+    use encrypter::integrity::{IntegrityKey, IntegrityKeyStore};
     use encrypter::keys_management::*;
 
     #[test]
     fn test_db_keys() {
-        let d = Database::open_database().expect("fail to open database");
+        let d = Database::open_in_memory().expect("fail to open database");
 
         let k = Key {
             rowid: 0,
             name: "hello".into(),
             sha1: "kk".into(),
             public_key: Some("hello".as_bytes().to_vec()),
+            ..Default::default()
         };
 
+        assert!(matches!(
+            d.insert(&k),
+            Err(KeyManagementError::InvalidSha1(_))
+        ));
+
+        let k = Key {
+            sha1: "30d9690cc085429a1d0a3ae787932bf1518a1798".into(),
+            ..k
+        };
         d.insert(&k).expect("fail to insert key");
 
-        while let Ok(k) = d.get_all() {
+        let keys = d.get_all().expect("fail to get keys");
+        for k in keys.iter() {
             println!("key : {:?}", k);
         }
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].sha1, k.sha1);
+        assert_eq!(keys[0].public_key, k.public_key);
+    }
+
+    #[test]
+    fn test_db_location() {
+        let folder = std::env::temp_dir().join("encrypter_test_db_location");
+        let _ = std::fs::remove_dir_all(&folder);
+        let db_path = folder.join("sub").join("keys.db");
+
+        let d = Database::open(&db_path).expect("fail to open database");
+        assert_eq!(d.path(), Some(db_path.as_path()));
+        assert!(db_path.exists());
+
+        assert!(Database::open_in_memory().unwrap().path().is_none());
+
+        if std::env::var_os(DATABASE_PATH_ENV).is_none() {
+            assert_eq!(resolve_database_path(Some(&db_path)), db_path);
+            assert_eq!(resolve_database_path(None), default_database_path());
+        }
+
+        // the database of the previous versions is kept
+        let legacy = folder.join("keys.db");
+        let default = folder.join("data").join("keys.db");
+        assert_eq!(with_legacy_database(default.clone(), &legacy), default);
+        std::fs::write(&legacy, b"").unwrap();
+        assert_eq!(with_legacy_database(default.clone(), &legacy), legacy);
+        std::fs::create_dir_all(default.parent().unwrap()).unwrap();
+        std::fs::write(&default, b"").unwrap();
+        assert_eq!(with_legacy_database(default.clone(), &legacy), default);
+
+        drop(d);
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    fn fixture_database(name: &str, script: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("encrypter_fixture_{}.db", name));
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).expect("fail to create fixture");
+        conn.execute_batch(script).expect("fail to load fixture");
+        path
+    }
+
+    #[test]
+    fn test_db_migration_from_v0() {
+        let path = fixture_database("v0", include_str!("fixtures/keys_v0.sql"));
+
+        let d = Database::open(&path).expect("fail to migrate database");
+        assert_eq!(d.schema_version().unwrap(), SCHEMA_VERSION);
+
+        let keys = d.get_all().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().any(|k| k.name == "martin"
+            && k.sha1 == "30d9690cc085429a1d0a3ae787932bf1518a1798"
+            && k.public_key == Some(b"hello".to_vec())));
+
+        // the stored public keys start the key history
+        let history = d
+            .get_key_history("30d9690cc085429a1d0a3ae787932bf1518a1798")
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].trust, KeyTrust::Accepted);
+        assert!(d.get_pending_key_changes().unwrap().is_empty());
+
+        // reopening an up to date database is a no-op
+        drop(d);
+        let d = Database::open(&path).expect("fail to reopen database");
+        assert_eq!(d.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(d.get_all().unwrap().len(), 2);
+
+        drop(d);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_db_newer_schema_is_refused() {
+        let script = format!("PRAGMA user_version = {};", SCHEMA_VERSION + 1);
+        let path = fixture_database("newer", &script);
+
+        assert!(Database::open(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    const SHA1_A: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+    const SHA1_B: &str = "30d9ffffffffffffffffffffffffffffffffffff";
+
+    fn key(name: &str, sha1: &str) -> Key {
+        Key {
+            rowid: 0,
+            name: name.into(),
+            sha1: sha1.into(),
+            public_key: Some(include_bytes!("../test_public.key.pem").to_vec()),
+            ..Default::default()
+        }
+    }
+
+    fn check_crud(d: &dyn KeyStore) {
+        d.insert(&key("martin", SHA1_A)).unwrap();
+        d.insert(&key("durand", SHA1_B)).unwrap();
+
+        assert!(matches!(
+            d.insert(&key("short", "kk")),
+            Err(KeyManagementError::InvalidSha1(_))
+        ));
+
+        assert_eq!(d.get_by_sha1(SHA1_A).unwrap().name, "martin");
+        assert!(matches!(
+            d.get_by_sha1("unknown"),
+            Err(KeyManagementError::NotFound(_))
+        ));
+
+        let found = d.find_by_prefix("30d9").unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].sha1, SHA1_A);
+        assert_eq!(d.find_by_prefix("30d9f").unwrap().len(), 1);
+        assert!(d.find_by_prefix("ab").unwrap().is_empty());
+
+        d.rename(SHA1_A, "martin (lyon)").unwrap();
+        assert_eq!(d.get_by_sha1(SHA1_A).unwrap().name, "martin (lyon)");
+        assert!(matches!(
+            d.rename("unknown", "x"),
+            Err(KeyManagementError::NotFound(_))
+        ));
+
+        assert!(matches!(
+            d.update_public_key(SHA1_A, b"not a key"),
+            Err(KeyManagementError::InvalidPublicKey)
+        ));
+        d.update_public_key(SHA1_B, include_bytes!("../test_public.key.pem"))
+            .unwrap();
+
+        d.delete(SHA1_B).unwrap();
+        assert!(matches!(
+            d.delete(SHA1_B),
+            Err(KeyManagementError::NotFound(_))
+        ));
+        assert_eq!(d.get_all().unwrap().len(), 1);
+    }
+
+    fn check_groups(d: &dyn KeyStore) {
+        d.insert(&key("martin", SHA1_A)).unwrap();
+        d.insert(&key("durand", SHA1_B)).unwrap();
+
+        assert!(matches!(
+            d.add_to_group("salon", SHA1_A),
+            Err(KeyManagementError::GroupNotFound(_))
+        ));
+
+        d.create_group("salon").unwrap();
+        d.create_group("distributeur").unwrap();
+        d.create_group("salon").unwrap();
+        assert_eq!(d.get_groups().unwrap(), vec!["distributeur", "salon"]);
+
+        d.add_to_group("salon", SHA1_A).unwrap();
+        d.add_to_group("salon", SHA1_B).unwrap();
+        d.add_to_group("distributeur", SHA1_A).unwrap();
+        assert!(matches!(
+            d.add_to_group("salon", "unknown"),
+            Err(KeyManagementError::NotFound(_))
+        ));
+
+        let members = d.get_group_members("salon").unwrap();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].name, "durand");
+        assert_eq!(
+            d.get_key_groups(SHA1_A).unwrap(),
+            vec!["distributeur", "salon"]
+        );
+
+        d.remove_from_group("salon", SHA1_B).unwrap();
+        assert_eq!(d.get_group_members("salon").unwrap().len(), 1);
+
+        // removing a key or a group removes the memberships
+        d.delete(SHA1_A).unwrap();
+        assert!(d.get_group_members("salon").unwrap().is_empty());
+        d.delete_group("distributeur").unwrap();
+        assert_eq!(d.get_groups().unwrap(), vec!["salon"]);
+        assert!(matches!(
+            d.get_group_members("distributeur"),
+            Err(KeyManagementError::GroupNotFound(_))
+        ));
+    }
+
+    fn check_metadata(d: &dyn KeyStore) {
+        let mut k = key("martin", SHA1_A);
+        k.owner = "Jean Martin, 06 00 00 00 00".into();
+        k.instrument_model = "OR1 serinette".into();
+        k.instrument_serial = "SN-42_b".into();
+        k.source = KeySource::Downloaded;
+        d.insert(&k).unwrap();
+        d.insert(&key("durand", SHA1_B)).unwrap();
+
+        let stored = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(stored.owner, k.owner);
+        assert_eq!(stored.source, KeySource::Downloaded);
+        let added_at = stored.added_at.expect("no recording time");
+        assert!(stored.last_used.is_none());
+
+        // replacing the key keeps the first recording time
+        let mut replaced = stored.clone();
+        replaced.added_at = Some(added_at + 1000);
+        d.insert(&replaced).unwrap();
+        assert_eq!(d.get_by_sha1(SHA1_A).unwrap().added_at, Some(added_at));
+
+        d.mark_used(SHA1_A).unwrap();
+        assert!(d.get_by_sha1(SHA1_A).unwrap().last_used.is_some());
+
+        let mut edited = d.get_by_sha1(SHA1_B).unwrap();
+        edited.notes = "exposition de Lyon".into();
+        d.update_metadata(&edited).unwrap();
+        assert_eq!(d.get_by_sha1(SHA1_B).unwrap().notes, "exposition de Lyon");
+
+        assert_eq!(d.search("martin").unwrap().len(), 1);
+        assert_eq!(d.search("SERINETTE").unwrap()[0].sha1, SHA1_A);
+        assert_eq!(d.search("42_b").unwrap().len(), 1);
+        assert!(d.search("42%").unwrap().is_empty());
+        assert_eq!(d.search("lyon").unwrap()[0].sha1, SHA1_B);
+        assert_eq!(d.search("").unwrap().len(), 2);
+
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1706000000), "2024-01-23 08:53");
+    }
+
+    fn check_audit_log(d: &dyn KeyStore) {
+        let entry = |timestamp: i64, source: &str, key_sha1: &str| AuditEntry {
+            timestamp,
+            source_path: source.into(),
+            source_sha256: "ab".repeat(32),
+            output_path: format!("/out/{}x", source),
+            output_size: 1024,
+            key_sha1: key_sha1.into(),
+            ..Default::default()
+        };
+
+        d.record_encryption(&entry(10, "/music/valse.mid", SHA1_A))
+            .unwrap();
+        d.record_encryption(&entry(20, "/music/polka_1.mid", SHA1_A))
+            .unwrap();
+        let id = d
+            .record_encryption(&entry(30, "/music/valse.mid", SHA1_B))
+            .unwrap();
+
+        let all = d.get_audit_log(&AuditFilter::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].id, id);
+        assert_eq!(all[0].output_size, 1024);
+
+        let for_key = d
+            .get_audit_log(&AuditFilter {
+                key_sha1: Some(SHA1_A.into()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(for_key.len(), 2);
+        assert_eq!(for_key[0].timestamp, 20);
+
+        let for_file = d
+            .get_audit_log(&AuditFilter {
+                path: Some("valse".into()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(for_file.len(), 2);
+
+        let wildcard = d
+            .get_audit_log(&AuditFilter {
+                path: Some("polka_".into()),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(wildcard.len(), 1);
+        assert!(d
+            .get_audit_log(&AuditFilter {
+                path: Some("polka%".into()),
+                ..Default::default()
+            })
+            .unwrap()
+            .is_empty());
+    }
+
+    fn check_key_history(d: &dyn KeyStore) {
+        let first = include_bytes!("../test_public.key.pem").to_vec();
+        let second = b"another public key".to_vec();
+
+        assert_eq!(
+            d.insert(&key("martin", SHA1_A)).unwrap(),
+            KeyInsertion::Added
+        );
+        assert_eq!(
+            d.insert(&key("martin (lyon)", SHA1_A)).unwrap(),
+            KeyInsertion::Updated
+        );
+
+        // a different public key is not stored silently
+        let changed = Key {
+            public_key: Some(second.clone()),
+            ..key("martin", SHA1_A)
+        };
+        assert_eq!(d.insert(&changed).unwrap(), KeyInsertion::ChangePending);
+        assert_eq!(d.insert(&changed).unwrap(), KeyInsertion::ChangePending);
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(k.public_key, Some(first.clone()));
+        assert_eq!(k.name, "martin");
+
+        let pending = d.get_pending_key_changes().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].public_key, second);
+        assert_eq!(d.get_key_history(SHA1_A).unwrap().len(), 2);
+
+        // a missing public key keeps the stored one
+        d.insert(&Key {
+            public_key: None,
+            ..key("martin", SHA1_A)
+        })
+        .unwrap();
+        assert_eq!(
+            d.get_by_sha1(SHA1_A).unwrap().public_key,
+            Some(first.clone())
+        );
+
+        d.reject_public_key(SHA1_A, pending[0].id).unwrap();
+        assert!(d.get_pending_key_changes().unwrap().is_empty());
+        assert!(matches!(
+            d.reject_public_key(SHA1_A, pending[0].id),
+            Err(KeyManagementError::HistoryNotFound(_, _))
+        ));
+        // a rejected key is not proposed again
+        d.insert(&changed).unwrap();
+        assert!(d.get_pending_key_changes().unwrap().is_empty());
+
+        d.accept_public_key(SHA1_A, pending[0].id).unwrap();
+        assert_eq!(d.get_by_sha1(SHA1_A).unwrap().public_key, Some(second));
+
+        // rollback to the first key
+        let history = d.get_key_history(SHA1_A).unwrap();
+        assert!(history.iter().all(|e| e.trust == KeyTrust::Accepted));
+        let previous = history.iter().find(|e| e.public_key == first).unwrap();
+        d.accept_public_key(SHA1_A, previous.id).unwrap();
+        assert_eq!(d.get_by_sha1(SHA1_A).unwrap().public_key, Some(first));
+        assert!(matches!(
+            d.accept_public_key(SHA1_B, previous.id),
+            Err(KeyManagementError::HistoryNotFound(_, _))
+        ));
+
+        // the history stays available once the key is deleted
+        d.delete(SHA1_A).unwrap();
+        assert_eq!(d.get_key_history(SHA1_A).unwrap().len(), 2);
+    }
+
+    fn check_revocation(d: &dyn KeyStore) {
+        d.insert(&key("martin", SHA1_A)).unwrap();
+        let now = unix_time();
+        assert_eq!(
+            d.get_by_sha1(SHA1_A).unwrap().validity(now),
+            KeyValidity::Valid
+        );
+
+        d.set_expiry(SHA1_A, Some(now - 10)).unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(k.validity(now), KeyValidity::Expired);
+        assert_eq!(k.validity(now - 20), KeyValidity::Valid);
+        assert!(k.validity_message(now).unwrap().starts_with("expirée"));
+
+        d.revoke(&Revocation {
+            sha1: SHA1_A.into(),
+            revoked_at: now,
+            reason: "volé".into(),
+        })
+        .unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(k.validity(now), KeyValidity::Revoked);
+        assert!(k.validity_message(now).unwrap().ends_with("volé"));
+
+        // downloading the key again does not clear the revocation
+        d.insert(&key("martin", SHA1_A)).unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(k.revoked_at, Some(now));
+        assert_eq!(k.revocation_reason, "volé");
+        assert_eq!(k.expires_at, Some(now - 10));
+
+        d.reinstate(SHA1_A).unwrap();
+        d.set_expiry(SHA1_A, None).unwrap();
+        assert_eq!(
+            d.get_by_sha1(SHA1_A).unwrap().validity(now),
+            KeyValidity::Valid
+        );
+        assert!(matches!(
+            d.set_expiry(SHA1_B, None),
+            Err(KeyManagementError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_db_dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        let t = parse_date("2026-10-19").unwrap();
+        assert_eq!(format_timestamp(t), "2026-10-19 00:00");
+        assert_eq!(
+            format_timestamp(parse_date("2024-02-29").unwrap()),
+            "2024-02-29 00:00"
+        );
+        assert_eq!(parse_date("2026-13-01"), None);
+        assert_eq!(parse_date("19/10/2026"), None);
+    }
+
+    #[test]
+    fn test_db_crud() {
+        check_crud(&Database::open_in_memory().unwrap());
+        check_crud(&MemoryKeyStore::new());
+        check_crud(&IntegrityKeyStore::new(
+            Box::new(MemoryKeyStore::new()),
+            IntegrityKey::new(b"secret"),
+        ));
+    }
+
+    #[test]
+    fn test_db_groups() {
+        check_groups(&Database::open_in_memory().unwrap());
+        check_groups(&MemoryKeyStore::new());
+    }
+
+    #[test]
+    fn test_db_metadata() {
+        check_metadata(&Database::open_in_memory().unwrap());
+        check_metadata(&MemoryKeyStore::new());
+    }
+
+    #[test]
+    fn test_db_audit_log() {
+        check_audit_log(&Database::open_in_memory().unwrap());
+        check_audit_log(&MemoryKeyStore::new());
+    }
+
+    #[test]
+    fn test_db_revocation() {
+        check_revocation(&Database::open_in_memory().unwrap());
+        check_revocation(&MemoryKeyStore::new());
+        check_revocation(&IntegrityKeyStore::new(
+            Box::new(MemoryKeyStore::new()),
+            IntegrityKey::new(b"secret"),
+        ));
+    }
+
+    #[test]
+    fn test_db_atomically() {
+        let d = Database::open_in_memory().unwrap();
+        d.insert(&key("martin", SHA1_A)).unwrap();
+        d.create_group("salon").unwrap();
+
+        // a failing change rolls back the previous ones, including the nested transactions
+        let result = d.atomically(&mut || {
+            d.rename(SHA1_A, "martin (lyon)")?;
+            d.update_public_key(SHA1_A, include_bytes!("../test_public.key.pem"))?;
+            d.add_to_group("salon", SHA1_A)?;
+            d.add_to_group("atelier", SHA1_A)
+        });
+        assert!(matches!(result, Err(KeyManagementError::GroupNotFound(_))));
+        assert_eq!(d.get_by_sha1(SHA1_A).unwrap().name, "martin");
+        assert!(d.get_group_members("salon").unwrap().is_empty());
+
+        d.atomically(&mut || {
+            d.rename(SHA1_A, "martin (lyon)")?;
+            d.add_to_group("salon", SHA1_A)
+        })
+        .unwrap();
+        assert_eq!(d.get_by_sha1(SHA1_A).unwrap().name, "martin (lyon)");
+        assert_eq!(d.get_group_members("salon").unwrap().len(), 1);
+
+        // the store is usable after a refused removal
+        assert!(d.delete_group("atelier").is_err());
+        d.delete(SHA1_A).unwrap();
+        assert!(d.get_group_members("salon").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_db_key_history() {
+        check_key_history(&Database::open_in_memory().unwrap());
+        check_key_history(&MemoryKeyStore::new());
+        check_key_history(&IntegrityKeyStore::new(
+            Box::new(MemoryKeyStore::new()),
+            IntegrityKey::new(b"secret"),
+        ));
     }
 }
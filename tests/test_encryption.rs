@@ -3,9 +3,12 @@
 mod test_encryption {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
 
+    use std::fs;
+    use std::path::Path;
     use std::time::{Duration, Instant};
 
     use encrypter::encrypt::*;
+    use encrypter::output::OutputLayout;
 
     fn encrypt_decrypt(midi_file: String) {
         println!("encrypt midi file {}", &midi_file);
@@ -29,6 +32,32 @@ mod test_encryption {
         }
     }
 
+    #[test]
+    fn test_encrypt_as() {
+        let out = std::env::temp_dir().join("encrypter_encrypt_as");
+        let _ = fs::remove_dir_all(&out);
+        let layout = OutputLayout::new(&out, "{out}/{key.name}/{relpath}/{stem}.midx").unwrap();
+        let output = encrypt_file_as(
+            Path::new("lalala1.mid"),
+            &"test_public.key.pem".into(),
+            &layout,
+            "martin",
+            "30d9690cc085429a1d0a3ae787932bf1518a1798",
+        )
+        .expect("fail to encrypt");
+        assert_eq!(output, out.join("martin").join("lalala1.midx"));
+
+        let result = out.join("result").to_string_lossy().to_string();
+        decrypt_file(
+            output.to_string_lossy().to_string(),
+            "test_private.key.pem".into(),
+            "30d9690cc085429a1d0a3ae787932bf1518a1798".into(),
+            result.clone(),
+        )
+        .expect("fail to decrypt");
+        assert_eq!(fs::read(result).unwrap(), fs::read("lalala1.mid").unwrap());
+    }
+
     #[test]
     fn test_encrypt() {
         env_logger::init();
@@ -39,4 +68,58 @@ mod test_encryption {
         //encrypt_decrypt("113-BennyHill.mid".into());
         println!("timed encrypt / decrypt {:?}", (Instant::now() - start))
     }
+
+    #[test]
+    fn test_encrypted_size_estimate() {
+        let midi_file: String = "lalala1.mid".into();
+        let output_file: String = "lalala1.mid.estimate".into();
+        let public_key = get_file_as_byte_vec(&"test_public.key.pem".into()).unwrap();
+        encrypt_file_with_inmemory_key(&midi_file, &output_file, &public_key)
+            .expect("fail to encrypt");
+
+        let encrypted = std::fs::metadata(&output_file).unwrap().len();
+        std::fs::remove_file(&output_file).unwrap();
+        let block = encrypted_block_size(&public_key).unwrap();
+        assert_eq!(block, DEFAULT_ENCRYPTED_BLOCK_SIZE);
+        assert_eq!(
+            estimate_encrypted_size(&[&midi_file], block).unwrap(),
+            encrypted
+        );
+
+        assert_eq!(encrypted_size(0, 128), 4);
+        assert_eq!(encrypted_size(64, 128), 4 + 4 + 128);
+        assert_eq!(encrypted_size(65, 128), 4 + 2 * (4 + 128));
+
+        let e = CapacityEstimate::estimate(&[&midi_file], encrypted, block).unwrap();
+        assert!(e.fits());
+        assert_eq!(e.remaining(), 0);
+        let e = CapacityEstimate::estimate(&[&midi_file], encrypted - 1, block).unwrap();
+        assert!(!e.fits());
+    }
+
+    #[test]
+    fn test_encrypted_size_2048() {
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        let public_key = rsa.public_key_to_pem_pkcs1().unwrap();
+        let output_file = std::env::temp_dir().join("encrypter_lalala1.mid.2048");
+        encrypt_file_with_inmemory_key("lalala1.mid", &output_file, &public_key)
+            .expect("fail to encrypt");
+
+        let encrypted = std::fs::metadata(&output_file).unwrap().len();
+        std::fs::remove_file(&output_file).unwrap();
+        let block = encrypted_block_size(&public_key).unwrap();
+        assert_eq!(block, 256);
+        assert_eq!(
+            estimate_encrypted_size(&["lalala1.mid"], block).unwrap(),
+            encrypted
+        );
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
 }
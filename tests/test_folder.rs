@@ -1,19 +1,242 @@
+mod common;
+
 #[cfg(test)]
 
 mod test_folder {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
 
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::common::*;
+    use encrypter::folder::*;
+
+    const SHA1: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+
+    /// music folder with sub folders, key files and encrypted files
+    fn music_folder(name: &str) -> PathBuf {
+        let root = temp_folder(&format!("walk_{}", name));
+        for folder in [
+            "valses/lentes".to_string(),
+            ".git".to_string(),
+            format!("martin-{}", SHA1),
+        ] {
+            fs::create_dir_all(root.join(folder)).unwrap();
+        }
+        for file in [
+            "polka.mid".to_string(),
+            "notes.txt".to_string(),
+            "keys.db".to_string(),
+            "public.key.pem".to_string(),
+            ".cache".to_string(),
+            "valses/Vienne.MIDI".to_string(),
+            "valses/lentes/lente.mid".to_string(),
+            "valses/lentes/pochette.jpg".to_string(),
+            ".git/config".to_string(),
+            format!("martin-{}/polka.midx", SHA1),
+        ] {
+            fs::write(root.join(file), b"").unwrap();
+        }
+        root
+    }
+
+    fn relative_paths(root: &Path, options: &WalkOptions) -> Vec<String> {
+        walk(root, options)
+            .entries
+            .iter()
+            .map(|e| e.relative.to_string_lossy().replace('\\', "/"))
+            .collect()
+    }
+
+    #[test]
+    fn test_walk() {
+        let root = music_folder("filters");
+        assert_eq!(
+            relative_paths(&root, &WalkOptions::default()),
+            vec![
+                "notes.txt",
+                "polka.mid",
+                "valses",
+                "valses/Vienne.MIDI",
+                "valses/lentes",
+                "valses/lentes/lente.mid",
+                "valses/lentes/pochette.jpg",
+            ]
+        );
+        assert_eq!(
+            relative_paths(&root, &WalkOptions::music()),
+            vec![
+                "polka.mid",
+                "valses",
+                "valses/Vienne.MIDI",
+                "valses/lentes",
+                "valses/lentes/lente.mid",
+            ]
+        );
+        assert_eq!(
+            relative_paths(&root, &WalkOptions::music().with_max_depth(Some(2))),
+            vec!["polka.mid", "valses", "valses/Vienne.MIDI", "valses/lentes"]
+        );
+
+        let mut options = WalkOptions::music();
+        options.exclude.push("valses/lentes".into());
+        options.hidden = true;
+        assert_eq!(
+            relative_paths(&root, &options),
+            vec![".git", "polka.mid", "valses", "valses/Vienne.MIDI"]
+        );
+
+        let depth: Vec<usize> = walk(&root, &WalkOptions::music())
+            .entries
+            .iter()
+            .map(|e| e.depth)
+            .collect();
+        assert_eq!(depth, vec![1, 1, 2, 2, 3]);
+        let absent = walk(&root.join("absent"), &WalkOptions::default());
+        assert!(absent.entries.is_empty());
+        assert_eq!(absent.errors.len(), 1);
+        assert_eq!(absent.errors[0].path, root.join("absent"));
+    }
+
+    #[test]
+    fn test_expand_with() {
+        let root = music_folder("expand");
+        let mut node = FolderNode::new_root(&root);
+        assert!(expand_with(&mut node, &WalkOptions::music()).is_empty());
+        let names: Vec<String> = node.subfolders.iter().map(|n| n.name().into()).collect();
+        assert_eq!(names, vec!["polka.mid", "valses"]);
+        assert!(node.expanded);
+        assert!(node.subfolders[1].is_folder);
+        assert!(node.subfolders[1].subfolders.is_empty());
+    }
+
+    #[test]
+    fn test_refresh() {
+        let root = music_folder("refresh");
+        let options = WalkOptions::music();
+        let mut tree = FolderNode::new_root(&root);
+        expand_with(&mut tree, &options);
+        let valses = find_mut(&mut tree, &root.join("valses")).unwrap();
+        expand_with(valses, &options);
+        valses.subfolders[0].selected = true;
+        assert_eq!(
+            expanded_folders(&tree),
+            vec![root.clone(), root.join("valses")]
+        );
+
+        fs::write(root.join("marche.mid"), b"").unwrap();
+        fs::remove_file(root.join("polka.mid")).unwrap();
+        assert!(refresh_with(&mut tree, &options).is_empty());
+
+        let names: Vec<String> = tree.subfolders.iter().map(|n| n.name().into()).collect();
+        assert_eq!(names, vec!["marche.mid", "valses"]);
+        // the expansion and the selection are kept
+        let valses = &tree.subfolders[1];
+        assert!(valses.expanded);
+        assert_eq!(
+            selected_paths(&tree),
+            vec![root.join("valses").join("Vienne.MIDI")]
+        );
+        assert!(find_mut(&mut tree, &root.join("valses/lentes")).is_some());
+        assert!(find_mut(&mut tree, &root.join("absent")).is_none());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.mid", "Polka.MID"));
+        assert!(!glob_match("*.mid", "polka.midx"));
+        assert!(glob_match("valse?.mid", "valse1.mid"));
+        assert!(!glob_match("*.mid", "valses/polka.mid"));
+        assert!(glob_match("valses/*.mid", "valses/polka.mid"));
+        assert!(glob_match("**/*.mid", "polka.mid"));
+        assert!(glob_match("**/*.mid", "a/b/polka.mid"));
+        assert!(glob_match("valses/**", "valses/lentes/lente.mid"));
+        assert!(!glob_match("valses/**", "marches/polka.mid"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_symlinks() {
+        let root = music_folder("symlinks");
+        std::os::unix::fs::symlink(root.join("valses"), root.join("lien")).unwrap();
+        std::os::unix::fs::symlink(root.join("polka.mid"), root.join("lien.mid")).unwrap();
+        // loop back to the root
+        std::os::unix::fs::symlink(&root, root.join("valses/boucle")).unwrap();
+
+        let options = |symlinks| WalkOptions {
+            symlinks,
+            ..WalkOptions::music()
+        };
+        let skipped = relative_paths(&root, &options(SymlinkPolicy::Skip));
+        assert!(!skipped
+            .iter()
+            .any(|p| p.contains("lien") || p.contains("boucle")));
+        let files = relative_paths(&root, &options(SymlinkPolicy::Files));
+        assert!(files.contains(&"lien.mid".to_string()));
+        assert!(!files.iter().any(|p| p.starts_with("lien/")));
+
+        let followed = relative_paths(&root, &options(SymlinkPolicy::Follow));
+        assert!(followed.contains(&"lien.mid".to_string()));
+        assert!(followed.contains(&"lien".to_string()));
+        // the folders are only entered once, the loop is listed but not entered
+        assert!(followed.iter().any(|p| p.ends_with("/boucle")));
+        assert!(!followed.iter().any(|p| p.contains("boucle/")));
+        assert_eq!(
+            followed.iter().filter(|p| p.ends_with("lente.mid")).count(),
+            1
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_expand_errors() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = music_folder("errors");
+        std::os::unix::fs::symlink(root.join("absent.mid"), root.join("cassé.mid")).unwrap();
+        let latin1 = root.join(OsStr::from_bytes(b"caf\xe9.mid"));
+        fs::write(&latin1, b"").unwrap();
+
+        let mut node = FolderNode::new_root(&root);
+        let errors = expand_with(&mut node, &WalkOptions::music());
+        // the broken link is shown with its error, the other entries are read
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, root.join("cassé.mid"));
+        let names: Vec<String> = node.subfolders.iter().map(|n| n.name().into()).collect();
+        assert_eq!(
+            names,
+            vec!["caf\u{fffd}.mid", "cassé.mid", "polka.mid", "valses"]
+        );
+        assert!(node.subfolders[1].error.is_some());
+        assert_eq!(node.subfolders[0].path, latin1);
+        assert!(node.error.is_none());
+
+        // a folder which can't be read keeps its error
+        let mut absent = FolderNode::new_root(root.join("absent"));
+        assert_eq!(expand(&mut absent).len(), 1);
+        assert!(absent.expanded);
+        assert!(absent.error.is_some());
+        assert!(absent.subfolders.is_empty());
+
+        // the walk goes on after the errors
+        let walk = walk(&root, &WalkOptions::music());
+        assert_eq!(walk.errors.len(), 1);
+        assert_eq!(walk.files().len(), 4);
+    }
+
     #[test]
     fn test_folder() {
         let mut f = encrypter::folder::FolderNode {
-            path: "/home/use".into(),
+            path: ".".into(),
             expanded: false,
             is_folder: true,
             subfolders: vec![],
             selected: false,
+            error: None,
         };
 
-        encrypter::folder::expand(&mut f).unwrap();
+        assert!(encrypter::folder::expand(&mut f).is_empty());
 
         println!("{:?} \n", f.subfolders);
         println!("{} \n", f.subfolders[0].name())
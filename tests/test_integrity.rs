@@ -0,0 +1,256 @@
+mod common;
+
+#[cfg(test)]
+
+mod test_integrity {
+
+    use std::path::PathBuf;
+
+    use crate::common::*;
+    use encrypter::integrity::*;
+    use encrypter::keys_management::*;
+
+    const SHA1_A: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+    const SHA1_B: &str = "30d9ffffffffffffffffffffffffffffffffffff";
+
+    fn public_key() -> Vec<u8> {
+        include_bytes!("../test_public.key.pem").to_vec()
+    }
+
+    fn database_path(name: &str) -> PathBuf {
+        temp_folder(&format!("integrity_{}", name)).join("keys.db")
+    }
+
+    #[test]
+    fn test_integrity_tag() {
+        let key = IntegrityKey::new(b"secret");
+        let mut k = Key {
+            name: "martin".into(),
+            sha1: SHA1_A.into(),
+            public_key: Some(public_key()),
+            ..Default::default()
+        };
+        assert_eq!(key.verify(&k), IntegrityStatus::Unsigned);
+
+        k.integrity = Some(key.tag(&k).unwrap());
+        assert_eq!(key.verify(&k), IntegrityStatus::Valid);
+        assert_eq!(
+            IntegrityKey::new(b"other").verify(&k),
+            IntegrityStatus::Tampered
+        );
+
+        // the metadata is not protected
+        k.notes = "changed".into();
+        assert_eq!(key.verify(&k), IntegrityStatus::Valid);
+
+        k.public_key = None;
+        assert_eq!(key.verify(&k), IntegrityStatus::Tampered);
+
+        let a = IntegrityKey::from_passphrase("passphrase", b"salt").unwrap();
+        let b = IntegrityKey::from_passphrase("passphrase", b"salt").unwrap();
+        k.integrity = Some(a.tag(&k).unwrap());
+        assert_eq!(b.verify(&k), IntegrityStatus::Valid);
+    }
+
+    #[test]
+    fn test_integrity_tampered_database() {
+        let path = database_path("tampered");
+        let d = open_protected(&path).unwrap();
+        assert!(master_key_path(&path).exists());
+        assert_eq!(
+            d.verify_integrity(&Key::default()),
+            IntegrityStatus::Unsigned
+        );
+
+        d.insert(&Key {
+            name: "martin".into(),
+            sha1: SHA1_A.into(),
+            public_key: Some(public_key()),
+            ..Default::default()
+        })
+        .unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Valid);
+        drop(d);
+
+        // the public key is swapped directly in the file
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute(
+            "UPDATE all_keys SET public_key = ?1 WHERE sha1 = ?2",
+            (b"attacker key".to_vec(), SHA1_A),
+        )
+        .unwrap();
+        drop(conn);
+
+        let d = open_protected(&path).unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Tampered);
+        assert!(matches!(
+            d.rename(SHA1_A, "martin"),
+            Err(KeyManagementError::Tampered(_))
+        ));
+
+        // setting the public key again repairs the record
+        d.update_public_key(SHA1_A, &public_key()).unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Valid);
+        d.rename(SHA1_A, "martin").unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Valid);
+
+        drop(d);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_integrity_revocation() {
+        let path = database_path("revocation");
+        let d = open_protected(&path).unwrap();
+        d.insert(&Key {
+            name: "martin".into(),
+            sha1: SHA1_A.into(),
+            public_key: Some(public_key()),
+            ..Default::default()
+        })
+        .unwrap();
+        d.revoke(&Revocation {
+            sha1: SHA1_A.into(),
+            revoked_at: 1_700_000_000,
+            reason: "volé".into(),
+        })
+        .unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Valid);
+        drop(d);
+
+        // clearing the revocation outside of the application is detected
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute("UPDATE all_keys SET revoked_at = NULL", [])
+            .unwrap();
+        drop(conn);
+
+        let d = open_protected(&path).unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Tampered);
+
+        drop(d);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_integrity_unsigned_records() {
+        let path = database_path("unsigned");
+        let raw = Database::open(&path).unwrap();
+        raw.insert(&Key {
+            name: "martin".into(),
+            sha1: SHA1_A.into(),
+            public_key: Some(public_key()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            raw.verify_integrity(&raw.get_by_sha1(SHA1_A).unwrap()),
+            IntegrityStatus::Unchecked
+        );
+        drop(raw);
+
+        // the records written before the protection are signed when it is set up
+        let d = open_protected(&path).unwrap();
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Valid);
+        drop(d);
+
+        // a record added afterwards outside of the application is refused
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute(
+            "INSERT INTO all_keys (name, sha1, public_key, owner, instrument_model,
+                instrument_serial, notes, source) VALUES ('durand', ?1, ?2, '', '', '', '', '')",
+            (SHA1_B, public_key()),
+        )
+        .unwrap();
+        drop(conn);
+
+        let d = open_protected(&path).unwrap();
+        let k = d.get_by_sha1(SHA1_B).unwrap();
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Unsigned);
+        assert!(d.verify_integrity(&k).is_altered());
+        assert!(matches!(
+            d.rename(SHA1_B, "durand"),
+            Err(KeyManagementError::Tampered(_))
+        ));
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Valid);
+
+        drop(d);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_integrity_key_removed() {
+        let path = database_path("key_removed");
+        let d = open_protected(&path).unwrap();
+        d.insert(&Key {
+            name: "martin".into(),
+            sha1: SHA1_A.into(),
+            public_key: Some(public_key()),
+            ..Default::default()
+        })
+        .unwrap();
+        drop(d);
+
+        // a key swapped with its tag cleared, and the master key deleted
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute(
+            "UPDATE all_keys SET public_key = ?1, integrity = NULL WHERE sha1 = ?2",
+            (b"attacker key".to_vec(), SHA1_A),
+        )
+        .unwrap();
+        drop(conn);
+        std::fs::remove_file(master_key_path(&path)).unwrap();
+        assert!(matches!(
+            open_protected(&path).err().unwrap().downcast_ref(),
+            Some(KeyManagementError::IntegrityKey(_))
+        ));
+        // the swapped key is not signed with a new master key
+        assert!(!master_key_path(&path).exists());
+
+        // nor with another master key
+        std::fs::write(master_key_path(&path), [7; 32]).unwrap();
+        assert!(open_protected(&path).is_err());
+
+        // nor once the protection is removed from the database
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute("DELETE FROM metadata", []).unwrap();
+        drop(conn);
+        std::fs::remove_file(master_key_path(&path)).unwrap();
+        assert!(open_protected(&path).is_err());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_integrity_atomic_update() {
+        let path = database_path("atomic_update");
+        let d = open_protected(&path).unwrap();
+        d.insert(&Key {
+            name: "martin".into(),
+            sha1: SHA1_A.into(),
+            public_key: Some(public_key()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // a change failing while the record is signed again is not applied
+        let result = d.atomically(&mut || {
+            d.set_expiry(SHA1_A, Some(1_700_000_000))?;
+            Err(KeyManagementError::NotFound(SHA1_B.into()))
+        });
+        assert!(result.is_err());
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(k.expires_at, None);
+        assert_eq!(d.verify_integrity(&k), IntegrityStatus::Valid);
+
+        drop(d);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+mod common;
+
+#[cfg(test)]
+
+mod test_key_book {
+
+    use std::path::PathBuf;
+
+    use crate::common::*;
+    use encrypter::key_book::*;
+    use encrypter::keys_management::*;
+
+    const SHA1_A: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+    const SHA1_B: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn public_key() -> Vec<u8> {
+        include_bytes!("../test_public.key.pem").to_vec()
+    }
+
+    fn test_database() -> Database {
+        let d = Database::open_in_memory().unwrap();
+        for (name, sha1) in [("martin, lyon", SHA1_A), ("durand \"orgue\"", SHA1_B)] {
+            d.insert(&Key {
+                rowid: 0,
+                name: name.into(),
+                sha1: sha1.into(),
+                public_key: Some(public_key()),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        d
+    }
+
+    fn output(name: &str) -> PathBuf {
+        temp_folder(&format!("key_book_{}", name)).join(name)
+    }
+
+    fn roundtrip(format: KeyBookFormat, name: &str) {
+        let path = output(name);
+        assert_eq!(export_keys(&test_database(), format, &path).unwrap(), 2);
+
+        let records = read_key_book(format, &path).unwrap();
+        assert_eq!(records.len(), 2);
+
+        let d = Database::open_in_memory().unwrap();
+        let report = import_keys(&d, &records, ImportStrategy::Merge).unwrap();
+        assert_eq!(report.added, 2);
+
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(k.public_key, Some(public_key()));
+        if format != KeyBookFormat::PemBundle {
+            assert_eq!(k.name, "martin, lyon");
+            assert_eq!(d.get_by_sha1(SHA1_B).unwrap().name, "durand \"orgue\"");
+        }
+    }
+
+    #[test]
+    fn test_key_book_json() {
+        roundtrip(KeyBookFormat::Json, "roundtrip.json");
+    }
+
+    #[test]
+    fn test_key_book_csv() {
+        roundtrip(KeyBookFormat::Csv, "roundtrip.csv");
+    }
+
+    #[test]
+    fn test_key_book_pem_bundle() {
+        roundtrip(KeyBookFormat::PemBundle, "roundtrip_pem");
+    }
+
+    #[test]
+    fn test_key_book_strategies() {
+        let other_key = "-----BEGIN RSA PUBLIC KEY-----\nxx\n-----END RSA PUBLIC KEY-----\n";
+        let records = vec![
+            KeyRecord {
+                name: "renamed".into(),
+                sha1: SHA1_A.into(),
+                public_key: Some(other_key.into()),
+                ..Default::default()
+            },
+            KeyRecord {
+                name: "new".into(),
+                sha1: "ffffffffffffffffffffffffffffffffffffffff".into(),
+                public_key: None,
+                ..Default::default()
+            },
+        ];
+
+        let d = test_database();
+        let preview = preview_import(&d, &records).unwrap();
+        assert_eq!(preview.new_keys.len(), 1);
+        assert_eq!(preview.conflicts.len(), 1);
+        assert_eq!(preview.conflicts[0].existing.sha1, SHA1_A);
+
+        let report = import_keys(&d, &records, ImportStrategy::SkipExisting).unwrap();
+        assert_eq!((report.added, report.updated, report.skipped), (1, 0, 1));
+
+        let report = import_keys(&d, &records, ImportStrategy::Merge).unwrap();
+        assert_eq!((report.added, report.updated, report.skipped), (0, 0, 2));
+        assert_eq!(
+            d.get_by_sha1(SHA1_A).unwrap().public_key,
+            Some(public_key())
+        );
+
+        // the different public key waits for the operator
+        let report = import_keys(&d, &records, ImportStrategy::Overwrite).unwrap();
+        assert_eq!((report.added, report.updated, report.skipped), (0, 1, 0));
+        assert_eq!(report.pending, 1);
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(k.name, "renamed");
+        assert_eq!(k.public_key, Some(public_key()));
+
+        let change = &d.get_pending_key_changes().unwrap()[0];
+        d.accept_public_key(SHA1_A, change.id).unwrap();
+        assert_eq!(
+            d.get_by_sha1(SHA1_A).unwrap().public_key,
+            Some(other_key.as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_key_book_invalid_files() {
+        let path = output("invalid.csv");
+        std::fs::write(&path, "name,sha1\nmartin,".to_string() + SHA1_A).unwrap();
+        assert!(matches!(
+            read_key_book(KeyBookFormat::Csv, &path),
+            Err(KeyBookError::Csv(1, _))
+        ));
+
+        std::fs::write(
+            &path,
+            "name,sha1,public_key\nmartin,".to_string() + SHA1_A + ",bad",
+        )
+        .unwrap();
+        assert!(matches!(
+            read_key_book(KeyBookFormat::Csv, &path),
+            Err(KeyBookError::InvalidPublicKey(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
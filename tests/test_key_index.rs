@@ -0,0 +1,112 @@
+#[cfg(test)]
+
+mod test_key_index {
+
+    use encrypter::integrity::IntegrityStatus;
+    use encrypter::key_index::*;
+    use encrypter::keys_management::*;
+
+    const SHA1_LYON: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+    const SHA1_ORGUE: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn key(name: &str, sha1: &str, owner: &str) -> Key {
+        Key {
+            name: name.into(),
+            sha1: sha1.into(),
+            owner: owner.into(),
+            ..Default::default()
+        }
+    }
+
+    fn index() -> KeyIndex {
+        KeyIndex::new(
+            vec![
+                key("Serinette Lyon", SHA1_LYON, "Jean Martin"),
+                key("orgue de barbarie", SHA1_ORGUE, "Paul Durand"),
+                key(
+                    "Limonaire",
+                    "30d0000000000000000000000000000000000000",
+                    "Marie Martin",
+                ),
+            ],
+            &[
+                (
+                    "Atelier".to_string(),
+                    vec![SHA1_LYON.to_string(), SHA1_ORGUE.to_string()],
+                ),
+                ("location".to_string(), vec![SHA1_LYON.to_string()]),
+            ],
+        )
+    }
+
+    fn names(found: Vec<&Key>) -> Vec<String> {
+        found.into_iter().map(|k| k.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_query_parse() {
+        let q = KeyQuery::parse("Lyon tag:Atelier owner:jean-martin sha1:30D9");
+        assert_eq!(q.words, vec!["lyon".to_string()]);
+        assert_eq!(q.groups, vec!["atelier".to_string()]);
+        assert_eq!(q.owner, vec!["jean".to_string(), "martin".to_string()]);
+        assert_eq!(q.sha1_prefix, Some("30d9".to_string()));
+        assert!(KeyQuery::parse("  ").is_empty());
+    }
+
+    #[test]
+    fn test_index_search() {
+        let index = index();
+        assert_eq!(index.len(), 3);
+
+        // ordered by name, whatever the case
+        assert_eq!(
+            names(index.search(&KeyQuery::parse(""))),
+            vec!["Limonaire", "orgue de barbarie", "Serinette Lyon"]
+        );
+        assert_eq!(
+            names(index.search(&KeyQuery::parse("ser"))),
+            vec!["Serinette Lyon"]
+        );
+        assert_eq!(
+            names(index.search(&KeyQuery::parse("martin"))),
+            vec!["Limonaire", "Serinette Lyon"]
+        );
+        assert_eq!(
+            names(index.search(&KeyQuery::parse("martin lyon"))),
+            vec!["Serinette Lyon"]
+        );
+        assert_eq!(
+            names(index.search(&KeyQuery::parse("30d"))),
+            vec!["Limonaire", "Serinette Lyon"]
+        );
+        assert_eq!(
+            names(index.search(&KeyQuery::parse("sha1:0123"))),
+            vec!["orgue de barbarie"]
+        );
+        assert_eq!(
+            names(index.search(&KeyQuery::parse("tag:atelier owner:dur"))),
+            vec!["orgue de barbarie"]
+        );
+        // a group criteria matches the whole group name
+        assert!(index.search(&KeyQuery::parse("tag:atel")).is_empty());
+        assert!(index.search(&KeyQuery::parse("piano")).is_empty());
+    }
+
+    #[test]
+    fn test_index_build() {
+        let d = MemoryKeyStore::new();
+        d.insert(&Key {
+            name: "martin".into(),
+            sha1: SHA1_LYON.into(),
+            public_key: Some(include_bytes!("../test_public.key.pem").to_vec()),
+            ..Default::default()
+        })
+        .unwrap();
+        d.create_group("location").unwrap();
+        d.add_to_group("location", SHA1_LYON).unwrap();
+        let index = KeyIndex::build(&d).unwrap();
+        assert_eq!(index.keys().len(), 1);
+        assert_eq!(index.search(&KeyQuery::parse("tag:location")).len(), 1);
+        assert_eq!(index.integrity(SHA1_LYON), IntegrityStatus::Unchecked);
+    }
+}
@@ -0,0 +1,138 @@
+mod common;
+
+#[cfg(test)]
+
+mod test_key_provider {
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::common::*;
+    use encrypter::key_provider::*;
+    use encrypter::key_sync::*;
+    use encrypter::keys_management::*;
+    use encrypter::keyserver::*;
+
+    const SHA1_A: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+    const SHA1_B: &str = "0123456789abcdef0123456789abcdef01234567";
+    const SHA1_C: &str = "ffffffffffffffffffffffffffffffffffffffff";
+
+    /// key folder with the signed key of A and the unsigned key of B
+    fn key_folder(name: &str) -> PathBuf {
+        let folder = temp_folder(&format!("key_folder_{}", name));
+        fs::create_dir_all(folder.join(SHA1_A)).unwrap();
+        fs::write(folder.join(SHA1_A).join("public.key.pem"), PUBLIC_KEY).unwrap();
+        fs::write(
+            folder.join(SHA1_A).join("public.key.sig"),
+            sign(SHA1_A, PUBLIC_KEY),
+        )
+        .unwrap();
+        fs::create_dir_all(folder.join(SHA1_B)).unwrap();
+        fs::write(folder.join(SHA1_B).join("public.key.pem"), PUBLIC_KEY).unwrap();
+        folder
+    }
+
+    fn folder_source(folder: &PathBuf) -> FolderKeySource {
+        FolderKeySource::new(folder).with_publisher_key(publisher())
+    }
+
+    #[test]
+    fn test_folder_source() {
+        let folder = key_folder("lookup");
+        let source = folder_source(&folder);
+
+        let fetched = source.fetch(SHA1_A).unwrap();
+        assert_eq!(fetched.public_key, PUBLIC_KEY.to_vec());
+        assert_eq!(fetched.origin, KeySource::Folder);
+
+        assert!(matches!(
+            source.fetch(SHA1_B),
+            Err(KeyServerError::Unsigned(_))
+        ));
+        // the keys are checked against the root key by default
+        if std::env::var_os(encrypter::key_signature::PUBLISHER_KEY_ENV).is_none() {
+            let default = FolderKeySource::new(&folder);
+            assert!(matches!(
+                default.fetch(SHA1_A),
+                Err(KeyServerError::Signature(_))
+            ));
+            assert!(matches!(
+                default.fetch(SHA1_B),
+                Err(KeyServerError::Unsigned(_))
+            ));
+        }
+        assert!(matches!(
+            source.fetch(SHA1_C),
+            Err(KeyServerError::NotFound(_))
+        ));
+        assert!(matches!(source.fetch("../a"), Err(KeyServerError::Key(_))));
+
+        // a key changed on the stick
+        fs::write(folder.join(SHA1_A).join("public.key.pem"), b"-----").unwrap();
+        assert!(matches!(
+            source.fetch(SHA1_A),
+            Err(KeyServerError::InvalidKey(..))
+        ));
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_sources_in_turn() {
+        let folder = key_folder("sources");
+        // the server has B and C
+        let (base_url, _) = serve(|path, _| {
+            for sha1 in [SHA1_B, SHA1_C] {
+                if path.contains(sha1) {
+                    return published(path, sha1, PUBLIC_KEY, &sign(sha1, PUBLIC_KEY));
+                }
+            }
+            (404, vec![])
+        });
+        let sources = KeySources::new(vec![
+            Box::new(folder_source(&folder)),
+            Box::new(client(&base_url)),
+        ]);
+
+        assert_eq!(sources.fetch(SHA1_A).unwrap().origin, KeySource::Folder);
+        // the unsigned key of the folder is not used
+        assert_eq!(sources.fetch(SHA1_B).unwrap().origin, KeySource::Downloaded);
+        assert_eq!(sources.fetch(SHA1_C).unwrap().origin, KeySource::Downloaded);
+        assert!(matches!(
+            sources.fetch("1111111111111111111111111111111111111111"),
+            Err(KeyServerError::NotFound(_))
+        ));
+
+        // the bulk download uses the sources too
+        let sha1: Vec<String> = [SHA1_A, SHA1_C].iter().map(|s| s.to_string()).collect();
+        let d = MemoryKeyStore::new();
+        let report = record_fetched(&d, &fetch_keys(&sources, &sha1, 2, |_, _| {})).unwrap();
+        assert_eq!(report.added, 2);
+        assert_eq!(d.get_by_sha1(SHA1_A).unwrap().source, KeySource::Folder);
+        assert_eq!(d.get_by_sha1(SHA1_C).unwrap().source, KeySource::Downloaded);
+
+        // without the server, the error of the folder is reported
+        let sources = KeySources::new(vec![Box::new(folder_source(&folder))]);
+        assert!(matches!(
+            sources.fetch(SHA1_B),
+            Err(KeyServerError::Unsigned(_))
+        ));
+        assert!(matches!(
+            KeySources::default().fetch(SHA1_A),
+            Err(KeyServerError::NotFound(_))
+        ));
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_parse_sources() {
+        let sources = KeySources::parse(" /media/usb/cles ; http://localhost:8080/k ;");
+        assert_eq!(sources.len(), 2);
+        assert_eq!(
+            sources.describe(),
+            "dossier /media/usb/cles, puis serveur http://localhost:8080/k/"
+        );
+        assert!(KeySources::parse("").is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+#[cfg(test)]
+
+mod test_key_signature {
+
+    use encrypter::key_signature::*;
+    use openssl::pkey::PKey;
+
+    const SHA1: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+    const PUBLIC_KEY: &[u8] = include_bytes!("../test_public.key.pem");
+
+    #[test]
+    fn test_sign_and_verify() {
+        let private =
+            PKey::private_key_from_pem(include_bytes!("fixtures/publisher_test.key.pem")).unwrap();
+        let publisher =
+            PublisherKey::from_pem(include_bytes!("fixtures/publisher_test.pub.pem")).unwrap();
+
+        let signature = sign_key(&private, SHA1, PUBLIC_KEY).unwrap();
+        publisher
+            .verify(SHA1, PUBLIC_KEY, signature.as_bytes())
+            .unwrap();
+        // the sha1 case does not matter
+        publisher
+            .verify(&SHA1.to_uppercase(), PUBLIC_KEY, signature.as_bytes())
+            .unwrap();
+        // nor the pem layout, the fingerprint is computed on the der encoding
+        let reformatted = String::from_utf8_lossy(PUBLIC_KEY).replace('\n', "\r\n");
+        publisher
+            .verify(SHA1, reformatted.as_bytes(), signature.as_bytes())
+            .unwrap();
+
+        assert!(matches!(
+            publisher.verify(
+                "0123456789abcdef0123456789abcdef01234567",
+                PUBLIC_KEY,
+                signature.as_bytes()
+            ),
+            Err(KeySignatureError::Invalid(_))
+        ));
+        assert!(matches!(
+            other_publisher().verify(SHA1, PUBLIC_KEY, signature.as_bytes()),
+            Err(KeySignatureError::Invalid(_))
+        ));
+    }
+
+    fn other_publisher() -> PublisherKey {
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        PublisherKey::from_pem(&PKey::from_rsa(rsa).unwrap().public_key_to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_publisher_trust() {
+        let trust = PublisherTrust::from_file(std::path::Path::new(
+            "tests/fixtures/publisher_test.pub.pem",
+        ));
+        assert!(matches!(trust, PublisherTrust::Required(_)));
+        let signature = sign_key(
+            &PKey::private_key_from_pem(include_bytes!("fixtures/publisher_test.key.pem")).unwrap(),
+            SHA1,
+            PUBLIC_KEY,
+        )
+        .unwrap();
+        assert!(trust.verify(SHA1, PUBLIC_KEY, signature.as_bytes()).is_ok());
+
+        // a missing or unreadable publisher key refuses every key, instead of not checking them
+        let trust = PublisherTrust::from_file(std::path::Path::new("tests/fixtures/missing.pem"));
+        assert!(matches!(trust, PublisherTrust::Invalid(_, _)));
+        assert!(matches!(
+            trust.verify(SHA1, PUBLIC_KEY, signature.as_bytes()),
+            Err(KeySignatureError::PublisherKey(..))
+        ));
+        let trust = PublisherTrust::from_file(std::path::Path::new("lalala1.mid"));
+        assert!(matches!(trust, PublisherTrust::Invalid(_, _)));
+
+        // the embedded root key by default, a key signed by another publisher is refused
+        if std::env::var_os(PUBLISHER_KEY_ENV).is_none() {
+            assert!(matches!(
+                PublisherTrust::default().verify(SHA1, PUBLIC_KEY, signature.as_bytes()),
+                Err(KeySignatureError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let fingerprint = key_fingerprint(PUBLIC_KEY).unwrap();
+        assert_eq!(fingerprint.len(), 64);
+        assert!(key_fingerprint(b"not a key").is_err());
+    }
+}
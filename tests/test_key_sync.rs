@@ -0,0 +1,151 @@
+mod common;
+
+#[cfg(test)]
+
+mod test_key_sync {
+
+    use std::collections::HashMap;
+
+    use crate::common::*;
+    use encrypter::key_sync::*;
+    use encrypter::keys_management::*;
+    use encrypter::keyserver::*;
+    use openssl::rsa::Rsa;
+
+    const SHA1_A: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+    const SHA1_B: &str = "0123456789abcdef0123456789abcdef01234567";
+    const SHA1_C: &str = "ffffffffffffffffffffffffffffffffffffffff";
+
+    fn new_public_key() -> Vec<u8> {
+        Rsa::generate(1024)
+            .unwrap()
+            .public_key_to_pem_pkcs1()
+            .unwrap()
+    }
+
+    /// stand-in of the key server publishing the signed keys
+    fn serve_keys(keys: &[(&str, Vec<u8>)]) -> String {
+        let mut files = HashMap::new();
+        for (sha1, public_key) in keys {
+            files.insert(format!("/k/{}/public.key.pem", sha1), public_key.clone());
+            files.insert(
+                format!("/k/{}/public.key.sig", sha1),
+                sign(sha1, public_key),
+            );
+        }
+        let (base_url, _) = serve(move |path, _| match files.get(path) {
+            Some(content) => (200, content.clone()),
+            None => (404, vec![]),
+        });
+        base_url
+    }
+
+    #[test]
+    fn test_parse_sha1_list() {
+        let list = parse_sha1_list(&format!(
+            "# livraison du distributeur\n{}, {}\n{} # doublon\npas-un-sha1\n",
+            SHA1_A,
+            SHA1_B.to_uppercase(),
+            SHA1_A
+        ));
+        assert_eq!(list.sha1, vec![SHA1_A.to_string(), SHA1_B.to_string()]);
+        assert_eq!(list.invalid, vec!["pas-un-sha1".to_string()]);
+        assert_eq!(parse_sha1_list(""), Sha1List::default());
+    }
+
+    #[test]
+    fn test_bulk_fetch() {
+        let base_url = serve_keys(&[(SHA1_A, PUBLIC_KEY.to_vec()), (SHA1_B, new_public_key())]);
+        let server = client(&base_url);
+
+        let sha1 = vec![SHA1_A.to_string(), SHA1_B.to_string(), SHA1_C.to_string()];
+        let mut progress = vec![];
+        let fetched = fetch_keys(&server, &sha1, 2, |done, total| {
+            progress.push((done, total))
+        });
+        assert_eq!(progress, vec![(1, 3), (2, 3), (3, 3)]);
+        // in the order of the list
+        let order: Vec<&str> = fetched.iter().map(|f| f.sha1.as_str()).collect();
+        assert_eq!(order, vec![SHA1_A, SHA1_B, SHA1_C]);
+        assert!(matches!(
+            fetched[2].result,
+            Err(KeyServerError::NotFound(_))
+        ));
+
+        // the known key keeps its name
+        let d = Database::open_in_memory().unwrap();
+        d.insert(&Key {
+            name: "martin".into(),
+            sha1: SHA1_A.into(),
+            public_key: Some(PUBLIC_KEY.to_vec()),
+            ..Default::default()
+        })
+        .unwrap();
+        let report = record_fetched(&d, &fetched).unwrap();
+        assert_eq!((report.added, report.unchanged, report.pending), (1, 1, 0));
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, SHA1_C);
+        assert_eq!(d.get_by_sha1(SHA1_A).unwrap().name, "martin");
+        let added = d.get_by_sha1(SHA1_B).unwrap();
+        assert_eq!(added.name, SHA1_B);
+        assert_eq!(added.source, KeySource::Downloaded);
+
+        assert!(fetch_keys(&server, &[], DEFAULT_WORKERS, |_, _| {}).is_empty());
+    }
+
+    #[test]
+    fn test_refresh() {
+        let d = MemoryKeyStore::new();
+        for (name, sha1) in [("martin", SHA1_A), ("durand", SHA1_B), ("retiré", SHA1_C)] {
+            d.insert(&Key {
+                name: name.into(),
+                sha1: sha1.into(),
+                public_key: Some(PUBLIC_KEY.to_vec()),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let changed = new_public_key();
+        // the same key, with other line endings, is unchanged
+        let reformatted = String::from_utf8_lossy(PUBLIC_KEY)
+            .replace('\n', "\r\n")
+            .into_bytes();
+        let base_url = serve_keys(&[(SHA1_A, reformatted.clone()), (SHA1_B, changed.clone())]);
+
+        let entries = refresh_keys(d.get_all().unwrap(), &client(&base_url), 4, |_, _| {});
+        let status: HashMap<String, &RefreshStatus> = entries
+            .iter()
+            .map(|e| (e.key.sha1.clone(), &e.status))
+            .collect();
+        assert!(matches!(status[SHA1_A], RefreshStatus::Unchanged));
+        assert!(matches!(status[SHA1_B], RefreshStatus::Changed(k) if k.public_key == changed));
+        assert!(matches!(status[SHA1_C], RefreshStatus::Missing));
+
+        // nothing is recorded by the refresh
+        assert_eq!(
+            d.get_by_sha1(SHA1_B).unwrap().public_key,
+            Some(PUBLIC_KEY.to_vec())
+        );
+
+        // the recorded change waits for the operator
+        let report = record_fetched(&d, &changed_keys(&entries)).unwrap();
+        assert_eq!(report.pending, 1);
+        let report = record_fetched(
+            &d,
+            &[KeyFetch {
+                sha1: SHA1_A.into(),
+                result: Ok(encrypter::key_provider::FetchedKey {
+                    public_key: reformatted,
+                    origin: KeySource::Downloaded,
+                }),
+            }],
+        )
+        .unwrap();
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(
+            d.get_by_sha1(SHA1_B).unwrap().public_key,
+            Some(PUBLIC_KEY.to_vec())
+        );
+        assert_eq!(d.get_pending_key_changes().unwrap().len(), 1);
+    }
+}
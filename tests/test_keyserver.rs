@@ -0,0 +1,208 @@
+mod common;
+
+#[cfg(test)]
+
+mod test_keyserver {
+
+    use std::net::TcpListener;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::common::*;
+    use encrypter::key_signature::*;
+    use encrypter::keyserver::*;
+    use encrypter::revocation::*;
+    use openssl::rsa::Rsa;
+
+    const SHA1: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+
+    #[test]
+    fn test_fetch_public_key() {
+        let signature = sign(SHA1, PUBLIC_KEY);
+        let (base_url, count) = serve(move |path, _| published(path, SHA1, PUBLIC_KEY, &signature));
+        let server = client(&base_url);
+        assert_eq!(
+            server.key_url(SHA1),
+            format!("{}/{}/public.key.pem", base_url, SHA1)
+        );
+
+        let public_key = server.fetch_public_key(SHA1).unwrap();
+        assert_eq!(public_key, PUBLIC_KEY.to_vec());
+
+        // a missing key is not retried
+        let missing = "0123456789abcdef0123456789abcdef01234567";
+        assert!(matches!(
+            server.fetch_public_key(missing),
+            Err(KeyServerError::NotFound(sha1)) if sha1 == missing
+        ));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // the sha1 is checked before any request
+        assert!(matches!(
+            server.fetch_public_key("30d9"),
+            Err(KeyServerError::Key(_))
+        ));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let (base_url, _) = serve(|_, _| (200, b"<html>maintenance</html>".to_vec()));
+        assert!(matches!(
+            client(&base_url).fetch_public_key(SHA1),
+            Err(KeyServerError::InvalidKey(..))
+        ));
+    }
+
+    #[test]
+    fn test_retry() {
+        // two server errors, then the key and its signature
+        let signature = sign(SHA1, PUBLIC_KEY);
+        let (base_url, count) = serve(move |path, request| {
+            if request < 2 {
+                (503, vec![])
+            } else {
+                published(path, SHA1, PUBLIC_KEY, &signature)
+            }
+        });
+        assert!(client(&base_url).fetch_public_key(SHA1).is_ok());
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+
+        // the attempts are exhausted
+        let (base_url, count) = serve(|_, _| (500, vec![]));
+        assert!(matches!(
+            client(&base_url).fetch_public_key(SHA1),
+            Err(KeyServerError::Status(500))
+        ));
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        let (base_url, count) = serve(|_, _| (500, vec![]));
+        let single = client(&base_url).with_retry(RetryPolicy::none());
+        assert!(single.fetch_public_key(SHA1).is_err());
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_signature() {
+        // no signature published
+        let (base_url, _) = serve(|path, _| match path.ends_with(".pem") {
+            true => (200, PUBLIC_KEY.to_vec()),
+            false => (404, vec![]),
+        });
+        assert!(matches!(
+            client(&base_url).fetch_public_key(SHA1),
+            Err(KeyServerError::Unsigned(sha1)) if sha1 == SHA1
+        ));
+
+        // a key substituted on the way, with the signature of the genuine key
+        let substituted = Rsa::generate(1024)
+            .unwrap()
+            .public_key_to_pem_pkcs1()
+            .unwrap();
+        let signature = sign(SHA1, PUBLIC_KEY);
+        let (base_url, _) = serve(move |path, _| published(path, SHA1, &substituted, &signature));
+        assert!(matches!(
+            client(&base_url).fetch_public_key(SHA1),
+            Err(KeyServerError::Signature(KeySignatureError::Invalid(_)))
+        ));
+
+        // the genuine key, published under another sha1
+        let other = "0123456789abcdef0123456789abcdef01234567";
+        let signature = sign(SHA1, PUBLIC_KEY);
+        let (base_url, _) = serve(move |path, _| published(path, other, PUBLIC_KEY, &signature));
+        assert!(matches!(
+            client(&base_url).fetch_public_key(other),
+            Err(KeyServerError::Signature(KeySignatureError::Invalid(_)))
+        ));
+
+        // signed by another publisher
+        let signature = sign(SHA1, PUBLIC_KEY);
+        let (base_url, _) = serve(move |path, _| published(path, SHA1, PUBLIC_KEY, &signature));
+        let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+        let other_publisher = PublisherKey::from_pem(
+            &openssl::pkey::PKey::from_rsa(rsa)
+                .unwrap()
+                .public_key_to_pem()
+                .unwrap(),
+        )
+        .unwrap();
+        let server = client(&base_url).with_publisher_key(other_publisher);
+        assert!(matches!(
+            server.fetch_public_key(SHA1),
+            Err(KeyServerError::Signature(KeySignatureError::Invalid(_)))
+        ));
+
+        let (base_url, _) = serve(|path, _| published(path, SHA1, PUBLIC_KEY, b"%%%"));
+        assert!(matches!(
+            client(&base_url).fetch_public_key(SHA1),
+            Err(KeyServerError::Signature(KeySignatureError::Malformed(_)))
+        ));
+    }
+
+    #[test]
+    fn test_timeout() {
+        // the stand-in never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/k/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let _streams: Vec<_> = listener.incoming().collect();
+        });
+        let server = KeyServerClient::new(&base_url)
+            .with_timeout(Duration::from_millis(200))
+            .with_retry(RetryPolicy::none());
+        assert!(matches!(
+            server.fetch_public_key(SHA1),
+            Err(KeyServerError::Network(_))
+        ));
+    }
+
+    #[test]
+    fn test_fetch_revocation_list() {
+        let list = format!(
+            r#"[{{"sha1": "{}", "revoked_at": 1700000000, "reason": "instrument volé"}}]"#,
+            SHA1
+        );
+        let signature = sign_revocation_list(&publisher_private_key(), list.as_bytes()).unwrap();
+        let published_list = move |list: String, signature: Option<String>| {
+            move |path: &str, _| match (path, &signature) {
+                ("/k/revocations.json", _) => (200, list.as_bytes().to_vec()),
+                ("/k/revocations.json.sig", Some(signature)) => {
+                    (200, signature.as_bytes().to_vec())
+                }
+                _ => (404, vec![]),
+            }
+        };
+        let (base_url, _) = serve(published_list(list.clone(), Some(signature.clone())));
+        let revocations = fetch_revocation_list(&client(&base_url)).unwrap();
+        assert_eq!(revocations.len(), 1);
+        assert_eq!(revocations[0].reason, "instrument volé");
+
+        // a list without signature, or changed on the way, is refused
+        let (base_url, _) = serve(published_list(list.clone(), None));
+        assert!(matches!(
+            fetch_revocation_list(&client(&base_url)),
+            Err(RevocationError::Unsigned)
+        ));
+        let forged = list.replace(SHA1, "0123456789abcdef0123456789abcdef01234567");
+        let (base_url, _) = serve(published_list(forged, Some(signature.clone())));
+        assert!(matches!(
+            fetch_revocation_list(&client(&base_url)),
+            Err(RevocationError::Signature(
+                KeySignatureError::InvalidRevocations
+            ))
+        ));
+        // a key signature is not a list signature
+        let (base_url, _) = serve(published_list(
+            list.clone(),
+            Some(String::from_utf8(sign(SHA1, PUBLIC_KEY)).unwrap()),
+        ));
+        assert!(fetch_revocation_list(&client(&base_url)).is_err());
+
+        let (base_url, _) = serve(|_, _| (404, vec![]));
+        assert!(matches!(
+            fetch_revocation_list(&client(&base_url)),
+            Err(RevocationError::Download(KeyServerError::Status(404)))
+        ));
+    }
+}
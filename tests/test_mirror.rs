@@ -0,0 +1,192 @@
+mod common;
+
+#[cfg(test)]
+
+mod test_mirror {
+
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::common::*;
+    use encrypter::folder::WalkOptions;
+    use encrypter::key_store::MemoryKeyStore;
+    use encrypter::keys_management::Key;
+    use encrypter::mirror::*;
+    use encrypter::output::*;
+
+    const PUBLIC_KEY: &[u8] = include_bytes!("../test_public.key.pem");
+    const SHA1: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+
+    /// master folder, in its own work folder
+    fn master_folder(name: &str) -> PathBuf {
+        let work = temp_folder(&format!("mirror_{}", name));
+        fs::create_dir_all(work.join("master/valses/lentes")).unwrap();
+        for file in [
+            "polka.mid",
+            "notes.txt",
+            "valses/polka.mid",
+            "valses/lentes/lente.mid",
+        ] {
+            fs::write(work.join("master").join(file), file.as_bytes()).unwrap();
+        }
+        work.join("master")
+    }
+
+    fn encrypted(plan: &MirrorPlan) -> Vec<String> {
+        plan.actions
+            .iter()
+            .filter_map(|a| match a {
+                MirrorAction::Encrypt { source, .. } => Some(relative(source, &plan.root)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn relative(path: &Path, root: &Path) -> String {
+        path.strip_prefix(root)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/")
+    }
+
+    #[test]
+    fn test_mirror() {
+        let master = master_folder("default");
+        // the default template, in the work folder
+        let work = master.parent().unwrap();
+        let db = MemoryKeyStore::new();
+        let options = MirrorOptions {
+            walk: WalkOptions::music(),
+            remove_orphans: true,
+            layout: OutputLayout::new(work, DEFAULT_TEMPLATE).unwrap(),
+        };
+
+        // the dry run changes nothing
+        let first = plan(&db, &master, "martin", SHA1, &options).unwrap();
+        assert_eq!(
+            encrypted(&first),
+            vec!["polka.mid", "valses/lentes/lente.mid", "valses/polka.mid"]
+        );
+        assert_eq!(first.to_remove(), 0);
+        let out = work.join(format!("martin-{}", SHA1));
+        assert!(!out.exists());
+
+        // the sub folders are kept, the files of the same name are not mixed up
+        let report = apply(&db, &first, PUBLIC_KEY, |_, _| {});
+        assert_eq!(report.encrypted, 3);
+        assert!(report.failed.is_empty());
+        assert!(out.join("polka.midx").exists());
+        assert!(out.join("valses/polka.midx").exists());
+        assert!(out.join("valses/lentes/lente.midx").exists());
+
+        let again = plan(&db, &master, "martin", SHA1, &options).unwrap();
+        assert!(again.is_empty());
+        assert_eq!(again.up_to_date, 3);
+
+        // only the new and changed files are encrypted, the removed ones are deleted
+        fs::write(master.join("valses/polka.mid"), b"polka 2").unwrap();
+        fs::write(master.join("marche.mid"), b"marche").unwrap();
+        fs::remove_file(master.join("valses/lentes/lente.mid")).unwrap();
+        let changes = plan(&db, &master, "martin", SHA1, &options).unwrap();
+        assert_eq!(encrypted(&changes), vec!["marche.mid", "valses/polka.mid"]);
+        assert!(changes.actions.contains(&MirrorAction::Encrypt {
+            source: master.join("valses/polka.mid"),
+            output: out.join("valses/polka.midx"),
+            status: EncryptionStatus::Stale,
+        }));
+        assert_eq!(changes.to_remove(), 1);
+        let without_removal = MirrorOptions {
+            remove_orphans: false,
+            ..options.clone()
+        };
+        let kept = plan(&db, &master, "martin", SHA1, &without_removal).unwrap();
+        assert_eq!(kept.to_remove(), 0);
+
+        let report = apply(&db, &changes, PUBLIC_KEY, |_, _| {});
+        assert_eq!((report.encrypted, report.removed), (2, 1));
+        assert!(!out.join("valses/lentes").exists());
+        assert!(plan(&db, &master, "martin", SHA1, &options)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_mirror_template() {
+        let master = master_folder("template").join("valses");
+        let out = temp_folder("mirror_template_out");
+        fs::write(master.join("polka.midi"), b"polka midi").unwrap();
+        let db = MemoryKeyStore::new();
+        let options = MirrorOptions {
+            walk: WalkOptions::music(),
+            layout: OutputLayout::new(&out, "{out}/{key.name}/{relpath}/{stem}.midx").unwrap(),
+            ..Default::default()
+        };
+
+        // the files of the same stem are not encrypted
+        let plan = plan(&db, &master, "martin", SHA1, &options).unwrap();
+        assert_eq!(encrypted(&plan), vec!["lentes/lente.mid"]);
+        assert_eq!(plan.errors.len(), 2);
+        assert_eq!(plan.errors[0].path, master.join("polka.mid"));
+
+        let report = apply(&db, &plan, PUBLIC_KEY, |_, _| {});
+        assert_eq!(report.encrypted, 1);
+        assert!(out.join("martin/lentes/lente.midx").exists());
+    }
+
+    #[test]
+    fn test_mirror_outputs_in_source() {
+        let db = MemoryKeyStore::new();
+        for (name, template) in [
+            ("in_source", "{out}/{relpath}/{stem}.{key.sha1}.enc"),
+            (
+                "in_source_folder",
+                "{out}/chiffres-{key.name}/{relpath}/{name}x",
+            ),
+            ("in_source_name", "{out}/{key.name}/{relpath}/{stem}.midx"),
+        ] {
+            let master = master_folder(name);
+            let options = MirrorOptions {
+                layout: OutputLayout::new(&master, template).unwrap(),
+                ..Default::default()
+            };
+            let first = plan(&db, &master, "martin", SHA1, &options).unwrap();
+            assert_eq!(first.to_encrypt(), 4, "{}", template);
+            apply(&db, &first, PUBLIC_KEY, |_, _| {});
+
+            // the encrypted files are not sources
+            assert!(plan(&db, &master, "martin", SHA1, &options)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn test_mirror_keys_collision() {
+        let master = master_folder("keys_collision");
+        let options = MirrorOptions {
+            layout: OutputLayout::new(&master, "{out}/{key.name}/{relpath}/{name}x").unwrap(),
+            ..Default::default()
+        };
+        let key = |name: &str, sha1: &str| Key {
+            name: name.into(),
+            sha1: sha1.into(),
+            ..Default::default()
+        };
+        let other = "0123456789abcdef0123456789abcdef01234567";
+        assert!(check_keys(&master, &[key("martin", SHA1)], &options).is_ok());
+        assert!(check_keys(
+            &master,
+            &[key("martin", SHA1), key("durand", other)],
+            &options
+        )
+        .is_ok());
+        assert!(matches!(
+            check_keys(
+                &master,
+                &[key("martin", SHA1), key("martin", other)],
+                &options
+            ),
+            Err(OutputError::KeyCollision(_, _, _))
+        ));
+    }
+}
@@ -0,0 +1,242 @@
+mod common;
+
+#[cfg(test)]
+
+mod test_output {
+
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use crate::common::*;
+    use encrypter::encrypt::sha256_hex;
+    use encrypter::folder::*;
+    use encrypter::key_store::{KeyStore, MemoryKeyStore};
+    use encrypter::keys_management::{AuditEntry, Key};
+    use encrypter::output::*;
+
+    const SHA1: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+
+    /// default template, the encrypted files are written in the work folder
+    fn layout(folder: &Path) -> OutputLayout {
+        OutputLayout::new(folder, DEFAULT_TEMPLATE).unwrap()
+    }
+
+    fn source(folder: &Path, name: &str, content: &[u8]) -> PathBuf {
+        let path = folder.join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn encrypted(folder: &Path, source: &Path, key_name: &str) -> PathBuf {
+        let layout = layout(folder);
+        let output = layout.path(Path::new(source.file_name().unwrap()), key_name, SHA1);
+        fs::create_dir_all(layout.key_folder(key_name, SHA1)).unwrap();
+        fs::write(&output, b"encrypted").unwrap();
+        output
+    }
+
+    fn record(db: &dyn KeyStore, source: &Path, output: &Path, content: &[u8]) {
+        let absolute = |p: &Path| fs::canonicalize(p).unwrap().to_string_lossy().to_string();
+        db.record_encryption(&AuditEntry {
+            id: 0,
+            timestamp: 10,
+            source_path: absolute(source),
+            source_sha256: sha256_hex(content),
+            output_path: absolute(output),
+            output_size: 9,
+            key_sha1: SHA1.into(),
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = OutputLayout::default();
+        let key_folder = PathBuf::from(format!("martin-{}", SHA1));
+        assert_eq!(layout.key_folder("martin", SHA1), key_folder);
+        assert_eq!(
+            layout.path(Path::new("valses/polka.mid"), "martin", SHA1),
+            key_folder.join("valses").join("polka.midx")
+        );
+        assert_eq!(
+            layout.path(Path::new("polka.mid"), "martin", SHA1),
+            key_folder.join("polka.midx")
+        );
+
+        let layout = OutputLayout::new("/out", "{out}/{key.name}/{relpath}/{stem}.midx").unwrap();
+        assert_eq!(layout.key_folder("a/b", SHA1), PathBuf::from("/out/a_b"));
+        assert_eq!(
+            layout.path(Path::new("valses/polka.mid"), "martin", SHA1),
+            PathBuf::from("/out/martin/valses/polka.midx")
+        );
+        assert_eq!(
+            relative_to(Path::new("/music/valses/polka.mid"), Path::new("/music")),
+            PathBuf::from("valses/polka.mid")
+        );
+        assert_eq!(
+            relative_to(Path::new("/music/polka.mid"), Path::new("")),
+            PathBuf::from("polka.mid")
+        );
+
+        // the files of the same stem are encrypted to the same file
+        let sources = vec![
+            PathBuf::from("/music/polka.mid"),
+            PathBuf::from("/music/valses/polka.mid"),
+            PathBuf::from("/music/valses/polka.midi"),
+        ];
+        let collision = layout.paths(&sources, Path::new("/music"), "martin", SHA1);
+        assert!(matches!(
+            collision,
+            Err(OutputError::Collision(a, b, _)) if a == sources[1] && b == sources[2]
+        ));
+        let outputs = layout
+            .paths(&sources[..2], Path::new("/music"), "martin", SHA1)
+            .unwrap();
+        assert_eq!(outputs[0], PathBuf::from("/out/martin/polka.midx"));
+
+        for template in [
+            "{out}/{key}/{name}",
+            "{out}/{key.name",
+            "{out}-x/{name}",
+            "{name}/{out}/a",
+            "{out}/{relpath}",
+        ] {
+            assert!(OutputLayout::new("", template).is_err(), "{}", template);
+        }
+        assert!(matches!(
+            OutputLayout::new("", "{out}/{key}/{name}"),
+            Err(OutputError::UnknownPlaceholder(p)) if p == "key"
+        ));
+    }
+
+    #[test]
+    fn test_path_safe() {
+        assert_eq!(path_safe("martin"), "martin");
+        assert_eq!(path_safe("a/b\\c"), "a_b_c");
+        assert_eq!(path_safe("a<b>:\"|?*"), "a_b______");
+        assert_eq!(path_safe("a\tb"), "a_b");
+        assert_eq!(path_safe(""), "_");
+        assert_eq!(path_safe("."), "_");
+        assert_eq!(path_safe(".."), "__");
+        assert_eq!(path_safe("martin. "), "martin__");
+        assert_eq!(path_safe(".martin"), ".martin");
+        assert_eq!(path_safe("CON"), "_CON");
+        assert_eq!(path_safe("lpt1.txt"), "_lpt1.txt");
+        assert_eq!(path_safe("console"), "console");
+    }
+
+    #[test]
+    fn test_exclude_globs() {
+        assert_eq!(
+            OutputLayout::default().exclude_globs(),
+            vec![format!("*-{}", "?".repeat(40))]
+        );
+        let layout = OutputLayout::new("", "{out}/chiffres/{key.name}/{relpath}/{name}x").unwrap();
+        // the key folders can't be told from the source folders
+        assert_eq!(layout.exclude_globs(), vec!["*x".to_string()]);
+        let layout = OutputLayout::new("", "{out}/{relpath}/{stem}.{key.sha1}.enc").unwrap();
+        assert_eq!(
+            layout.exclude_globs(),
+            vec![format!("*.{}.enc", "?".repeat(40))]
+        );
+        // the outputs have the names of the sources
+        let layout = OutputLayout::new("/out", "{out}/{relpath}/{name}").unwrap();
+        assert!(layout.exclude_globs().is_empty());
+
+        let options = WalkOptions::default().with_outputs(&layout);
+        assert_eq!(options.exclude, vec!["keys.db", "*.pem"]);
+    }
+
+    #[test]
+    fn test_check_keys() {
+        let key = |name: &str, sha1: &str| Key {
+            name: name.into(),
+            sha1: sha1.into(),
+            ..Default::default()
+        };
+        let other = "0123456789abcdef0123456789abcdef01234567";
+        let relative = vec![
+            PathBuf::from("polka.mid"),
+            PathBuf::from("valses/polka.mid"),
+        ];
+        let keys = [key("martin", SHA1), key("martin", other)];
+
+        assert!(OutputLayout::default().check_keys(&relative, &keys).is_ok());
+        let layout = OutputLayout::new("", "{out}/{key.name}/{relpath}/{name}x").unwrap();
+        assert!(layout.check_keys(&relative, &keys[..1]).is_ok());
+        assert!(matches!(
+            layout.check_keys(&relative, &keys),
+            Err(OutputError::KeyCollision(_, _, output)) if output == Path::new("martin/polka.midx")
+        ));
+        // two names written the same in a path
+        let keys = [key("a/b", SHA1), key("a:b", other)];
+        assert!(layout.check_keys(&relative, &keys).is_err());
+    }
+
+    #[test]
+    fn test_status_recorded() {
+        let folder = temp_folder("output_recorded");
+        let db = MemoryKeyStore::new();
+        let polka = source(&folder, "polka.mid", b"MThd polka");
+        let valse = source(&folder, "valse.mid", b"MThd valse");
+        let output = encrypted(&folder, &polka, "recorded");
+        record(&db, &polka, &output, b"MThd polka");
+
+        let checker = StatusChecker::new(&db, "recorded", SHA1)
+            .unwrap()
+            .with_layout(layout(&folder), &folder);
+        assert_eq!(checker.status(&valse).unwrap(), EncryptionStatus::Missing);
+        assert_eq!(checker.status(&polka).unwrap(), EncryptionStatus::UpToDate);
+
+        // the content is compared, not the modification time
+        fs::write(&polka, b"MThd polka 2").unwrap();
+        assert_eq!(checker.status(&polka).unwrap(), EncryptionStatus::Stale);
+        assert!(checker.status(&polka).unwrap().is_outdated());
+        assert!(!EncryptionStatus::UpToDate.is_outdated());
+
+        // the encryptions of the other keys are ignored
+        let other = StatusChecker::new(&db, "recorded", "other")
+            .unwrap()
+            .with_layout(layout(&folder), &folder);
+        assert_eq!(other.status(&polka).unwrap(), EncryptionStatus::Missing);
+    }
+
+    #[test]
+    fn test_status_unrecorded() {
+        let folder = temp_folder("output_unrecorded");
+        let db = MemoryKeyStore::new();
+        let polka = source(&folder, "marche.mid", b"MThd marche");
+        let output = encrypted(&folder, &polka, "unrecorded");
+
+        let checker = StatusChecker::new(&db, "unrecorded", SHA1)
+            .unwrap()
+            .with_layout(layout(&folder), &folder);
+        assert_eq!(checker.status(&polka).unwrap(), EncryptionStatus::UpToDate);
+
+        // without record, the source more recent than the output is stale
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        let file = fs::File::options().write(true).open(&output).unwrap();
+        file.set_modified(old).unwrap();
+        assert_eq!(checker.status(&polka).unwrap(), EncryptionStatus::Stale);
+    }
+
+    #[test]
+    fn test_select_files() {
+        let folder = temp_folder("output_select");
+        fs::create_dir_all(folder.join("valses")).unwrap();
+        for name in ["polka.mid", "marche.mid", "valses/vienne.mid"] {
+            fs::write(folder.join(name), b"").unwrap();
+        }
+        let mut tree = FolderNode::new_root(&folder);
+        expand(&mut tree);
+        expand(find_mut(&mut tree, &folder.join("valses")).unwrap());
+        tree.subfolders[0].selected = true;
+
+        select_files(&mut tree, &mut |p| p.file_name().unwrap() != "marche.mid");
+        assert_eq!(
+            selected_paths(&tree),
+            vec![folder.join("polka.mid"), folder.join("valses/vienne.mid")]
+        );
+    }
+}
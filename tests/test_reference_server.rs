@@ -0,0 +1,361 @@
+mod common;
+
+#[cfg(test)]
+
+mod test_reference_server {
+
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use crate::common::*;
+    use encrypter::integrity::*;
+    use encrypter::keys_management::*;
+    use encrypter::keyserver::*;
+    use encrypter::reference_server::*;
+    use openssl::rsa::Rsa;
+
+    const SHA1_A: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+    const SHA1_B: &str = "0123456789abcdef0123456789abcdef01234567";
+    const TOKEN: &str = "atelier-secret";
+
+    fn repository(name: &str) -> PathBuf {
+        temp_folder(&format!("reference_server_{}", name))
+    }
+
+    /// reference server on a free port, returns the client of the server
+    fn start(folder: &PathBuf) -> KeyServerClient {
+        let server = KeyServer::new(
+            DirectoryRepository::new(folder),
+            ServerConfig {
+                tokens: vec![TOKEN.into()],
+                publishers: vec![publisher()],
+                signing_key: Some(publisher_private_key()),
+            },
+        )
+        .unwrap();
+        let address = server.spawn("127.0.0.1:0").unwrap();
+        client(&format!("http://{}/k", address))
+    }
+
+    fn signed(sha1: &str, public_key: &[u8]) -> UploadAuth {
+        UploadAuth::Signature(String::from_utf8(sign(sha1, public_key)).unwrap())
+    }
+
+    #[test]
+    fn test_signed_upload() {
+        let folder = repository("signed");
+        let server = start(&folder);
+
+        assert!(matches!(
+            server.fetch_public_key(SHA1_A),
+            Err(KeyServerError::NotFound(_))
+        ));
+        let auth = signed(SHA1_A, PUBLIC_KEY);
+        assert_eq!(
+            server.upload_public_key(SHA1_A, PUBLIC_KEY, &auth).unwrap(),
+            UploadOutcome::Created
+        );
+        assert_eq!(server.fetch_public_key(SHA1_A).unwrap(), PUBLIC_KEY);
+        assert_eq!(
+            server.upload_public_key(SHA1_A, PUBLIC_KEY, &auth).unwrap(),
+            UploadOutcome::Unchanged
+        );
+
+        // the signature does not cover another sha1
+        assert!(matches!(
+            server.upload_public_key(SHA1_B, PUBLIC_KEY, &auth),
+            Err(KeyServerError::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    fn test_token_upload() {
+        let folder = repository("token");
+        let server = start(&folder);
+
+        assert!(matches!(
+            server.upload_public_key(SHA1_A, PUBLIC_KEY, &UploadAuth::Token("faux".into())),
+            Err(KeyServerError::Unauthorized(_))
+        ));
+        let token = UploadAuth::Token(TOKEN.into());
+        assert_eq!(
+            server
+                .upload_public_key(SHA1_A, PUBLIC_KEY, &token)
+                .unwrap(),
+            UploadOutcome::Created
+        );
+        // published with the signature of the server
+        assert_eq!(server.fetch_public_key(SHA1_A).unwrap(), PUBLIC_KEY);
+        assert_eq!(
+            fs::read(folder.join(SHA1_A).join("public.key.sig")).unwrap(),
+            sign(SHA1_A, PUBLIC_KEY)
+        );
+        assert_eq!(
+            server
+                .upload_public_key(SHA1_A, PUBLIC_KEY, &signed(SHA1_A, PUBLIC_KEY))
+                .unwrap(),
+            UploadOutcome::Unchanged
+        );
+
+        // another key is not replaced
+        let other = Rsa::generate(1024)
+            .unwrap()
+            .public_key_to_pem_pkcs1()
+            .unwrap();
+        assert!(matches!(
+            server.upload_public_key(SHA1_A, &other, &token),
+            Err(KeyServerError::Conflict(_))
+        ));
+        assert_eq!(
+            fs::read(folder.join(SHA1_A).join("public.key.pem")).unwrap(),
+            PUBLIC_KEY
+        );
+
+        // checked by the client before the upload
+        assert!(matches!(
+            server.upload_public_key(SHA1_B, b"pas une clef", &token),
+            Err(KeyServerError::InvalidKey(_, _))
+        ));
+    }
+
+    #[test]
+    fn test_unsigned_key_upload() {
+        // published before the signing key was configured
+        let folder = repository("unsigned");
+        fs::create_dir_all(folder.join(SHA1_A)).unwrap();
+        fs::write(folder.join(SHA1_A).join("public.key.pem"), PUBLIC_KEY).unwrap();
+        let server = start(&folder);
+
+        assert_eq!(
+            server
+                .upload_public_key(SHA1_A, PUBLIC_KEY, &UploadAuth::Token(TOKEN.into()))
+                .unwrap(),
+            UploadOutcome::Unchanged
+        );
+        // the signature of the server is stored beside the key
+        let signature = folder.join(SHA1_A).join("public.key.sig");
+        assert_eq!(fs::read(&signature).unwrap(), sign(SHA1_A, PUBLIC_KEY));
+        assert_eq!(
+            fs::read(folder.join(SHA1_A).join("public.key.pem")).unwrap(),
+            PUBLIC_KEY
+        );
+
+        // the stored signature is kept
+        fs::write(&signature, b"signature en place").unwrap();
+        assert_eq!(
+            server
+                .upload_public_key(SHA1_A, PUBLIC_KEY, &signed(SHA1_A, PUBLIC_KEY))
+                .unwrap(),
+            UploadOutcome::Unchanged
+        );
+        assert_eq!(fs::read(&signature).unwrap(), b"signature en place");
+    }
+
+    #[test]
+    fn test_idle_connection() {
+        let folder = repository("idle");
+        fs::create_dir_all(folder.join(SHA1_A)).unwrap();
+        fs::write(folder.join(SHA1_A).join("public.key.pem"), PUBLIC_KEY).unwrap();
+        let server = start(&folder);
+        let address = server.base_url().trim_start_matches("http://");
+        let address = address.split('/').next().unwrap().to_string();
+
+        // a client that sends nothing does not hold the other requests
+        let _idle = TcpStream::connect(&address).unwrap();
+        let time = Instant::now();
+        assert_eq!(server.fetch_public_key(SHA1_A).unwrap(), PUBLIC_KEY);
+        assert!(time.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_handle() {
+        let server = KeyServer::new(
+            DirectoryRepository::new(repository("handle")),
+            ServerConfig {
+                tokens: vec![TOKEN.into()],
+                signing_key: Some(publisher_private_key()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let request = |method: &str, path: &str, body: &[u8]| {
+            server
+                .handle(&Request {
+                    method: method.into(),
+                    path: path.into(),
+                    headers: vec![("authorization".into(), format!("Bearer {}", TOKEN))],
+                    body: body.to_vec(),
+                })
+                .status
+        };
+        let key_path = format!("/k/{}/public.key.pem", SHA1_B);
+        assert_eq!(request("PUT", &key_path, b"pas une clef"), 400);
+        assert_eq!(request("PUT", &key_path, PUBLIC_KEY), 201);
+        assert_eq!(request("GET", &key_path, b""), 200);
+        assert_eq!(request("DELETE", &key_path, b""), 405);
+        assert_eq!(request("GET", "/k/../../etc/public.key.pem", b""), 404);
+        assert_eq!(
+            request("PUT", "/k/..%2F..%2Fetc/public.key.pem", PUBLIC_KEY),
+            404
+        );
+
+        // no authentication
+        let status = server
+            .handle(&Request {
+                method: "PUT".into(),
+                path: format!("/k/{}/public.key.pem", SHA1_A),
+                body: PUBLIC_KEY.to_vec(),
+                ..Default::default()
+            })
+            .status;
+        assert_eq!(status, 401);
+    }
+
+    #[test]
+    fn test_tokens_need_signing_key() {
+        let server = KeyServer::new(
+            DirectoryRepository::new(repository("no_signing_key")),
+            ServerConfig {
+                tokens: vec![TOKEN.into()],
+                ..Default::default()
+            },
+        );
+        assert!(matches!(server, Err(ServerError::Config(_))));
+    }
+
+    #[test]
+    fn test_line_too_long() {
+        let server = KeyServer::new(
+            DirectoryRepository::new(repository("long_line")),
+            ServerConfig::default(),
+        )
+        .unwrap();
+        let address = server.spawn("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        // the whole line is read by the server, without newline
+        let request = format!("GET /k/{}", "a".repeat(8 * 1024 - 7));
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 "));
+    }
+
+    fn get(server: &KeyServer<impl KeyRepository>, path: &str, if_none_match: &str) -> Response {
+        let mut request = Request {
+            method: "GET".into(),
+            path: path.into(),
+            ..Default::default()
+        };
+        if !if_none_match.is_empty() {
+            request
+                .headers
+                .push(("if-none-match".into(), if_none_match.into()));
+        }
+        server.handle(&request)
+    }
+
+    #[test]
+    fn test_listing_and_etag() {
+        let folder = repository("listing");
+        for sha1 in [SHA1_B, SHA1_A] {
+            fs::create_dir_all(folder.join(sha1)).unwrap();
+            fs::write(folder.join(sha1).join("public.key.pem"), PUBLIC_KEY).unwrap();
+        }
+        fs::create_dir_all(folder.join("pas-un-sha1")).unwrap();
+        let server =
+            KeyServer::new(DirectoryRepository::new(&folder), ServerConfig::default()).unwrap();
+
+        let list = get(&server, "/k/", "");
+        assert_eq!(list.status, 200);
+        let sha1: Vec<String> = serde_json::from_slice(&list.body).unwrap();
+        assert_eq!(sha1, vec![SHA1_B.to_string(), SHA1_A.to_string()]);
+
+        let key_path = format!("/k/{}/public.key.pem", SHA1_A);
+        let key = get(&server, &key_path, "");
+        assert_eq!(key.body, PUBLIC_KEY);
+        // no signing key, the key is not signed
+        assert_eq!(key.header(SIGNATURE_HEADER), None);
+        assert_eq!(
+            get(&server, &format!("/k/{}/public.key.sig", SHA1_A), "").status,
+            404
+        );
+
+        let tag = key.header("etag").unwrap().to_string();
+        let cached = get(&server, &key_path, &tag);
+        assert_eq!(cached.status, 304);
+        assert!(cached.body.is_empty());
+        assert_eq!(get(&server, &key_path, "\"autre\", *").status, 304);
+        assert_eq!(get(&server, &key_path, "\"autre\"").status, 200);
+        // the listing changes with the keys
+        assert_ne!(list.header("etag"), key.header("etag"));
+    }
+
+    #[test]
+    fn test_store_repository() {
+        let path = temp_folder("reference_server_store").join("keys.db");
+        // written before the protection, signed when the integrity key is created
+        Database::open(&path)
+            .unwrap()
+            .insert(&Key {
+                name: "martin".into(),
+                sha1: SHA1_A.into(),
+                public_key: Some(PUBLIC_KEY.to_vec()),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let server = KeyServer::new(
+            StoreRepository::new(&path),
+            ServerConfig {
+                tokens: vec![TOKEN.into()],
+                signing_key: Some(publisher_private_key()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let client = client(&format!(
+            "http://{}/k",
+            server.spawn("127.0.0.1:0").unwrap()
+        ));
+
+        // signed by the server
+        assert_eq!(client.fetch_public_key(SHA1_A).unwrap(), PUBLIC_KEY);
+        assert_eq!(
+            client
+                .upload_public_key(SHA1_B, PUBLIC_KEY, &UploadAuth::Token(TOKEN.into()))
+                .unwrap(),
+            UploadOutcome::Created
+        );
+        let store = open_protected(&path).unwrap();
+        let added = store.get_by_sha1(SHA1_B).unwrap();
+        assert_eq!(added.public_key, Some(PUBLIC_KEY.to_vec()));
+        assert_eq!(added.source, KeySource::Downloaded);
+        assert_eq!(store.verify_integrity(&added), IntegrityStatus::Valid);
+        drop(store);
+        assert_eq!(
+            client.get(client.base_url()).unwrap(),
+            serde_json::to_vec(&[SHA1_B, SHA1_A]).unwrap()
+        );
+
+        // a key swapped outside of the application is not served
+        let other = Rsa::generate(1024)
+            .unwrap()
+            .public_key_to_pem_pkcs1()
+            .unwrap();
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute(
+                "UPDATE all_keys SET public_key = ?1 WHERE sha1 = ?2",
+                (other, SHA1_A),
+            )
+            .unwrap();
+        assert!(client.fetch_public_key(SHA1_A).is_err());
+        assert_eq!(
+            client.get(client.base_url()).unwrap(),
+            serde_json::to_vec(&[SHA1_B]).unwrap()
+        );
+    }
+}
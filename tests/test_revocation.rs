@@ -0,0 +1,71 @@
+#[cfg(test)]
+
+mod test_revocation {
+
+    use encrypter::keys_management::*;
+    use encrypter::revocation::*;
+
+    const SHA1_A: &str = "30d9690cc085429a1d0a3ae787932bf1518a1798";
+    const SHA1_B: &str = "0123456789abcdef0123456789abcdef01234567";
+    const SHA1_UNKNOWN: &str = "ffffffffffffffffffffffffffffffffffffffff";
+
+    fn test_database() -> Database {
+        let d = Database::open_in_memory().unwrap();
+        for (name, sha1) in [("martin", SHA1_A), ("durand", SHA1_B)] {
+            d.insert(&Key {
+                name: name.into(),
+                sha1: sha1.into(),
+                public_key: Some(include_bytes!("../test_public.key.pem").to_vec()),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        d
+    }
+
+    #[test]
+    fn test_revocation_list() {
+        let list = format!(
+            r#"[
+                {{"sha1": "{}", "revoked_at": 1700000000, "reason": "instrument volé"}},
+                {{"sha1": "{}", "revoked_at": 1700000000}}
+            ]"#,
+            SHA1_A, SHA1_UNKNOWN
+        );
+        let path = std::env::temp_dir().join("encrypter_revocations.json");
+        std::fs::write(&path, list).unwrap();
+        let revocations = read_revocation_list(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(revocations.len(), 2);
+        assert_eq!(revocations[1].reason, "");
+
+        let d = test_database();
+        let report = apply_revocations(&d, &revocations).unwrap();
+        assert_eq!(
+            (report.revoked, report.already_revoked, report.unknown),
+            (1, 0, 1)
+        );
+        let k = d.get_by_sha1(SHA1_A).unwrap();
+        assert_eq!(k.validity(unix_time()), KeyValidity::Revoked);
+        assert_eq!(k.revocation_reason, "instrument volé");
+        assert_eq!(
+            d.get_by_sha1(SHA1_B).unwrap().validity(unix_time()),
+            KeyValidity::Valid
+        );
+
+        let report = apply_revocations(&d, &revocations).unwrap();
+        assert_eq!(report.already_revoked, 1);
+    }
+
+    #[test]
+    fn test_revocation_invalid_list() {
+        assert!(matches!(
+            parse_revocation_list(r#"[{"sha1": "kk", "revoked_at": 0}]"#),
+            Err(RevocationError::Key(KeyManagementError::InvalidSha1(_)))
+        ));
+        assert!(matches!(
+            parse_revocation_list("not json"),
+            Err(RevocationError::Json(_))
+        ));
+    }
+}
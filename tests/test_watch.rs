@@ -0,0 +1,147 @@
+mod common;
+
+#[cfg(test)]
+
+mod test_watch {
+
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::common::*;
+    use encrypter::watch::*;
+
+    fn watched_folder(name: &str) -> PathBuf {
+        let folder = temp_folder(&format!("watch_{}", name));
+        fs::create_dir_all(folder.join("valses")).unwrap();
+        folder
+    }
+
+    /// changed folders reported within a few seconds
+    fn wait_changed(watcher: &mut FolderWatcher) -> Vec<PathBuf> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            let changed = watcher.changed();
+            if !changed.is_empty() {
+                return changed;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        vec![]
+    }
+
+    fn check_watcher(mut watcher: FolderWatcher, folder: &Path) {
+        let valses = folder.join("valses");
+        watcher.watch(&[folder.to_path_buf(), valses.clone()]);
+        assert!(watcher.changed().is_empty());
+
+        fs::write(valses.join("vienne.mid"), b"").unwrap();
+        assert_eq!(wait_changed(&mut watcher), vec![valses.clone()]);
+
+        // a file written in place
+        thread::sleep(Duration::from_millis(20));
+        fs::write(valses.join("vienne.mid"), b"MThd").unwrap();
+        assert_eq!(wait_changed(&mut watcher), vec![valses.clone()]);
+        fs::remove_dir(folder.join("absent")).unwrap_err();
+        thread::sleep(Duration::from_millis(100));
+        assert!(watcher.changed().is_empty());
+
+        // unwatched folders are not reported
+        watcher.watch(&[folder.to_path_buf()]);
+        assert_eq!(watcher.watched(), &[folder.to_path_buf()]);
+        fs::remove_file(valses.join("vienne.mid")).unwrap();
+        fs::write(folder.join("polka.mid"), b"").unwrap();
+        assert_eq!(wait_changed(&mut watcher), vec![folder.to_path_buf()]);
+    }
+
+    #[test]
+    fn test_watch() {
+        let folder = watched_folder("default");
+        check_watcher(FolderWatcher::new(), &folder);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watch_removed_folder() {
+        let folder = watched_folder("removed");
+        let valses = folder.join("valses");
+        let folders = vec![folder.clone(), valses.clone()];
+        let mut watcher = FolderWatcher::new();
+        watcher.watch(&folders);
+
+        fs::remove_dir(&valses).unwrap();
+        assert!(wait_changed(&mut watcher).contains(&folder));
+        assert_eq!(watcher.watched(), std::slice::from_ref(&folder));
+
+        // watched again once created
+        fs::create_dir(&valses).unwrap();
+        watcher.watch(&folders);
+        assert_eq!(watcher.watched(), folders.as_slice());
+        assert_eq!(wait_changed(&mut watcher), vec![folder.clone()]);
+        fs::write(valses.join("vienne.mid"), b"").unwrap();
+        assert_eq!(wait_changed(&mut watcher), vec![valses]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watch_linked_folder() {
+        let folder = watched_folder("linked");
+        let valses = folder.join("valses");
+        let link = temp_folder("watch_linked_link").join("valses");
+        std::os::unix::fs::symlink(&valses, &link).unwrap();
+
+        // the two paths share the watch of the folder
+        let mut watcher = FolderWatcher::new();
+        watcher.watch(&[valses.clone(), link.clone()]);
+        fs::write(valses.join("vienne.mid"), b"").unwrap();
+        assert_eq!(
+            wait_changed(&mut watcher),
+            vec![valses.clone(), link.clone()]
+        );
+
+        watcher.watch(std::slice::from_ref(&valses));
+        fs::write(valses.join("polka.mid"), b"").unwrap();
+        assert_eq!(wait_changed(&mut watcher), vec![valses]);
+        fs::remove_file(&link).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watch_wakes() {
+        let folder = watched_folder("wakes");
+        let valses = folder.join("valses");
+        let mut watcher = FolderWatcher::new();
+        watcher.watch(std::slice::from_ref(&valses));
+        assert!(watcher.needs_polling());
+        let (woken, wait_woken) = std::sync::mpsc::channel();
+        watcher.wake_on_change(move || woken.send(()).unwrap());
+        assert!(!watcher.needs_polling());
+
+        // woken once for the events, until they are read
+        fs::write(valses.join("vienne.mid"), b"").unwrap();
+        fs::write(valses.join("polka.mid"), b"").unwrap();
+        wait_woken.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(watcher.changed(), vec![valses.clone()]);
+        while wait_woken.recv_timeout(Duration::from_millis(100)).is_ok() {
+            watcher.changed();
+        }
+
+        fs::remove_file(valses.join("polka.mid")).unwrap();
+        wait_woken.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(watcher.changed(), vec![valses]);
+
+        // the thread stops with the watcher
+        drop(watcher);
+        assert!(wait_woken.recv_timeout(Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn test_polling() {
+        let folder = watched_folder("polling");
+        let mut watcher = FolderWatcher::polling(Duration::from_millis(10));
+        watcher.wake_on_change(|| {});
+        assert!(watcher.needs_polling());
+        check_watcher(watcher, &folder);
+    }
+}